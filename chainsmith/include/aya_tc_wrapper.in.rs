@@ -0,0 +1,34 @@
+#![no_std]
+#![no_main]
+#![allow(unused_imports)]
+use aya_ebpf::{{
+	bindings::{{TC_ACT_OK, TC_ACT_SHOT}},
+	helpers::bpf_redirect,
+	macros::{{classifier, map}},
+	maps::*,
+	programs::TcContext,
+}};
+
+#[no_mangle]
+#[link_section = "license"]
+pub static LICENSE: [u8; 4] = *b"GPL\0";
+
+#[map(name = "my_id_map")]
+static MY_ID_MAP: Array<u32> = Array::with_max_entries(1, 0);
+
+{2}
+
+// Named as for XDP, so that pulley finds the entry point of either hook.
+#[classifier]
+pub fn outer_xdp_sock_prog(ctx: TcContext) -> i32 {{
+	{3}
+
+	let _out = {0}::packet({1},{4});
+
+	{5}
+}}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {{
+	unsafe {{ core::hint::unreachable_unchecked() }}
+}}
@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+#![allow(unused_imports)]
+use aya_ebpf::{{
+	bindings::{{TC_ACT_OK, TC_ACT_SHOT}},
+	helpers::{{bpf_get_prandom_u32, bpf_redirect}},
+	macros::{{classifier, map}},
+	maps::*,
+	programs::TcContext,
+}};
+
+#[no_mangle]
+#[link_section = "license"]
+pub static LICENSE: [u8; 4] = *b"GPL\0";
+
+#[map(name = "acts_map")]
+static ACTS_MAP: Array<u8> = Array::with_max_entries({2}, 0);
+
+#[map(name = "progs_map")]
+static PROGS_MAP: ProgramArray = ProgramArray::with_max_entries({2}, 0);
+
+#[map(name = "split_map")]
+static SPLIT_MAP: Array<u32> = Array::with_max_entries({2}, 0);
+
+{3}
+
+// Named as for XDP, so that pulley finds the entry point of either hook.
+#[classifier]
+pub fn outer_xdp_sock_prog(ctx: TcContext) -> i32 {{
+	{4}
+
+	let out: u32 = {0}::packet({1},{5}) as u32;
+	{7}
+
+	match {8} {{
+		// tx
+		Some(0) => {6},
+		// drop, abort
+		Some(1) | Some(2) => TC_ACT_SHOT as i32,
+		// tailcall
+		Some(4) => {{
+			let _ = unsafe {{ PROGS_MAP.tail_call(&ctx, out) }};
+
+			TC_ACT_SHOT as i32
+		}},
+		// pass
+		Some(5) => TC_ACT_OK as i32,
+		// upcalls are rejected by chainsmith for tc chains.
+		_ => TC_ACT_SHOT as i32,
+	}}
+}}
+
+/// Picks a branch at random, in proportion to each's weight in `split_map`.
+///
+/// Keeps `out` if no branch has any weight.
+#[inline(always)]
+#[allow(dead_code)]
+fn split_branch(out: u32) -> u32 {{
+	let mut total = 0u32;
+	for i in 0..{2} {{
+		total = total.wrapping_add(SPLIT_MAP.get(i).copied().unwrap_or(0));
+	}}
+
+	if total == 0 {{
+		return out;
+	}}
+
+	let mut pick = unsafe {{ bpf_get_prandom_u32() }} % total;
+	for i in 0..{2} {{
+		let weight = SPLIT_MAP.get(i).copied().unwrap_or(0);
+		if pick < weight {{
+			return i;
+		}}
+		pick -= weight;
+	}}
+
+	out
+}}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {{
+	unsafe {{ core::hint::unreachable_unchecked() }}
+}}
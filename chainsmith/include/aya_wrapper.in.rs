@@ -0,0 +1,31 @@
+#![no_std]
+#![no_main]
+use aya_ebpf::{{
+	bindings::xdp_action,
+	macros::{{map, xdp}},
+	maps::*,
+	programs::XdpContext,
+}};
+
+#[no_mangle]
+#[link_section = "license"]
+pub static LICENSE: [u8; 4] = *b"GPL\0";
+
+#[map(name = "my_id_map")]
+static MY_ID_MAP: Array<u32> = Array::with_max_entries(1, 0);
+
+{2}
+
+#[xdp]
+pub fn outer_xdp_sock_prog(ctx: XdpContext) -> u32 {{
+	{3}
+
+	let _out = {0}::packet({1},{4});
+
+	xdp_action::XDP_TX
+}}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {{
+	unsafe {{ core::hint::unreachable_unchecked() }}
+}}
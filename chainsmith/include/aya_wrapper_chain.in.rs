@@ -0,0 +1,207 @@
+#![no_std]
+#![no_main]
+use aya_ebpf::{{
+	bindings::xdp_action,
+	helpers::{{bpf_get_prandom_u32, bpf_xdp_adjust_meta}},
+	macros::{{map, xdp}},
+	maps::*,
+	programs::XdpContext,
+}};
+
+type ProgId = u32;
+
+#[repr(C)]
+struct DataplaneState {{
+	prog_id: ProgId,
+	num_cores: u32,
+	steering: u32,
+}}
+
+/// Precedes each packet copied to `mirror_map` (see `protocol::MIRROR_MAP`).
+#[repr(C)]
+struct MirrorHeader {{
+	len: u32,
+	meta: [u8; {0}::meta::META_LEN],
+}}
+
+#[no_mangle]
+#[link_section = "license"]
+pub static LICENSE: [u8; 4] = *b"GPL\0";
+
+#[map(name = "acts_map")]
+static ACTS_MAP: Array<u8> = Array::with_max_entries({2}, 0);
+
+#[map(name = "progs_map")]
+static PROGS_MAP: ProgramArray = ProgramArray::with_max_entries({2}, 0);
+
+#[map(name = "split_map")]
+static SPLIT_MAP: Array<u32> = Array::with_max_entries({2}, 0);
+
+#[map(name = "my_state_map")]
+static MY_STATE_MAP: Array<DataplaneState> = Array::with_max_entries(1, 0);
+
+#[map(name = "xsk_map")]
+static XSK_MAP: XskMap = XskMap::with_max_entries(8, 0);
+
+// Sized to the number of CPUs by libbpf.
+#[map(name = "mirror_map")]
+static MIRROR_MAP: PerfEventArray<MirrorHeader> = PerfEventArray::new(0);
+
+{3}
+
+#[xdp]
+pub fn outer_xdp_sock_prog(ctx: XdpContext) -> u32 {{
+	reserve_meta(&ctx);
+	{4}
+
+	let out: u32 = {0}::packet({1},{5}) as u32;
+	{6}
+	{7}
+
+	match {8} {{
+		// tx
+		Some(0) => xdp_action::XDP_TX,
+		// drop
+		Some(1) => xdp_action::XDP_DROP,
+		// abort
+		Some(2) => xdp_action::XDP_ABORTED,
+		// upcall
+		Some(3) => upcall(&ctx, out),
+		// tailcall
+		Some(4) => {{
+			let _ = unsafe {{ PROGS_MAP.tail_call(&ctx, out) }};
+
+			{9}
+		}},
+		Some(5) => xdp_action::XDP_PASS,
+		_ => xdp_action::XDP_ABORTED,
+	}}
+}}
+
+#[inline(always)]
+fn upcall(ctx: &XdpContext, out: u32) -> u32 {{
+	const EXTRA_BYTES: i32 = (core::mem::size_of::<ProgId>() + core::mem::size_of::<u32>()) as i32;
+
+	// expand meta
+	if unsafe {{ bpf_xdp_adjust_meta(ctx.ctx, -EXTRA_BYTES) }} < 0 {{
+		return xdp_action::XDP_ABORTED;
+	}}
+
+	let s_ptr = ctx.metadata();
+	let n_ptr = s_ptr + (EXTRA_BYTES as usize);
+	if n_ptr > ctx.data() {{
+		return xdp_action::XDP_ABORTED;
+	}}
+
+	let state = match MY_STATE_MAP.get(0) {{
+		Some(state) => state,
+		None => return xdp_action::XDP_ABORTED,
+	}};
+
+	let target_core = if state.num_cores != 1 {{
+		let hash = match state.steering {{
+			// random
+			1 => unsafe {{ bpf_get_prandom_u32() }},
+			// flow
+			_ => {0}::flow_hash(&mut &*ctx),
+		}};
+		hash % state.num_cores
+	}} else {{
+		0
+	}};
+
+	unsafe {{
+		core::ptr::write(s_ptr as *mut ProgId, state.prog_id);
+		core::ptr::write((s_ptr + core::mem::size_of::<ProgId>()) as *mut u32, out);
+	}}
+
+	// redirect into xsk_map
+	XSK_MAP
+		.redirect(target_core, 0)
+		.unwrap_or(xdp_action::XDP_ABORTED)
+}}
+
+/// Returns whether to copy a packet to its mirror NF, given the chance
+/// `sample` (as a fraction of `u32::MAX`) of doing so.
+#[inline(always)]
+#[allow(dead_code)]
+fn mirror_sampled(sample: u32) -> bool {{
+	sample == u32::MAX || unsafe {{ bpf_get_prandom_u32() }} < sample
+}}
+
+/// Copies the packet and its metadata to userland via `mirror_map`, for the
+/// mirror NF. The packet itself is untouched, and keeps its NF's action.
+#[inline(always)]
+#[allow(dead_code)]
+fn mirror(ctx: &XdpContext) {{
+	const META_LEN: usize = {0}::meta::META_LEN;
+
+	let mut header = MirrorHeader {{
+		len: (ctx.data_end() - ctx.data()) as u32,
+		meta: [0; META_LEN],
+	}};
+
+	let s_ptr = ctx.metadata();
+	if s_ptr + META_LEN <= ctx.data() {{
+		unsafe {{
+			core::ptr::copy_nonoverlapping(s_ptr as *const u8, header.meta.as_mut_ptr(), META_LEN)
+		}};
+	}}
+
+	// The perf record is followed by the first `len` bytes of the packet.
+	MIRROR_MAP.output(ctx, &header, header.len);
+}}
+
+/// Reserves and zeroes `nf::meta` space ahead of the packet, unless an
+/// earlier NF in the chain already has.
+#[inline(always)]
+fn reserve_meta(ctx: &XdpContext) {{
+	const META_LEN: usize = {0}::meta::META_LEN;
+
+	if ctx.metadata() != ctx.data() {{
+		return;
+	}}
+
+	if unsafe {{ bpf_xdp_adjust_meta(ctx.ctx, -(META_LEN as i32)) }} < 0 {{
+		return;
+	}}
+
+	let s_ptr = ctx.metadata();
+	if s_ptr + META_LEN > ctx.data() {{
+		return;
+	}}
+
+	unsafe {{ core::ptr::write_bytes(s_ptr as *mut u8, 0, META_LEN) }};
+}}
+
+/// Picks a branch at random, in proportion to each's weight in `split_map`.
+///
+/// Keeps `out` if no branch has any weight.
+#[inline(always)]
+#[allow(dead_code)]
+fn split_branch(out: u32) -> u32 {{
+	let mut total = 0u32;
+	for i in 0..{2} {{
+		total = total.wrapping_add(SPLIT_MAP.get(i).copied().unwrap_or(0));
+	}}
+
+	if total == 0 {{
+		return out;
+	}}
+
+	let mut pick = unsafe {{ bpf_get_prandom_u32() }} % total;
+	for i in 0..{2} {{
+		let weight = SPLIT_MAP.get(i).copied().unwrap_or(0);
+		if pick < weight {{
+			return i;
+		}}
+		pick -= weight;
+	}}
+
+	out
+}}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {{
+	unsafe {{ core::hint::unreachable_unchecked() }}
+}}
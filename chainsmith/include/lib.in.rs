@@ -5,9 +5,10 @@
 use {0}::RawMap;
 
 #[no_mangle]
-pub fn user_nf_program(pkt: &mut [u8], maps: &mut [RawMap]) -> usize {{
+pub fn user_nf_program(pkt: &mut [u8], meta: &mut [u8], maps: &mut [RawMap]) -> usize {{
 	// don't need to hardcast Maps, but do need to wrap them I assume?
 	{1}
 
-	{0}::packet(pkt,{2}) as usize
+	let pkt = {0}::UserPacket::new(pkt, meta);
+	{0}::packet({3},{2}) as usize
 }}
@@ -0,0 +1,22 @@
+// Every userland NF in the chain, linked statically. Each NF's wrapper matches
+// `lib.in.rs`, and is dispatched to on the output of the last.
+use {0}::RawMap;
+{1}
+#[no_mangle]
+pub fn user_chain_program(
+	mut nf: usize,
+	pkt: &mut [u8],
+	meta: &mut [u8],
+	maps: &mut [Vec<RawMap>],
+) -> bool {{
+	loop {{
+		let nf_maps = match maps.get_mut(nf) {{
+			Some(nf_maps) => &mut nf_maps[..],
+			None => return false,
+		}};
+
+		nf = match nf {{
+{2}			_ => return false,
+		}};
+	}}
+}}
@@ -11,10 +11,9 @@ static mut my_id_map: Array<u32> = Array::with_max_entries(1);
 
 #[xdp]
 fn xdp_sock_prog(ctx: XdpContext) -> XdpResult {{
-	// TODO: optionally limit using sz param {1:?}
 	{3}
 
-	let out = {0}::packet(&ctx,{4});
+	let out = {0}::packet({1},{4});
 
 	Ok(XdpAction::Tx)
 }}
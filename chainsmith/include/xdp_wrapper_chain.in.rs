@@ -2,7 +2,7 @@
 #![no_main]
 use cty::*;
 use redbpf_probes::{{
-	bindings::bpf_func_id_BPF_FUNC_xdp_adjust_meta,
+	bindings::{{bpf_func_id_BPF_FUNC_perf_event_output, bpf_func_id_BPF_FUNC_xdp_adjust_meta}},
 	maps::*,
 	xdp::prelude::*,
 }};
@@ -13,6 +13,14 @@ type ProgId = u32;
 struct DataplaneState {{
 	prog_id: ProgId,
 	num_cores: u32,
+	steering: u32,
+}}
+
+/// Precedes each packet copied to `mirror_map` (see `protocol::MIRROR_MAP`).
+#[repr(C)]
+struct MirrorHeader {{
+	len: u32,
+	meta: [u8; {0}::meta::META_LEN],
 }}
 
 program!(0xFFFFFFFE, "GPL");
@@ -23,22 +31,31 @@ static mut acts_map: Array<u8> = Array::with_max_entries({2});
 #[map(link_section = "maps")]
 static mut progs_map: ProgramArray = ProgramArray::with_max_entries({2});
 
+#[map(link_section = "maps")]
+static mut split_map: Array<u32> = Array::with_max_entries({2});
+
 #[map(link_section = "maps")]
 static mut my_state_map: Array<DataplaneState> = Array::with_max_entries(1);
 
 #[map(link_section = "maps")]
 static mut xsk_map: XskMap = XskMap::with_max_entries(8);
 
+// Sized to the number of CPUs by libbpf.
+#[map(link_section = "maps")]
+static mut mirror_map: PerfMap<MirrorHeader> = PerfMap::with_max_entries(0);
+
 {3}
 
 #[xdp]
 fn xdp_sock_prog(mut ctx: XdpContext) -> XdpResult {{
-	// TODO: automatically limit using size param {1:?}
+	reserve_meta(&ctx);
 	{4}
 
-	let out: u32 = {0}::packet(&ctx,{5}) as u32;
+	let out: u32 = {0}::packet({1},{5}) as u32;
+	{6}
+	{7}
 
-	match unsafe {{ acts_map.get(out) }} {{
+	match {8} {{
 		// tx
 		Some(0) => Ok(XdpAction::Tx),
 		// drop
@@ -46,51 +63,14 @@ fn xdp_sock_prog(mut ctx: XdpContext) -> XdpResult {{
 		// abort
 		Some(2) => Ok(XdpAction::Aborted),
 		// upcall
-		Some(3) => {{
-            const EXTRA_BYTES: c_int = (core::mem::size_of::<ProgId>() + core::mem::size_of::<u32>()) as c_int;
-			// expand meta
-			let r_val = unsafe {{
-				let bpf_xdp_adjust_meta: unsafe extern "C" fn(
-					ctx: *mut c_void,
-					delta: c_int,
-				) -> i64 = core::mem::transmute(bpf_func_id_BPF_FUNC_xdp_adjust_meta as usize);
-				bpf_xdp_adjust_meta(ctx.ctx as *mut c_void, -EXTRA_BYTES)
-			}};
-
-            let s_ptr = unsafe {{ (*ctx.ctx).data_meta }} as usize;
-            let n_ptr = s_ptr + (EXTRA_BYTES as usize);
-            let e_ptr = unsafe {{ (*ctx.ctx).data }} as usize;
-
-			if r_val >= 0 && n_ptr <= e_ptr {{
-				Ok(unsafe {{
-					let state = my_state_map.get(0).unwrap();
-
-					let target_core = if state.num_cores != 1 {{
-						bpf_get_prandom_u32() % state.num_cores
-					}} else {{
-						0
-					}};
-					
-					core::ptr::write(s_ptr as *mut ProgId, state.prog_id);
-					core::ptr::write((s_ptr + core::mem::size_of::<ProgId>()) as *mut u32, out);
-
-					// redirect into xsk_map
-					xsk_map
-						.redirect(target_core)
-						.map(|_| XdpAction::Redirect)
-						.unwrap_or(XdpAction::Aborted)
-				}})
-			}} else {{
-				Ok(XdpAction::Aborted)
-			}}
-		}},
+		Some(3) => Ok(upcall(&ctx, out)),
 		// tailcall
 		Some(4) => {{
 			unsafe {{
 				progs_map.tail_call(ctx.ctx, out)
 			}};
 
-			Ok(XdpAction::Aborted)
+			{9}
 		}},
 		Some(5) => {{
 			Ok(XdpAction::Pass)
@@ -98,3 +78,153 @@ fn xdp_sock_prog(mut ctx: XdpContext) -> XdpResult {{
 		_ => unsafe {{ Err(NetworkError::OutOfBounds) }},
 	}}
 }}
+
+#[inline(always)]
+fn upcall(ctx: &XdpContext, out: u32) -> XdpAction {{
+	const EXTRA_BYTES: c_int = (core::mem::size_of::<ProgId>() + core::mem::size_of::<u32>()) as c_int;
+
+	// expand meta
+	let r_val = unsafe {{
+		let bpf_xdp_adjust_meta: unsafe extern "C" fn(
+			ctx: *mut c_void,
+			delta: c_int,
+		) -> i64 = core::mem::transmute(bpf_func_id_BPF_FUNC_xdp_adjust_meta as usize);
+		bpf_xdp_adjust_meta(ctx.ctx as *mut c_void, -EXTRA_BYTES)
+	}};
+
+	let s_ptr = unsafe {{ (*ctx.ctx).data_meta }} as usize;
+	let n_ptr = s_ptr + (EXTRA_BYTES as usize);
+	let e_ptr = unsafe {{ (*ctx.ctx).data }} as usize;
+
+	if r_val < 0 || n_ptr > e_ptr {{
+		return XdpAction::Aborted;
+	}}
+
+	unsafe {{
+		let state = my_state_map.get(0).unwrap();
+
+		let target_core = if state.num_cores != 1 {{
+			let hash = match state.steering {{
+				// random
+				1 => bpf_get_prandom_u32(),
+				// flow
+				_ => {0}::flow_hash(&mut &*ctx),
+			}};
+			hash % state.num_cores
+		}} else {{
+			0
+		}};
+
+		core::ptr::write(s_ptr as *mut ProgId, state.prog_id);
+		core::ptr::write((s_ptr + core::mem::size_of::<ProgId>()) as *mut u32, out);
+
+		// redirect into xsk_map
+		xsk_map
+			.redirect(target_core)
+			.map(|_| XdpAction::Redirect)
+			.unwrap_or(XdpAction::Aborted)
+	}}
+}}
+
+/// Returns whether to copy a packet to its mirror NF, given the chance
+/// `sample` (as a fraction of `u32::MAX`) of doing so.
+#[inline(always)]
+#[allow(dead_code)]
+fn mirror_sampled(sample: u32) -> bool {{
+	sample == u32::MAX || bpf_get_prandom_u32() < sample
+}}
+
+/// Copies the packet and its metadata to userland via `mirror_map`, for the
+/// mirror NF. The packet itself is untouched, and keeps its NF's action.
+#[inline(always)]
+#[allow(dead_code)]
+fn mirror(ctx: &XdpContext) {{
+	const META_LEN: usize = {0}::meta::META_LEN;
+	const BPF_F_CURRENT_CPU: u64 = 0xffff_ffff;
+
+	unsafe {{
+		let s_ptr = (*ctx.ctx).data_meta as usize;
+		let d_ptr = (*ctx.ctx).data as usize;
+		let mut header = MirrorHeader {{
+			len: ((*ctx.ctx).data_end as usize - d_ptr) as u32,
+			meta: [0; META_LEN],
+		}};
+
+		if s_ptr + META_LEN <= d_ptr {{
+			core::ptr::copy_nonoverlapping(s_ptr as *const u8, header.meta.as_mut_ptr(), META_LEN);
+		}}
+
+		// The perf record is followed by the first `len` bytes of the packet.
+		let bpf_perf_event_output: unsafe extern "C" fn(
+			ctx: *mut c_void,
+			map: *mut c_void,
+			flags: u64,
+			data: *mut c_void,
+			size: u64,
+		) -> i64 = core::mem::transmute(bpf_func_id_BPF_FUNC_perf_event_output as usize);
+		bpf_perf_event_output(
+			ctx.ctx as *mut c_void,
+			&mut mirror_map as *mut _ as *mut c_void,
+			(u64::from(header.len) << 32) | BPF_F_CURRENT_CPU,
+			&mut header as *mut _ as *mut c_void,
+			core::mem::size_of::<MirrorHeader>() as u64,
+		);
+	}}
+}}
+
+/// Picks a branch at random, in proportion to each's weight in `split_map`.
+///
+/// Keeps `out` if no branch has any weight.
+#[inline(always)]
+#[allow(dead_code)]
+fn split_branch(out: u32) -> u32 {{
+	unsafe {{
+		let mut total = 0u32;
+		for i in 0..{2} {{
+			total = total.wrapping_add(split_map.get(i).copied().unwrap_or(0));
+		}}
+
+		if total == 0 {{
+			return out;
+		}}
+
+		let mut pick = bpf_get_prandom_u32() % total;
+		for i in 0..{2} {{
+			let weight = split_map.get(i).copied().unwrap_or(0);
+			if pick < weight {{
+				return i;
+			}}
+			pick -= weight;
+		}}
+	}}
+
+	out
+}}
+
+/// Reserves and zeroes `nf::meta` space ahead of the packet, unless an
+/// earlier NF in the chain already has.
+#[inline(always)]
+fn reserve_meta(ctx: &XdpContext) {{
+	const META_LEN: usize = {0}::meta::META_LEN;
+
+	unsafe {{
+		if (*ctx.ctx).data_meta != (*ctx.ctx).data {{
+			return;
+		}}
+
+		let bpf_xdp_adjust_meta: unsafe extern "C" fn(
+			ctx: *mut c_void,
+			delta: c_int,
+		) -> i64 = core::mem::transmute(bpf_func_id_BPF_FUNC_xdp_adjust_meta as usize);
+		if bpf_xdp_adjust_meta(ctx.ctx as *mut c_void, -(META_LEN as c_int)) < 0 {{
+			return;
+		}}
+
+		let s_ptr = (*ctx.ctx).data_meta as usize;
+		if s_ptr + META_LEN > (*ctx.ctx).data as usize {{
+			return;
+		}}
+
+		core::ptr::write_bytes(s_ptr as *mut u8, 0, META_LEN);
+	}}
+}}
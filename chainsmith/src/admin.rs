@@ -0,0 +1,288 @@
+use std::{
+	collections::BTreeMap,
+	convert::Infallible,
+	net::SocketAddr,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use hyper::{
+	body,
+	header::CONTENT_TYPE,
+	service::{make_service_fn, service_fn},
+	Body,
+	Method,
+	Request,
+	Response,
+	Server,
+	StatusCode,
+};
+use serde::Serialize;
+use tokio::sync::{mpsc, watch};
+use uuid::Uuid;
+
+use crate::{
+	chain::Chain,
+	error::MapUpdateError,
+	placement::{self, PlacementLimits, PlacementOverrides},
+	updates::{MapUpdateHub, RawMapOp},
+	watch::BuildStatus,
+	ChainDirs,
+	ChainSet,
+};
+
+/// Time allowed for every client to acknowledge a map update made over the admin API.
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Handles shared between the admin API and the rest of chainsmith.
+#[derive(Clone)]
+pub struct AdminState {
+	pub chains: watch::Receiver<ChainSet>,
+	pub hub: MapUpdateHub,
+	pub status: Arc<Mutex<BuildStatus>>,
+	/// Requests a rebuild of all chains.
+	pub rebuild: mpsc::UnboundedSender<()>,
+	/// Where each chain is built from, so that NF placements can be stored.
+	pub chain_dirs: ChainDirs,
+	/// Costs above which proposed placements move NFs to userland.
+	pub limits: PlacementLimits,
+}
+
+#[derive(Serialize)]
+struct ChainSummary {
+	targets: BTreeMap<String, TargetSummary>,
+}
+
+#[derive(Serialize)]
+struct TargetSummary {
+	nfs: BTreeMap<String, Uuid>,
+	/// Key and value types of each map which can be updated, per NF.
+	maps: BTreeMap<String, BTreeMap<String, MapSummary>>,
+}
+
+#[derive(Serialize)]
+struct MapSummary {
+	key: String,
+	value: String,
+}
+
+#[derive(Serialize)]
+struct AckSummary {
+	client: SocketAddr,
+	error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+	error: String,
+}
+
+/// Serves the admin HTTP/JSON API on `addr` until an error occurs.
+///
+/// Routes:
+///  * `GET /clients`: connected clients, their certificate identity, and chain.
+///  * `GET /chains`: the NFs and updatable maps of every served chain and target.
+///  * `GET /status`: outcome of the latest build.
+///  * `POST /rebuild`: rebuild all chains, pushing the results to clients.
+///  * `POST /chains/<chain>/nfs/<nf>/maps/<map>`: apply a map update (e.g.,
+///    `{"insert": [{"key": "10.0.0.1", "value": true}]}`) to every client running
+///    `<chain>`, and report each client's outcome.
+///  * `GET /chains/<chain>/placement`: propose whether each NF in `<chain>` should
+///    run in eBPF or userland, from the costs reported by clients.
+///  * `POST /chains/<chain>/placement`: store the proposed placement in the chain's
+///    `placement.toml`, and rebuild all chains if any NF moved.
+pub async fn serve(addr: SocketAddr, state: AdminState) -> anyhow::Result<()> {
+	let make_svc = make_service_fn(move |_| {
+		let state = state.clone();
+		async move {
+			Ok::<_, Infallible>(service_fn(move |req| {
+				let state = state.clone();
+				async move { Ok::<_, Infallible>(handle(req, state).await) }
+			}))
+		}
+	});
+
+	let server = Server::try_bind(&addr)?.serve(make_svc);
+	println!("Admin API listening on http://{addr}.");
+
+	Ok(server.await?)
+}
+
+async fn handle(req: Request<Body>, state: AdminState) -> Response<Body> {
+	let path = req.uri().path().to_string();
+	let path: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+
+	match (req.method(), path.as_slice()) {
+		(&Method::GET, ["clients"]) => json(StatusCode::OK, &state.hub.clients()),
+		(&Method::GET, ["chains"]) => json(StatusCode::OK, &summarise(&state.chains.borrow())),
+		(&Method::GET, ["status"]) => {
+			let status = state.status.lock().unwrap().clone();
+			json(StatusCode::OK, &status)
+		},
+		(&Method::POST, ["rebuild"]) => match state.rebuild.send(()) {
+			Ok(()) => json(StatusCode::ACCEPTED, &state.status.lock().unwrap().clone()),
+			Err(_) => error(
+				StatusCode::SERVICE_UNAVAILABLE,
+				"chainsmith is no longer rebuilding chains".into(),
+			),
+		},
+		(&Method::POST, ["chains", chain, "nfs", nf, "maps", map]) =>
+			update_map(req, state, chain, nf, map).await,
+		(&Method::GET, ["chains", chain, "placement"]) => placement(state, chain, false).await,
+		(&Method::POST, ["chains", chain, "placement"]) => placement(state, chain, true).await,
+		_ => error(StatusCode::NOT_FOUND, "no such route".into()),
+	}
+}
+
+async fn update_map(
+	req: Request<Body>,
+	state: AdminState,
+	chain: &str,
+	nf: &str,
+	map: &str,
+) -> Response<Body> {
+	let op: RawMapOp = match body::to_bytes(req.into_body()).await {
+		Ok(bytes) => match serde_json::from_slice(&bytes) {
+			Ok(op) => op,
+			Err(e) => return error(StatusCode::BAD_REQUEST, format!("invalid map update: {e}")),
+		},
+		Err(e) => return error(StatusCode::BAD_REQUEST, format!("failed to read body: {e}")),
+	};
+
+	let chains = state.chains.borrow().clone();
+
+	match state
+		.hub
+		.apply(&chains, chain, nf, map, &op, ACK_TIMEOUT)
+		.await
+	{
+		Ok(acks) => {
+			let acks: Vec<_> = acks
+				.into_iter()
+				.map(|ack| AckSummary {
+					client: ack.client,
+					error: ack.result.err(),
+				})
+				.collect();
+
+			json(StatusCode::OK, &acks)
+		},
+		Err(e @ (MapUpdateError::UnknownChain(_) | MapUpdateError::UnknownMap { .. })) =>
+			error(StatusCode::NOT_FOUND, e.to_string()),
+		Err(e @ MapUpdateError::BadEntry { .. }) => error(StatusCode::BAD_REQUEST, e.to_string()),
+	}
+}
+
+/// Proposes a placement for each NF in `chain`, storing it if `apply` is set.
+async fn placement(state: AdminState, chain: &str, apply: bool) -> Response<Body> {
+	let chain_dir = match state.chain_dirs.iter().find(|(name, _)| name == chain) {
+		Some((_, dir)) => dir,
+		None => return error(StatusCode::NOT_FOUND, format!("no chain named `{chain}`")),
+	};
+
+	let config = match Chain::load(chain_dir).await {
+		Ok(config) => config,
+		Err(e) =>
+			return error(
+				StatusCode::INTERNAL_SERVER_ERROR,
+				format!("failed to load chain: {e:#}"),
+			),
+	};
+
+	let chains = state.chains.borrow().clone();
+	let costs = state.hub.costs(&chains, chain);
+	let proposal = placement::propose_placement(&config, &costs, &state.limits);
+
+	let moved: Vec<_> = proposal
+		.iter()
+		.filter(|(_, nf)| nf.proposed != nf.current)
+		.map(|(name, nf)| (name.clone(), nf.proposed))
+		.collect();
+
+	if !apply || moved.is_empty() {
+		return json(StatusCode::OK, &proposal);
+	}
+
+	let stored = match PlacementOverrides::load(chain_dir).await {
+		Ok(mut overrides) => {
+			overrides.nfs.extend(moved);
+			overrides.store(chain_dir).await
+		},
+		Err(e) => Err(e),
+	};
+
+	if let Err(e) = stored {
+		return error(
+			StatusCode::INTERNAL_SERVER_ERROR,
+			format!("failed to store placement: {e:#}"),
+		);
+	}
+
+	match state.rebuild.send(()) {
+		Ok(()) => json(StatusCode::ACCEPTED, &proposal),
+		Err(_) => error(
+			StatusCode::SERVICE_UNAVAILABLE,
+			"placement stored, but chainsmith is no longer rebuilding chains".into(),
+		),
+	}
+}
+
+fn summarise(chains: &ChainSet) -> BTreeMap<String, ChainSummary> {
+	chains
+		.iter()
+		.map(|(name, builds)| {
+			let targets = builds
+				.iter()
+				.map(|(target, build)| {
+					let maps = build
+						.map_types
+						.iter()
+						.map(|(nf, maps)| {
+							let maps = maps
+								.iter()
+								.map(|(map, (key, value))| {
+									let summary = MapSummary {
+										key: key.to_string(),
+										value: value.to_string(),
+									};
+									(map.clone(), summary)
+								})
+								.collect();
+							(nf.clone(), maps)
+						})
+						.collect();
+
+					let summary = TargetSummary {
+						nfs: build.name_to_uuid.clone().into_iter().collect(),
+						maps,
+					};
+
+					(target.clone(), summary)
+				})
+				.collect();
+
+			(name.clone(), ChainSummary { targets })
+		})
+		.collect()
+}
+
+fn json(status: StatusCode, body: &impl Serialize) -> Response<Body> {
+	match serde_json::to_vec(body) {
+		Ok(body) => Response::builder()
+			.status(status)
+			.header(CONTENT_TYPE, "application/json")
+			.body(Body::from(body))
+			.unwrap(),
+		Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+	}
+}
+
+fn error(status: StatusCode, error: String) -> Response<Body> {
+	let body = serde_json::to_vec(&ErrorBody { error }).unwrap_or_default();
+
+	Response::builder()
+		.status(status)
+		.header(CONTENT_TYPE, "application/json")
+		.body(Body::from(body))
+		.unwrap()
+}
@@ -0,0 +1,150 @@
+use std::{
+	fmt::{Display, Formatter, Result as FmtResult},
+	io::Error as IoError,
+	path::{Path, PathBuf},
+	sync::atomic::{AtomicU64, Ordering},
+};
+
+use ring::digest::{Context, SHA256, SHA256_OUTPUT_LEN};
+use tokio::fs;
+
+/// Directories which never contribute to an NF's build output.
+pub(crate) const IGNORED_DIRS: &[&str] = &["target", "tmp", "cache"];
+
+/// Distinguishes in-flight writes when several targets build concurrently.
+static PART_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Content hash identifying a single compiled artifact in a [`BuildCache`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ArtifactKey([u8; SHA256_OUTPUT_LEN]);
+
+impl ArtifactKey {
+	/// Start a new key for an artifact of the given `kind` (e.g., `"xdp"`).
+	pub fn builder(kind: &str) -> KeyBuilder {
+		let mut out = KeyBuilder {
+			ctx: Context::new(&SHA256),
+		};
+
+		out.field("kind", kind.as_bytes())
+			.field("chainsmith", env!("CARGO_PKG_VERSION").as_bytes());
+
+		out
+	}
+
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.0[..]
+	}
+}
+
+impl Display for ArtifactKey {
+	fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+		for byte in self.0 {
+			write!(f, "{byte:02x}")?;
+		}
+
+		Ok(())
+	}
+}
+
+pub struct KeyBuilder {
+	ctx: Context,
+}
+
+impl KeyBuilder {
+	/// Mix a named input into the key.
+	///
+	/// Names and values are length-prefixed, so that adjacent fields cannot
+	/// be confused with one another.
+	pub fn field(&mut self, name: &str, value: &[u8]) -> &mut Self {
+		for part in [name.as_bytes(), value] {
+			self.ctx.update(&(part.len() as u64).to_le_bytes());
+			self.ctx.update(part);
+		}
+
+		self
+	}
+
+	/// Mix the contents of every file below `root` into the key.
+	///
+	/// Files are visited in sorted order by their path relative to `root`, and
+	/// build output or hidden directories are skipped.
+	pub async fn dir(&mut self, root: &Path) -> Result<&mut Self, IoError> {
+		let mut files = vec![];
+		let mut to_visit = vec![root.to_path_buf()];
+
+		while let Some(dir) = to_visit.pop() {
+			let mut entries = fs::read_dir(&dir).await?;
+
+			while let Some(entry) = entries.next_entry().await? {
+				let path = entry.path();
+				let name = entry.file_name();
+				let name = name.to_string_lossy();
+
+				if entry.file_type().await?.is_dir() {
+					if !(name.starts_with('.') || IGNORED_DIRS.contains(&name.as_ref())) {
+						to_visit.push(path);
+					}
+				} else {
+					files.push(path);
+				}
+			}
+		}
+
+		files.sort();
+
+		for file in files {
+			let contents = fs::read(&file).await?;
+			let rel_path = file.strip_prefix(root).unwrap_or(&file);
+
+			self.field(&rel_path.to_string_lossy(), &contents);
+		}
+
+		Ok(self)
+	}
+
+	pub fn finish(&mut self) -> ArtifactKey {
+		let digest = self.ctx.clone().finish();
+
+		let mut out = [0u8; SHA256_OUTPUT_LEN];
+		out.copy_from_slice(digest.as_ref());
+
+		ArtifactKey(out)
+	}
+}
+
+/// Persistent store of compiled eBPF ELFs and userland dylibs, indexed by
+/// the hash of everything which went into building them.
+pub struct BuildCache {
+	dir: PathBuf,
+}
+
+impl BuildCache {
+	pub async fn open(dir: PathBuf) -> Result<Self, IoError> {
+		fs::create_dir_all(&dir).await?;
+
+		Ok(Self { dir })
+	}
+
+	fn path_of(&self, key: &ArtifactKey) -> PathBuf {
+		self.dir.join(key.to_string())
+	}
+
+	pub async fn contains(&self, key: &ArtifactKey) -> bool {
+		fs::metadata(self.path_of(key)).await.is_ok()
+	}
+
+	pub async fn get(&self, key: &ArtifactKey) -> Result<Vec<u8>, IoError> {
+		fs::read(self.path_of(key)).await
+	}
+
+	pub async fn put(&self, key: &ArtifactKey, data: &[u8]) -> Result<(), IoError> {
+		// Write then move, so that an interrupted build never leaves a
+		// truncated artifact under a valid key.
+		let final_path = self.path_of(key);
+		let part = PART_COUNTER.fetch_add(1, Ordering::Relaxed);
+		let tmp_path = self.dir.join(format!("{key}.{part}.part"));
+
+		fs::write(&tmp_path, data).await?;
+		fs::rename(&tmp_path, &final_path).await
+	}
+}
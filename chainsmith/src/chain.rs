@@ -1,13 +1,25 @@
 use std::{
-	collections::{BTreeMap, HashMap},
+	collections::{BTreeMap, BTreeSet, HashMap, HashSet},
 	fmt::Write as _,
-	path::PathBuf,
+	io::{Error as IoError, ErrorKind},
+	path::{Path, PathBuf},
 };
 
 use convert_case::{Case, Casing};
-use protocol::{EbpfFunction, Function as PFunction, LinkAction, XdpLink, XdpLinkState};
+use protocol::{
+	fused_map_prefix,
+	EbpfFunction,
+	Function as PFunction,
+	Hook,
+	LinkAction,
+	MapEntry,
+	Mirror as PMirror,
+	XdpLink,
+	XdpLinkState,
+	SPLIT_WEIGHTS_MAP,
+};
 use serde::Deserialize;
-use syn::{FnArg, Ident, Item, ReturnType, Type};
+use syn::{Fields, FnArg, Ident, Item, ReturnType, Type};
 use tokio::{
 	fs::{self, File},
 	io::{self, AsyncWriteExt},
@@ -15,11 +27,31 @@ use tokio::{
 };
 use uuid::Uuid;
 
-use super::error::*;
+use super::{
+	cache::{ArtifactKey, BuildCache},
+	config::TargetConfig,
+	entries::{DatumType, MapEntries},
+	error::*,
+	placement::PlacementOverrides,
+};
+
+/// Userland crate (and package `{USER_CHAIN_CRATE}-user`) built for the whole
+/// chain when `fuse_userland` is set.
+pub const USER_CHAIN_CRATE: &str = "chain";
+
+/// Initial map contents for each NF, keyed on NF name then map name.
+pub type ChainMapEntries = HashMap<String, BTreeMap<String, Vec<MapEntry>>>;
+
+/// Key and value types for each NF's maps, keyed on NF name then map name.
+///
+/// Only maps whose types have a known layout are included.
+pub type MapTypes = HashMap<String, BTreeMap<String, (DatumType, DatumType)>>;
 
 pub struct FnAnalysis {
 	ret_ty: NfReturnType,
 	map_ty_name: Option<String>,
+	/// Key and value types of each map, where these have a known layout.
+	map_kv_types: BTreeMap<String, (Option<DatumType>, Option<DatumType>)>,
 }
 
 #[derive(Debug)]
@@ -39,6 +71,24 @@ impl NfReturnType {
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Chain {
+	/// Toolchain used to build this chain's eBPF NFs.
+	#[serde(default)]
+	pub backend: EbpfBackend,
+	/// Where the chain's root NF is attached on each client.
+	#[serde(default)]
+	pub hook: Hook,
+	/// Inline straight-line runs of eBPF NFs into one program, rather than
+	/// tail calling between them.
+	#[serde(default)]
+	pub fuse: bool,
+	/// Build all userland NFs into one library, which dispatches between them
+	/// statically.
+	#[serde(default)]
+	pub fuse_userland: bool,
+	/// Compile each NF's actions into its eBPF program, rather than reading
+	/// them from `acts_map` as each packet leaves it.
+	#[serde(default)]
+	pub bake_actions: bool,
 	pub functions: BTreeMap<String, Function>,
 	pub links: Vec<Link>,
 	#[serde(default)]
@@ -46,9 +96,39 @@ pub struct Chain {
 }
 
 impl Chain {
+	/// Reads and parses the `chain.toml` within `chain_dir`, moving NFs as
+	/// recorded in its `placement.toml`.
+	pub async fn load(chain_dir: &Path) -> anyhow::Result<Self> {
+		let config_bytes = fs::read(chain_dir.join("chain.toml")).await?;
+		let mut chain: Self = toml::from_slice(&config_bytes)?;
+
+		PlacementOverrides::load(chain_dir).await?.apply(&mut chain);
+
+		Ok(chain)
+	}
+
+	/// Checks that this chain's backend can build programs for its hook.
+	pub fn check_hook(&self) -> Result<(), ChainBuildError> {
+		if self.hook.is_tc() && self.backend != EbpfBackend::Aya {
+			return Err(ChainBuildError::TcNeedsAya);
+		}
+
+		Ok(())
+	}
+
+	/// Locations of every NF crate used by this chain.
+	pub fn crate_dirs(&self, chain_toml_parent_dir: &Path) -> Vec<PathBuf> {
+		self.functions
+			.iter()
+			.map(|(name, info)| chain_toml_parent_dir.join(info.path.as_ref().unwrap_or(name)))
+			.collect()
+	}
+
 	pub async fn generate_xdp_cargo_toml(&self, mut base_dir: PathBuf) -> anyhow::Result<()> {
 		let mut deps = vec![];
 		let mut bins = vec![];
+		let runs = self.fused_runs();
+		let members = fused_members(&runs);
 
 		for (name, props) in &self.functions {
 			if props.disable_xdp {
@@ -57,9 +137,16 @@ impl Chain {
 
 			let path = props.path.as_ref().unwrap_or(name);
 			deps.push(format!(
-				"{0} = {{ version = \"*\", path = \"../../{1}\", features = [\"xdp\"] }}\n",
-				name, path,
+				"{0} = {{ version = \"*\", path = \"../../../{1}\", features = [\"{2}\"] }}\n",
+				name,
+				path,
+				self.backend.nf_feature(),
 			));
+
+			// Fused NFs have no program of their own.
+			if members.contains(name.as_str()) {
+				continue;
+			}
 			bins.push(format!(
 				r#"
 [[bin]]
@@ -80,9 +167,7 @@ path = "src/{0}/chain.rs""#,
 
 		let mut cargo = File::create(&base_dir).await?;
 
-		cargo
-			.write_all(include_bytes!("../include/Cargo.xdp.in.toml"))
-			.await?;
+		cargo.write_all(self.backend.cargo_toml()).await?;
 		for dep in deps {
 			cargo.write_all(dep.as_bytes()).await?;
 		}
@@ -105,7 +190,7 @@ path = "src/{0}/chain.rs""#,
 			.write_all("[workspace]\nmembers = [\n".as_bytes())
 			.await?;
 
-		for name in self.functions.keys() {
+		for name in self.userland_crates() {
 			cargo.write_all(b"\"").await?;
 			cargo.write_all(name.as_bytes()).await?;
 			cargo.write_all(b"\",\n").await?;
@@ -239,15 +324,115 @@ codegen-units = 1
 				return Err(SourceParseError::CantResolveReturnType(name.clone()));
 			};
 
+			let map_kv_types = map_ty_name
+				.as_ref()
+				.and_then(|map_ty_name| {
+					syn.items.iter().find_map(|el| match el {
+						Item::Struct(s) if s.ident == map_ty_name => Some(s),
+						_ => None,
+					})
+				})
+				.map(|map_struct| {
+					let mut out = BTreeMap::new();
+
+					if let Fields::Named(fields) = &map_struct.fields {
+						for field in &fields.named {
+							if let (Some(ident), Type::Tuple(kv)) = (&field.ident, &field.ty) {
+								let mut kv = kv.elems.iter().map(DatumType::from_syn);
+								let key = kv.next().flatten();
+								let value = kv.next().flatten();
+
+								out.insert(ident.to_string(), (key, value));
+							}
+						}
+					}
+
+					out
+				})
+				.unwrap_or_default();
+
 			fn_retvals.push(FnAnalysis {
 				ret_ty,
 				map_ty_name,
+				map_kv_types,
 			});
 		}
 
 		Ok(fn_retvals)
 	}
 
+	/// Generates the statics and map struct passed to one NF's `packet` function.
+	///
+	/// Returns the map definitions, the statement binding `chain_map_def`, and
+	/// the parameter passing it. Map statics are prefixed with `prefix`, so that
+	/// several NFs can share one program.
+	fn xdp_map_defs(
+		&self,
+		name: &str,
+		info: &Function,
+		fn_analysis: &FnAnalysis,
+		prefix: &str,
+	) -> (String, String, &'static str) {
+		let canon_name = name.replace('-', "_");
+
+		// TODO: define these based on the actual NF definition
+		let mut map_defs = String::new();
+		let mut map_struct_def = String::new();
+		let mut map_param = "";
+
+		// non-empty maps + map type def'd is an err -- lack of each also fine.
+		if !(info.maps.is_empty() ^ fn_analysis.map_ty_name.is_none()) {
+			let mut fields = vec![];
+
+			for (map_i, (map_name, maybe_data)) in info.maps.iter().enumerate() {
+				let data = match maybe_data {
+					LocalMap::Owned(m) => m,
+					// TODO: handle cleanly (non-panic)
+					// FIXME: maybe use the shared str param as a rename mechanism?
+					LocalMap::Shared(_) => self.maps.get(map_name).unwrap(),
+				};
+
+				let def_name = format!("{prefix}{}", map_name.to_case(Case::ScreamingSnake));
+
+				data.r#type
+					.define_xdp(
+						&mut map_defs,
+						self.backend,
+						&def_name,
+						map_i,
+						data.size,
+						&canon_name,
+					)
+					.expect("String append should be infallible.");
+
+				fields.push(format!("{map_name}: {}{def_name}", self.backend.map_ref()));
+			}
+
+			if !fields.is_empty() {
+				// redbpf maps are `static mut`.
+				let (open, close) = match self.backend {
+					EbpfBackend::Redbpf => ("unsafe {", "}"),
+					EbpfBackend::Aya => ("", ""),
+				};
+
+				map_struct_def = format!(
+					"let chain_map_def = {open}{}::{} {{\n\t\t",
+					canon_name,
+					fn_analysis.map_ty_name.as_ref().unwrap()
+				) + &fields.join(",\n\t\t")
+					+ "\n\t}" + close
+					+ ";";
+
+				map_param = " chain_map_def";
+			}
+		} else {
+			// TODO: make err variant somewhere.
+			panic!("need both or none of: map type in output, maps assigned to fn");
+		}
+
+		(map_defs, map_struct_def, map_param)
+	}
+
 	pub async fn write_xdp_programs(
 		&self,
 		variants: &Vec<FnAnalysis>,
@@ -256,8 +441,83 @@ codegen-units = 1
 		// TODO: map transforms further up the chain so that they can all be included
 		// in a fairly generic way? I.e., specify how each adds an entry to toml AND
 		// selects a template to interp into.
-		for ((name, info), fn_analysis) in self.functions.iter().zip(variants) {
-			let canon_name = name.replace('-', "_");
+		let analyses: HashMap<_, _> = self.functions.keys().zip(variants).collect();
+		let runs = self.fused_runs();
+		let members = fused_members(&runs);
+
+		for (name, fn_analysis) in self.functions.keys().zip(variants) {
+			let my_links = self.links.iter().find(|v| &v.from == name);
+
+			match (&fn_analysis.ret_ty, my_links) {
+				// The output of a mirror NF is ignored, so it need not link anywhere.
+				(_, None) if self.is_mirror(name) => {},
+				(_, None) =>
+					return Err(WriteXdpError::BranchMismatch {
+						nf: name.clone(),
+						given_branches: 0,
+						needed_branches: fn_analysis.ret_ty.len(),
+					}),
+				// A split ignores its NF's output, which must then be trivial.
+				(NfReturnType::Enum(_retval_name, variants), Some(my_links))
+					if my_links.is_split() =>
+					if variants.len() != 1 {
+						return Err(WriteXdpError::SplitBranches {
+							nf: name.clone(),
+							needed_branches: variants.len(),
+						});
+					},
+				(NfReturnType::Enum(_retval_name, variants), Some(my_links)) => {
+					if variants.len() != my_links.to.len() {
+						return Err(WriteXdpError::BranchMismatch {
+							nf: name.clone(),
+							given_branches: my_links.to.len(),
+							needed_branches: variants.len(),
+						});
+					}
+				},
+				(NfReturnType::Empty, _) => {},
+			}
+
+			// Fused NFs are built into the program of the run they belong to.
+			if members.contains(name.as_str()) {
+				continue;
+			}
+
+			// Every NF in a fused run but the last runs straight into the next,
+			// and the last decides what happens to the packet.
+			let run = runs.get(name).map(Vec::as_slice).unwrap_or_default();
+			let last = run.last().unwrap_or(name);
+			let canon_name = last.replace('-', "_");
+
+			let mut map_defs = String::new();
+			let mut statements = vec![];
+			let mut map_param = "";
+			let mut packet = String::new();
+			for (position, nf) in [name].into_iter().chain(run).enumerate() {
+				let nf_packet = packet_view(nf, "&ctx", self.functions[nf].slice);
+				let (defs, struct_def, param) = self.xdp_map_defs(
+					nf,
+					&self.functions[nf],
+					analyses[nf],
+					&fused_map_prefix(position),
+				);
+
+				map_defs += &defs;
+				if !struct_def.is_empty() {
+					statements.push(struct_def);
+				}
+
+				if nf == last {
+					map_param = param;
+					packet = nf_packet;
+				} else {
+					statements.push(format!(
+						"let _ = {}::packet({nf_packet},{param});",
+						nf.replace('-', "_")
+					));
+				}
+			}
+			let map_struct_def = statements.join("\n\t");
 
 			src_path.push(name);
 			fs::create_dir(&src_path)
@@ -269,103 +529,289 @@ codegen-units = 1
 				.await
 				.map_err(WriteXdpError::CreateFile)?;
 
-			// TODO: define these based on the actual NF definition
-			let mut map_defs = String::new();
-			let mut map_struct_def = String::new();
-			let mut map_param = "";
-
-			// non-empty maps + map type def'd is an err -- lack of each also fine.
-			if !(info.maps.is_empty() ^ fn_analysis.map_ty_name.is_none()) {
-				let mut fields = vec![];
-
-				for (map_i, (map_name, maybe_data)) in info.maps.iter().enumerate() {
-					let data = match maybe_data {
-						LocalMap::Owned(m) => m,
-						// TODO: handle cleanly (non-panic)
-						// FIXME: maybe use the shared str param as a rename mechanism?
-						LocalMap::Shared(_) => self.maps.get(map_name).unwrap(),
-					};
-
-					let def_name = map_name.to_case(Case::ScreamingSnake);
-
-					data.r#type
-						.define_xdp(&mut map_defs, &def_name, map_i, data.size, &canon_name)
-						.expect("String append should be infallible.");
+			main_file
+				.write_all(
+					self.backend
+						.wrapper(
+							self.hook,
+							&canon_name,
+							&packet,
+							&map_defs,
+							&map_struct_def,
+							map_param,
+						)
+						.as_bytes(),
+				)
+				.await
+				.map_err(WriteXdpError::WriteFile)?;
 
-					fields.push(format!("{map_name}: &mut {def_name}"));
-				}
+			src_path.pop();
+			src_path.push("chain.rs");
 
-				if !fields.is_empty() {
-					map_struct_def = format!(
-						"let chain_map_def = unsafe {{{}::{} {{\n\t\t",
-						canon_name,
-						fn_analysis.map_ty_name.as_ref().unwrap()
-					) + &fields.join(",\n\t\t")
-						+ "\n\t}};";
+			let last_link = self.links.iter().find(|link| &link.from == last);
 
-					map_param = " chain_map_def";
-				}
-			} else {
-				// TODO: make err variant somewhere.
-				panic!("need both or none of: map type in output, maps assigned to fn");
-			}
+			// A split chooses between all of its link's targets.
+			let split = last_link.filter(|link| link.is_split());
+			let needed_slots = split
+				.map(|link| link.to.len())
+				.unwrap_or_else(|| analyses[last].ret_ty.len())
+				.next_power_of_two();
+			let acts = last_link
+				.filter(|_| self.bake_actions)
+				.map(|link| self.target_kinds(link));
 
-			main_file
+			let mut chain_file = File::create(&src_path)
+				.await
+				.map_err(WriteXdpError::CreateFile)?;
+			chain_file
 				.write_all(
-					format!(
-						include_str!("../include/xdp_wrapper.in.rs"),
-						canon_name, info.slice, map_defs, map_struct_def, map_param
-					)
-					.as_bytes(),
+					self.backend
+						.chain_wrapper(
+							self.hook,
+							&canon_name,
+							&packet,
+							needed_slots,
+							&map_defs,
+							&map_struct_def,
+							map_param,
+							split.is_some(),
+							last_link
+								.and_then(|link| link.mirror.as_ref())
+								.map(|mirror| mirror.sample),
+							acts.as_deref(),
+						)
+						.as_bytes(),
 				)
 				.await
 				.map_err(WriteXdpError::WriteFile)?;
 
 			src_path.pop();
-			src_path.push("chain.rs");
+			src_path.pop();
+		}
 
-			let needed_slots = fn_analysis.ret_ty.len().next_power_of_two();
+		Ok(())
+	}
 
-			let my_links = if let Some(ml) = self.links.iter().find(|v| &v.from == name) {
-				ml
-			} else {
-				return Err(WriteXdpError::BranchMismatch {
-					nf: name.clone(),
-					given_branches: 0,
-					needed_branches: fn_analysis.ret_ty.len(),
-				});
+	/// Finds the straight-line runs of eBPF NFs which are fused into one program,
+	/// if enabled by `fuse`.
+	///
+	/// Runs are keyed on their first NF, and list the NFs inlined after it.
+	/// An NF is inlined into its predecessor if that links only to it (and does
+	/// not mirror packets), and it is reached from nowhere else.
+	pub fn fused_runs(&self) -> BTreeMap<String, Vec<String>> {
+		let mut runs = BTreeMap::new();
+		if !self.fuse {
+			return runs;
+		}
+
+		let mut incoming: HashMap<&str, usize> = HashMap::new();
+		for link in &self.links {
+			for to in &link.to {
+				*incoming.entry(to.trim_start_matches('!')).or_default() += 1;
+			}
+		}
+
+		let is_ebpf = |name: &str| {
+			self.functions
+				.get(name)
+				.map(|f| !f.disable_xdp)
+				.unwrap_or(false)
+		};
+
+		// The NF inlined after `name`, if any.
+		let next = |name: &str| -> Option<&str> {
+			let link = self
+				.links
+				.iter()
+				.find(|link| link.from == name && link.mirror.is_none())?;
+			let to = match &link.to[..] {
+				[to] => to.as_str(),
+				_ => return None,
 			};
 
-			match &fn_analysis.ret_ty {
-				NfReturnType::Enum(_retval_name, variants) => {
-					if variants.len() != my_links.to.len() {
-						return Err(WriteXdpError::BranchMismatch {
-							nf: name.clone(),
-							given_branches: my_links.to.len(),
-							needed_branches: variants.len(),
-						});
-					}
-				},
-				NfReturnType::Empty => {},
+			(to != name
+				&& is_ebpf(to)
+				&& !self.functions[to].disable_fusion
+				&& incoming.get(to) == Some(&1))
+			.then_some(to)
+		};
+
+		let inlined: HashSet<&str> = self
+			.functions
+			.keys()
+			.filter(|name| is_ebpf(name))
+			.filter_map(|name| next(name))
+			.collect();
+
+		for name in self.functions.keys() {
+			if !is_ebpf(name) || inlined.contains(name.as_str()) {
+				continue;
 			}
 
-			let mut chain_file = File::create(&src_path)
-				.await
-				.map_err(WriteXdpError::CreateFile)?;
-			chain_file
+			let mut run = vec![];
+			let mut curr = name.as_str();
+			// Runs cannot loop, as no NF inlined into a run links to its head.
+			while let Some(to) = next(curr) {
+				run.push(to.to_string());
+				curr = to;
+			}
+
+			if !run.is_empty() {
+				runs.insert(name.clone(), run);
+			}
+		}
+
+		runs
+	}
+
+	/// Kinds (see [`LinkAction::to_kind`]) of the actions by which packets
+	/// reach each target of `link`, as resolved by [`Chain::make_concrete`].
+	///
+	/// Unknown targets abort, though `make_concrete` rejects them.
+	fn target_kinds(&self, link: &Link) -> Vec<u8> {
+		link.to
+			.iter()
+			.map(|name| {
+				self.target_action(name, |_| Some(Uuid::nil()))
+					.unwrap_or(LinkAction::Abort)
+					.to_kind()
+			})
+			.collect()
+	}
+
+	/// Resolves the action by which packets leaving a link reach its target
+	/// `name`, finding the ID of a target NF via `uuid_of`.
+	///
+	/// Targets prefixed with `!` are always upcalled. Returns `None` if `name`
+	/// is neither an NF nor a special target.
+	fn target_action(
+		&self,
+		name: &str,
+		uuid_of: impl Fn(&str) -> Option<Uuid>,
+	) -> Option<LinkAction> {
+		let force_upcall = name.starts_with('!');
+		let nf = &name[(force_upcall as usize)..];
+
+		if let Some(f) = self.functions.get(nf) {
+			let uuid = uuid_of(nf)?;
+
+			return Some(if force_upcall || f.disable_xdp {
+				LinkAction::Upcall(uuid)
+			} else {
+				LinkAction::Tailcall(uuid)
+			});
+		}
+
+		match name {
+			"tx" => Some(LinkAction::Tx),
+			"drop" => Some(LinkAction::Drop),
+			"pass" => Some(LinkAction::Pass),
+			"abort" => Some(LinkAction::Abort),
+			_ => None,
+		}
+	}
+
+	/// Returns whether `name` is the mirror of any link.
+	pub fn is_mirror(&self, name: &str) -> bool {
+		self.links
+			.iter()
+			.filter_map(|link| link.mirror.as_ref())
+			.any(|mirror| mirror.nf == name)
+	}
+
+	/// Generates the statement binding `chain_map_def` for one NF's userland
+	/// wrapper, and the parameter passing it, from a slice of `maps`.
+	fn user_map_defs(
+		&self,
+		name: &str,
+		info: &Function,
+		fn_analysis: &FnAnalysis,
+	) -> (String, &'static str) {
+		let canon_name = name.replace('-', "_");
+
+		// TODO: define these based on the actual NF definition
+		let mut map_struct_def = String::new();
+		let mut map_param = "";
+
+		// non-empty maps + map type def'd is an err -- lack of each also fine.
+		if !(info.maps.is_empty() ^ fn_analysis.map_ty_name.is_none()) {
+			let mut fields = vec![];
+			let mut defs = vec![];
+
+			for (map_i, (map_name, maybe_data)) in info.maps.iter().enumerate() {
+				let _data = match maybe_data {
+					LocalMap::Owned(m) => m,
+					// TODO: handle cleanly (non-panic)
+					// FIXME: maybe use the shared str param as a rename mechanism?
+					LocalMap::Shared(_) => self.maps.get(map_name).unwrap(),
+				};
+
+				fields.push(format!("{map_name}: m{map_i}"));
+				defs.push(format!("m{map_i}"));
+			}
+
+			if !fields.is_empty() {
+				// Want to have:
+				// if let [m0, m1, m2, ..] = maps { .. } else { panic!() }
+				map_struct_def = "let chain_map_def = if let [".to_owned()
+					+ &defs.join(",")
+					+ &format!(
+						"] = maps {{ {}::{} {{\n\t\t",
+						canon_name,
+						fn_analysis.map_ty_name.as_ref().unwrap()
+					) + &fields.join(",\n\t\t")
+					+ "\n\t} } else { return usize::MAX };";
+
+				map_param = " chain_map_def";
+			}
+		} else {
+			// TODO: make err variant somewhere.
+			panic!("need both or none of: map type in output, maps assigned to fn");
+		}
+
+		(map_struct_def, map_param)
+	}
+
+	/// Crates built in the userland workspace: one per NF, or a single crate
+	/// for the whole chain if `fuse_userland` is set.
+	pub fn userland_crates(&self) -> Vec<String> {
+		if self.fuse_userland {
+			vec![USER_CHAIN_CRATE.into()]
+		} else {
+			self.functions.keys().cloned().collect()
+		}
+	}
+
+	/// Writes the `Cargo.toml` of userland crate `name`, depending on `nfs`.
+	async fn write_userland_cargo_toml<'a>(
+		&'a self,
+		src_path: &mut PathBuf,
+		name: &str,
+		nfs: impl IntoIterator<Item = &'a String>,
+	) -> Result<(), WriteXdpError> {
+		src_path.push("Cargo.toml");
+		let mut cargo_file = File::create(&src_path)
+			.await
+			.map_err(WriteXdpError::CreateFile)?;
+		cargo_file
+			.write_all(format!(include_str!("../include/Cargo.user.in.toml"), name,).as_bytes())
+			.await
+			.map_err(WriteXdpError::WriteFile)?;
+
+		for nf in nfs {
+			let path = self.functions[nf].path.as_ref().unwrap_or(nf);
+			cargo_file
 				.write_all(
 					format!(
-						include_str!("../include/xdp_wrapper_chain.in.rs"),
-						canon_name, info.slice, needed_slots, map_defs, map_struct_def, map_param
+						"{0} = {{ version = \"*\", path = \"../../../../{1}\", features = [\"user\"] }}\n",
+						nf, path,
 					)
 					.as_bytes(),
 				)
 				.await
 				.map_err(WriteXdpError::WriteFile)?;
-
-			src_path.pop();
-			src_path.pop();
 		}
+		src_path.pop();
 
 		Ok(())
 	}
@@ -375,6 +821,10 @@ codegen-units = 1
 		variants: &Vec<FnAnalysis>,
 		src_path: &mut PathBuf,
 	) -> Result<(), WriteXdpError> {
+		if self.fuse_userland {
+			return self.write_userland_chain(variants, src_path).await;
+		}
+
 		for ((name, info), fn_analysis) in self.functions.iter().zip(variants) {
 			let canon_name = name.replace('-', "_");
 
@@ -384,27 +834,8 @@ codegen-units = 1
 				.map_err(WriteXdpError::CreateDir)?;
 
 			// --- CARGO ---
-			src_path.push("Cargo.toml");
-			let mut cargo_file = File::create(&src_path)
-				.await
-				.map_err(WriteXdpError::CreateFile)?;
-			cargo_file
-				.write_all(format!(include_str!("../include/Cargo.user.in.toml"), name,).as_bytes())
-				.await
-				.map_err(WriteXdpError::WriteFile)?;
-
-			let path = info.path.as_ref().unwrap_or(name);
-			cargo_file
-				.write_all(
-					format!(
-						"{0} = {{ version = \"*\", path = \"../../../{1}\", features = [\"user\"] }}\n",
-						name, path,
-					)
-					.as_bytes(),
-				)
-				.await
-				.map_err(WriteXdpError::WriteFile)?;
-			src_path.pop();
+			self.write_userland_cargo_toml(src_path, name, [name])
+				.await?;
 			// --- CARGO ---
 
 			// --- LIB ---
@@ -418,50 +849,16 @@ codegen-units = 1
 				.await
 				.map_err(WriteXdpError::CreateFile)?;
 
-			// TODO: define these based on the actual NF definition
-			let mut map_struct_def = String::new();
-			let mut map_param = "";
-
-			// non-empty maps + map type def'd is an err -- lack of each also fine.
-			if !(info.maps.is_empty() ^ fn_analysis.map_ty_name.is_none()) {
-				let mut fields = vec![];
-				let mut defs = vec![];
-
-				for (map_i, (map_name, maybe_data)) in info.maps.iter().enumerate() {
-					let _data = match maybe_data {
-						LocalMap::Owned(m) => m,
-						// TODO: handle cleanly (non-panic)
-						// FIXME: maybe use the shared str param as a rename mechanism?
-						LocalMap::Shared(_) => self.maps.get(map_name).unwrap(),
-					};
-
-					fields.push(format!("{map_name}: m{map_i}"));
-					defs.push(format!("m{map_i}"));
-				}
-
-				if !fields.is_empty() {
-					// Want to have:
-					// if let [m0, m1, m2, ..] = maps { .. } else { panic!() }
-					map_struct_def = "let chain_map_def = if let [".to_owned()
-						+ &defs.join(",") + &format!(
-						"] = maps {{ {}::{} {{\n\t\t",
-						canon_name,
-						fn_analysis.map_ty_name.as_ref().unwrap()
-					) + &fields.join(",\n\t\t")
-						+ "\n\t} } else { return usize::MAX };";
-
-					map_param = " chain_map_def";
-				}
-			} else {
-				// TODO: make err variant somewhere.
-				panic!("need both or none of: map type in output, maps assigned to fn");
-			}
+			let (map_struct_def, map_param) = self.user_map_defs(name, info, fn_analysis);
 
 			lib_file
 				.write_all(
 					format!(
 						include_str!("../include/lib.in.rs"),
-						canon_name, map_struct_def, map_param,
+						canon_name,
+						map_struct_def,
+						map_param,
+						packet_view(name, "pkt", info.slice),
 					)
 					.as_bytes(),
 				)
@@ -478,199 +875,544 @@ codegen-units = 1
 		Ok(())
 	}
 
-	pub async fn compile_xdp_binaries(
+	/// Writes a single userland crate linking every NF, which runs packets
+	/// through the chain by itself (see [`protocol::UserlandChain`]).
+	///
+	/// NFs are numbered in the order of [`Chain::functions`].
+	async fn write_userland_chain(
 		&self,
-		mut src_path: PathBuf,
-		vmlinux: &Option<String>,
-	) -> Result<(HashMap<Uuid, PFunction>, HashMap<String, Uuid>), CompileError> {
-		// TODO: build binaries on Windows
-		// ...cross-compile? Still need to target BPF headers of target OS.
-		// NOTE: look at redbpf-probes docs, which support this!
-		let mut binaries = HashMap::new();
-		let mut fn_map = HashMap::new();
+		variants: &[FnAnalysis],
+		src_path: &mut PathBuf,
+	) -> Result<(), WriteXdpError> {
+		if let Some(link) = self.links.iter().find(|link| link.is_split()) {
+			return Err(WriteXdpError::FusedUserlandSplit(link.from.clone()));
+		}
 
-		src_path.pop();
-		if !cfg!(target_os = "windows") {
-			print!("Compiling binaries...");
-			let _ = io::stdout().flush().await;
+		if let Some(link) = self.links.iter().find(|link| link.mirror.is_some()) {
+			return Err(WriteXdpError::FusedUserlandMirror(link.from.clone()));
+		}
 
-			let mut envs = HashMap::new();
-			if let Some(vmlinux_loc) = vmlinux {
-				envs.insert("ENV_VMLINUX_PATH", vmlinux_loc.clone());
-			}
+		let indices: HashMap<&str, usize> = self
+			.functions
+			.keys()
+			.enumerate()
+			.map(|(i, name)| (name.as_str(), i))
+			.collect();
 
-			let o = Command::new("cargo")
-				.args(["+1.59", "bpf", "build", "--target-dir", "../../target"])
-				.current_dir(&src_path)
-				.envs(envs)
-				.output()
-				.await
-				.map_err(CompileError::CallCompile)?;
+		let mut nf_fns = String::new();
+		let mut arms = String::new();
+		for (i, ((name, info), fn_analysis)) in self.functions.iter().zip(variants).enumerate() {
+			let (map_struct_def, map_param) = self.user_map_defs(name, info, fn_analysis);
+			let canon_name = name.replace('-', "_");
 
-			if !o.status.success() {
-				return Err(CompileError::DoCompile(o));
+			let _ = write!(
+				nf_fns,
+				"\n/// `{name}`\n#[inline(always)]\nfn nf_{i}(pkt: &mut [u8], meta: &mut [u8], maps: &mut \
+				 [RawMap]) -> usize {{\n\t{map_struct_def}\n\n\tlet pkt = \
+				 {canon_name}::UserPacket::new(pkt, meta);\n\t{canon_name}::packet({},{map_param}) as \
+				 usize\n}}\n",
+				packet_view(name, "pkt", info.slice),
+			);
+
+			let to = self
+				.links
+				.iter()
+				.find(|link| &link.from == name)
+				.map(|link| &link.to[..])
+				.unwrap_or_default();
+
+			if let [tx] = to {
+				if tx == "tx" {
+					let _ = write!(
+						arms,
+						"\t\t\t{i} => {{\n\t\t\t\tnf_{i}(pkt, meta, nf_maps);\n\t\t\t\treturn true;\n\t\t\t}},\n"
+					);
+					continue;
+				}
 			}
 
-			println!(" Done!");
+			let _ = writeln!(arms, "\t\t\t{i} => match nf_{i}(pkt, meta, nf_maps) {{");
+			for (out, dest) in to.iter().enumerate() {
+				// Tailcalls and upcalls alike continue in userland.
+				let next = match (dest.as_str(), indices.get(dest.trim_start_matches('!'))) {
+					(_, Some(next)) => next.to_string(),
+					("tx", None) => "return true".into(),
+					_ => "return false".into(),
+				};
 
-			src_path.pop();
-			src_path.pop();
-			src_path.push("target/bpf/programs");
-			for (name, props) in self.functions.iter() {
-				let uuid = uuid::Uuid::new_v4();
+				let _ = writeln!(arms, "\t\t\t\t{out} => {next},");
+			}
+			arms += "\t\t\t\t_ => return false,\n\t\t\t},\n";
+		}
 
-				let ebpf = if props.disable_xdp {
-					None
-				} else {
-					let elf_dir = format!("{name}.elf");
-					let chain_name = format!("{name}-chain");
-					let chain_elf = format!("{chain_name}.elf");
+		src_path.push(USER_CHAIN_CRATE);
+		fs::create_dir(&src_path)
+			.await
+			.map_err(WriteXdpError::CreateDir)?;
 
-					// can this be abstracted away? in line w/ above?
-					// i.e., early-ident
+		self.write_userland_cargo_toml(src_path, USER_CHAIN_CRATE, self.functions.keys())
+			.await?;
 
+		src_path.push("src");
+		fs::create_dir(&src_path)
+			.await
+			.map_err(WriteXdpError::CreateDir)?;
+
+		src_path.push("lib.rs");
+		let first = self
+			.functions
+			.keys()
+			.next()
+			.map(|name| name.replace('-', "_"))
+			.unwrap_or_default();
+		fs::write(
+			&src_path,
+			format!(
+				include_str!("../include/user_chain.in.rs"),
+				first, nf_fns, arms
+			),
+		)
+		.await
+		.map_err(WriteXdpError::WriteFile)?;
+
+		src_path.pop();
+		src_path.pop();
+		src_path.pop();
+
+		Ok(())
+	}
+
+	pub async fn digest_nf_sources(
+		&self,
+		chain_toml_parent_dir: PathBuf,
+	) -> Result<HashMap<String, ArtifactKey>, CompileError> {
+		let mut out = HashMap::new();
+
+		for (name, crate_dir) in self
+			.functions
+			.keys()
+			.zip(self.crate_dirs(&chain_toml_parent_dir))
+		{
+			let mut key = ArtifactKey::builder("source");
+			key.dir(&crate_dir)
+				.await
+				.map_err(|e| CompileError::ReadSource(name.clone(), e))?;
+
+			// Every NF builds against `nf` and `nf-macros`, so must be rebuilt
+			// when they change.
+			let deps = path_dependencies(&crate_dir)
+				.await
+				.map_err(|e| CompileError::ReadSource(name.clone(), e))?;
+			for dep in deps {
+				let dep_name = dep.file_name().unwrap_or_default().to_string_lossy();
+				key.field("dependency", dep_name.as_bytes())
+					.dir(&dep)
+					.await
+					.map_err(|e| CompileError::ReadSource(name.clone(), e))?;
+			}
+
+			out.insert(name.clone(), key.finish());
+		}
+
+		Ok(out)
+	}
+
+	pub async fn compile_xdp_binaries(
+		&self,
+		mut src_path: PathBuf,
+		target_dir: &Path,
+		vmlinux: &Option<String>,
+		cache: &BuildCache,
+		sources: &HashMap<String, ArtifactKey>,
+	) -> Result<HashMap<String, EbpfFunction>, CompileError> {
+		// TODO: build binaries on Windows
+		// ...cross-compile? Still need to target BPF headers of target OS.
+		// NOTE: look at redbpf-probes docs, which support this!
+		let mut binaries = HashMap::new();
+
+		if !cfg!(target_os = "windows") {
+			// Only redbpf generates bindings from the target kernel's BTF.
+			let vmlinux = vmlinux.as_ref().filter(|_| self.backend.uses_vmlinux());
+			let vmlinux_bytes = if let Some(vmlinux_loc) = vmlinux {
+				fs::read(vmlinux_loc)
+					.await
+					.map_err(|e| CompileError::ReadVmlinux(vmlinux_loc.clone(), e))?
+			} else {
+				vec![]
+			};
+			let vmlinux_bytes = &vmlinux_bytes[..];
+			let rustc = rustc_version(Some(self.backend.toolchain()), &BTreeMap::new()).await?;
+
+			// Each NF yields two programs: `{name}` (chain end) and `{name}-chain`,
+			// other than those fused into another NF's programs.
+			let runs = self.fused_runs();
+			let members = fused_members(&runs);
+			let mut keys = HashMap::new();
+			let mut stale = vec![];
+			for (name, props) in self.functions.iter() {
+				if props.disable_xdp || members.contains(name.as_str()) {
+					continue;
+				}
+
+				for (bin_name, wrapper) in [
+					(name.clone(), "main.rs"),
+					(format!("{name}-chain"), "chain.rs"),
+				] {
 					src_path.push(name);
-					src_path.push(elf_dir);
-					let end = fs::read(&src_path)
+					src_path.push(wrapper);
+					let wrapper_src = fs::read(&src_path)
 						.await
-						.map_err(|e| CompileError::ReadElf(name.clone(), e))?;
+						.map_err(|e| CompileError::ReadSource(name.clone(), e))?;
 					src_path.pop();
 					src_path.pop();
 
-					src_path.push(&chain_name);
-					src_path.push(chain_elf);
-					let link = fs::read(&src_path)
-						.await
-						.map_err(|e| CompileError::ReadElf(name.clone(), e))?;
-					src_path.pop();
-					src_path.pop();
+					let mut key = ArtifactKey::builder("xdp");
+					key.field("source", sources[name].as_bytes());
+					for member in runs.get(name).into_iter().flatten() {
+						key.field("fused", sources[member].as_bytes());
+					}
 
-					Some(EbpfFunction { link, end })
-				};
+					let key = key
+						.field("wrapper", &wrapper_src)
+						.field("features", self.backend.nf_feature().as_bytes())
+						.field("target", self.backend.bpf_target().as_bytes())
+						.field("vmlinux", vmlinux_bytes)
+						.field("rustc", &rustc)
+						.finish();
 
-				let fun = PFunction {
-					uuid,
-					elf: None,
-					ebpf,
-				};
+					if !cache.contains(&key).await {
+						stale.push(bin_name.clone());
+					}
+
+					keys.insert(bin_name, key);
+				}
+			}
+
+			src_path.pop();
+			if stale.is_empty() {
+				println!("All eBPF binaries cached.");
+			} else {
+				print!("Compiling binaries {stale:?}...");
+				let _ = io::stdout().flush().await;
+
+				let mut envs = HashMap::new();
+				if let Some(vmlinux_loc) = vmlinux {
+					envs.insert("ENV_VMLINUX_PATH", vmlinux_loc.clone());
+				}
+
+				let o = self
+					.backend
+					.build_command(target_dir, &stale)
+					.current_dir(&src_path)
+					.envs(envs)
+					.output()
+					.await
+					.map_err(CompileError::CallCompile)?;
+
+				if !o.status.success() {
+					return Err(CompileError::DoCompile(o));
+				}
+
+				println!(" Done!");
+			}
+
+			for bin_name in &stale {
+				let elf = fs::read(self.backend.elf_path(target_dir, bin_name))
+					.await
+					.map_err(|e| CompileError::ReadElf(bin_name.clone(), e))?;
+
+				cache
+					.put(&keys[bin_name], &elf)
+					.await
+					.map_err(|e| CompileError::CacheWrite(bin_name.clone(), e))?;
+			}
+
+			for (name, props) in self.functions.iter() {
+				if props.disable_xdp || members.contains(name.as_str()) {
+					continue;
+				}
+
+				let chain_name = format!("{name}-chain");
+
+				let end = cache
+					.get(&keys[name])
+					.await
+					.map_err(|e| CompileError::ReadElf(name.clone(), e))?;
+				let link = cache
+					.get(&keys[&chain_name])
+					.await
+					.map_err(|e| CompileError::ReadElf(chain_name, e))?;
 
-				fn_map.insert(name.clone(), uuid);
-				binaries.insert(uuid, fun);
+				binaries.insert(name.clone(), EbpfFunction { link, end });
 			}
 		} else {
 			eprintln!("Skipping eBPF NF compilation.");
 			for name in self.functions.keys() {
-				let uuid = uuid::Uuid::new_v4();
-
-				let ebpf = Some(EbpfFunction {
+				let ebpf = EbpfFunction {
 					link: vec![],
 					end: vec![],
-				});
-
-				let fun = PFunction {
-					uuid,
-					elf: None,
-					ebpf,
 				};
 
-				fn_map.insert(name.clone(), uuid);
-				binaries.insert(uuid, fun);
+				binaries.insert(name.clone(), ebpf);
 			}
 		}
 
-		Ok((binaries, fn_map))
+		Ok(binaries)
 	}
 
 	pub async fn compile_userland_binaries(
 		&self,
 		mut workspace_path: PathBuf,
-		binaries: &mut HashMap<Uuid, PFunction>,
-		fn_map: &mut HashMap<String, Uuid>,
-		target: &Option<String>,
-	) -> Result<(), CompileError> {
+		target_dir: &Path,
+		target: &str,
+		target_cfg: &TargetConfig,
+		cache: &BuildCache,
+		sources: &HashMap<String, ArtifactKey>,
+	) -> Result<HashMap<String, Vec<u8>>, CompileError> {
+		let mut binaries = HashMap::new();
+
 		if !cfg!(target_os = "windows") {
-			print!("Compiling binaries...");
-			let _ = io::stdout().flush().await;
-
-			let mut extra_args = vec![];
-			let mut extra_env = HashMap::new();
-			if let Some(target) = target {
-				extra_args.push("--target");
-				extra_args.push(target);
-
-				let compiler_triple = match target.as_str() {
-					env!("TARGET") => None,
-					"aarch64-unknown-linux-gnu" => Some("aarch64-linux-gnu"),
-					_ => panic!("Cannot currently cross compile to this target."),
+			let crates = self.userland_crates();
+			let cargo_env = target_cfg.cargo_env(target);
+			let rustc = rustc_version(None, &cargo_env).await?;
+			let mut keys = HashMap::new();
+			let mut stale = vec![];
+			for name in &crates {
+				workspace_path.push(name);
+				workspace_path.push("Cargo.toml");
+				let cargo_src = fs::read(&workspace_path)
+					.await
+					.map_err(|e| CompileError::ReadSource(name.clone(), e))?;
+				workspace_path.pop();
+				workspace_path.push("src/lib.rs");
+				let wrapper_src = fs::read(&workspace_path)
+					.await
+					.map_err(|e| CompileError::ReadSource(name.clone(), e))?;
+				workspace_path.pop();
+				workspace_path.pop();
+				workspace_path.pop();
+
+				// The chain crate builds in the sources of every NF.
+				let nfs = if self.fuse_userland {
+					self.functions.keys().collect()
+				} else {
+					vec![name]
 				};
 
-				if let Some(triple) = compiler_triple {
-					extra_env.insert("RUSTFLAGS", format!("-C linker={triple}-gcc"));
+				let mut key = ArtifactKey::builder("user");
+				for nf in nfs {
+					key.field("source", sources[nf].as_bytes());
 				}
-			}
 
-			let o = Command::new("cargo")
-				.args(["build", "--release", "--target-dir", "../../target"])
-				.args(extra_args)
-				.envs(extra_env)
-				.current_dir(&workspace_path)
-				.output()
-				.await
-				.map_err(CompileError::CallCompile)?;
+				key.field("cargo", &cargo_src)
+					.field("wrapper", &wrapper_src)
+					.field("features", b"user")
+					.field("target", target.as_bytes())
+					.field("rustc", &rustc);
 
-			if !o.status.success() {
-				return Err(CompileError::DoCompile(o));
-			}
+				// Linkers, sysroots, and flags all change the built dylib.
+				for (var, value) in &cargo_env {
+					key.field(var, value.as_bytes());
+				}
 
-			println!(" Done!");
+				let key = key.finish();
 
-			workspace_path.pop();
-			workspace_path.pop();
-			workspace_path.push("target");
+				if !cache.contains(&key).await {
+					stale.push(name.clone());
+				}
 
-			if let Some(target) = target {
-				workspace_path.push(target);
+				keys.insert(name.clone(), key);
 			}
 
-			workspace_path.push("release");
+			if stale.is_empty() {
+				println!("All userland binaries cached.");
+			} else {
+				print!("Compiling binaries {stale:?}...");
+				let _ = io::stdout().flush().await;
 
-			// target/release/lib{name}_user.so
+				let mut extra_args = vec!["--target".to_string(), target.to_string()];
+				for name in &stale {
+					extra_args.push("-p".to_string());
+					extra_args.push(format!("{name}-user"));
+				}
 
-			// TODO: WRITE INTO BINARIES, FN_MAP -- check write dest.
-			for name in self.functions.keys() {
+				let o = Command::new("cargo")
+					.args(["build", "--release", "--target-dir"])
+					.arg(target_dir)
+					.args(extra_args)
+					.envs(&cargo_env)
+					.current_dir(&workspace_path)
+					.output()
+					.await
+					.map_err(CompileError::CallCompile)?;
+
+				if !o.status.success() {
+					return Err(CompileError::DoCompile(o));
+				}
+
+				println!(" Done!");
+			}
+
+			// target/{triple}/release/lib{name}_user.so
+			let mut dylib_dir = target_dir.join(target);
+			dylib_dir.push("release");
+			for name in &stale {
 				let dylib_path = format!("lib{}_user.so", name.replace('-', "_"));
 
-				// can this be abstracted away? in line w/ above?
-				// i.e., early-ident
+				dylib_dir.push(dylib_path);
+				let dylib = fs::read(&dylib_dir)
+					.await
+					.map_err(|e| CompileError::ReadElf(name.clone(), e))?;
+				dylib_dir.pop();
+
+				cache
+					.put(&keys[name], &dylib)
+					.await
+					.map_err(|e| CompileError::CacheWrite(name.clone(), e))?;
+			}
 
-				workspace_path.push(dylib_path);
-				let dylib = fs::read(&workspace_path)
+			for name in &crates {
+				let dylib = cache
+					.get(&keys[name])
 					.await
 					.map_err(|e| CompileError::ReadElf(name.clone(), e))?;
-				workspace_path.pop();
 
-				let uuid = fn_map
-					.entry(name.clone())
-					.or_insert_with(uuid::Uuid::new_v4);
-				binaries
-					.entry(*uuid)
-					.or_insert(PFunction {
-						uuid: *uuid,
-						elf: None,
-						ebpf: None,
-					})
-					.elf = Some(dylib);
+				binaries.insert(name.clone(), dylib);
 			}
 		} else {
 			eprintln!("Skipping userland NF compilation.");
 		}
-		Ok(())
+		Ok(binaries)
+	}
+
+	/// Combines compiled eBPF and userland payloads into [`PFunction`]s.
+	///
+	/// NF IDs are derived from each function's payload digest and name, so repeated
+	/// builds of the same sources produce identical chains.
+	pub fn assemble_functions(
+		&self,
+		mut ebpfs: HashMap<String, EbpfFunction>,
+		mut dylibs: HashMap<String, Vec<u8>>,
+	) -> (HashMap<Uuid, PFunction>, HashMap<String, Uuid>) {
+		let mut binaries = HashMap::new();
+		let mut fn_map = HashMap::new();
+
+		for name in self.functions.keys() {
+			let fun = PFunction::new(name, dylibs.remove(name), ebpfs.remove(name));
+
+			fn_map.insert(name.clone(), fun.uuid);
+			binaries.insert(fun.uuid, fun);
+		}
+
+		(binaries, fn_map)
+	}
+
+	pub fn map_types(&self, variants: &[FnAnalysis]) -> MapTypes {
+		self.functions
+			.iter()
+			.zip(variants)
+			.map(|((name, info), fn_analysis)| {
+				let mut nf_types: BTreeMap<_, _> = info
+					.maps
+					.keys()
+					.filter_map(|map_name| match fn_analysis.map_kv_types.get(map_name) {
+						Some((Some(k), Some(v))) =>
+							Some((map_name.clone(), (k.clone(), v.clone()))),
+						_ => None,
+					})
+					.collect();
+
+				// Split weights are updated as though they were a map of branch
+				// index to weight.
+				if self
+					.links
+					.iter()
+					.any(|link| &link.from == name && link.is_split())
+				{
+					nf_types.insert(SPLIT_WEIGHTS_MAP.into(), (DatumType::U32, DatumType::U32));
+				}
+
+				(name.clone(), nf_types)
+			})
+			.collect()
+	}
+
+	/// Reads the initial entries for every map in the chain, checking each
+	/// against the key and value types declared by its NF.
+	pub async fn resolve_map_entries(
+		&self,
+		chain_toml_parent_dir: &Path,
+		variants: &[FnAnalysis],
+	) -> Result<ChainMapEntries, MapEntryError> {
+		let mut out = HashMap::new();
+
+		for ((name, info), fn_analysis) in self.functions.iter().zip(variants) {
+			let mut nf_entries = BTreeMap::new();
+
+			for (map_name, maybe_data) in &info.maps {
+				let map = match maybe_data {
+					LocalMap::Owned(m) => Some(m),
+					LocalMap::Shared(_) => self.maps.get(map_name),
+				};
+
+				let entries = if let Some(entries) = map.and_then(|m| m.entries.as_ref()) {
+					entries
+				} else {
+					continue;
+				};
+
+				let (key_ty, val_ty) = match fn_analysis.map_kv_types.get(map_name) {
+					Some((Some(k), Some(v))) => (k, v),
+					Some(_) =>
+						return Err(MapEntryError::UnsupportedType {
+							nf: name.clone(),
+							map: map_name.clone(),
+						}),
+					None =>
+						return Err(MapEntryError::UndeclaredMap {
+							nf: name.clone(),
+							map: map_name.clone(),
+						}),
+				};
+
+				let parsed = entries
+					.load(chain_toml_parent_dir)
+					.await?
+					.iter()
+					.enumerate()
+					.map(|(index, row)| {
+						row.parse(key_ty, val_ty)
+							.map_err(|reason| MapEntryError::BadEntry {
+								nf: name.clone(),
+								map: map_name.clone(),
+								index,
+								reason,
+							})
+					})
+					.collect::<Result<Vec<_>, _>>()?;
+
+				if parsed.len() as u64 > map.map(|m| m.size).unwrap_or_default() {
+					return Err(MapEntryError::TooManyEntries {
+						nf: name.clone(),
+						map: map_name.clone(),
+					});
+				}
+
+				nf_entries.insert(map_name.clone(), parsed);
+			}
+
+			out.insert(name.clone(), nf_entries);
+		}
+
+		Ok(out)
 	}
 
 	pub fn make_concrete(
 		&self,
 		fn_map: &HashMap<String, Uuid>,
+		map_entries: &mut ChainMapEntries,
 	) -> Result<Vec<XdpLink>, ChainBuildError> {
+		let runs = self.fused_runs();
 		let mut new_fns: HashMap<&String, XdpLink> = fn_map
 			.iter()
 			.map(|(name, uuid)| {
@@ -681,7 +1423,21 @@ codegen-units = 1
 						state: XdpLinkState::Body(vec![]),
 						root: false,
 						disable_xdp: self.functions.get(name).unwrap().disable_xdp,
+						fused: runs
+							.get(name)
+							.into_iter()
+							.flatten()
+							.map(|member| fn_map[member])
+							.collect(),
+						weights: self
+							.links
+							.iter()
+							.find(|link| &link.from == name)
+							.and_then(|link| link.weights.clone())
+							.unwrap_or_default(),
+						mirror: None,
 						map_names: self.functions[name].maps.keys().cloned().collect(),
+						map_entries: map_entries.remove(name).unwrap_or_default(),
 					},
 				)
 			})
@@ -690,54 +1446,93 @@ codegen-units = 1
 		let mut rx_recv_count = 0;
 		for link in &self.links {
 			if link.from.as_str() == "rx" {
+				if link.is_split() {
+					return Err(ChainBuildError::RxSplit);
+				}
+
+				if link.mirror.is_some() {
+					return Err(ChainBuildError::RxMirror);
+				}
+
 				// if source is rx, make dest root.
 				for dest in &link.to {
 					let t_dest = dest.clone();
 					let first_hop = new_fns.get_mut(&t_dest).ok_or_else(|| {
-						ChainBuildError::UndefinedTarget(link.clone(), dest.clone())
+						ChainBuildError::UndefinedTarget(Box::new(link.clone()), dest.clone())
 					})?;
 					first_hop.root = true;
 					rx_recv_count += 1;
 				}
 			} else {
-				let (tail, dests) = if link.to.len() == 1 && link.to[0].as_str() == "tx" {
-					// source_link.state = XdpLinkState::Tail;
-					(true, vec![])
-				} else {
-					let dest_links: Result<Vec<LinkAction>, ChainBuildError> = link
-						.to
-						.iter()
-						.map(|name| {
-							let force_upcall = name.starts_with('!');
-
-							new_fns
-								.get(&name[(force_upcall as usize)..].to_string())
-								.ok_or_else(|| ChainBuildError::UndefinedTarget(link.clone(), name.clone()))
-								// TODO: figure out when to upcall?
-								.map(|link| if force_upcall || link.disable_xdp { LinkAction::Upcall } else { LinkAction::Tailcall }(link.uuid) )
-								// translate special names to actions
-								.or_else(|e| {
-									if let ChainBuildError::UndefinedTarget(_, ref s) = e {
-										match s.as_str() {
-											"tx" => Ok(LinkAction::Tx),
-											"drop" => Ok(LinkAction::Drop),
-											"pass" => Ok(LinkAction::Pass),
-											"abort" => Ok(LinkAction::Abort),
-											_ => Err(e),
-										}
-									} else {
-										unreachable!()
-									}
-								})
-						})
-						.collect();
-
-					(false, dest_links?)
-				};
+				// Mirrors are taken by the chain program, which tail NFs do not run.
+				let (tail, dests) =
+					if link.to.len() == 1 && link.to[0].as_str() == "tx" && link.mirror.is_none() {
+						// source_link.state = XdpLinkState::Tail;
+						(true, vec![])
+					} else {
+						let dest_links: Result<Vec<LinkAction>, ChainBuildError> = link
+							.to
+							.iter()
+							.map(|name| {
+								self.target_action(name, |nf| fn_map.get(nf).copied())
+									.ok_or_else(|| {
+										ChainBuildError::UndefinedTarget(
+											Box::new(link.clone()),
+											name.clone(),
+										)
+									})
+							})
+							.collect();
+
+						let dest_links = dest_links?;
+
+						// tc programs cannot redirect into AF_XDP sockets.
+						if self.hook.is_tc() {
+							if let Some(upcall) = dest_links
+								.iter()
+								.find(|act| matches!(act, LinkAction::Upcall(_)))
+							{
+								let name = fn_map
+									.iter()
+									.find(|(_, uuid)| Some(**uuid) == upcall.next_nf())
+									.map(|(name, _)| name.clone())
+									.unwrap_or_default();
+
+								return Err(ChainBuildError::TcUpcall(name));
+							}
+						}
+
+						(false, dest_links)
+					};
+
+				let mirror = link
+					.mirror
+					.as_ref()
+					.map(|mirror| {
+						// Mirrored copies are only sent to userland from XDP.
+						if self.hook.is_tc() {
+							return Err(ChainBuildError::TcMirror(mirror.nf.clone()));
+						}
+
+						fn_map
+							.get(&mirror.nf)
+							.map(|uuid| PMirror {
+								nf: *uuid,
+								sample: mirror.sample,
+							})
+							.ok_or_else(|| {
+								ChainBuildError::UndefinedTarget(
+									Box::new(link.clone()),
+									mirror.nf.clone(),
+								)
+							})
+					})
+					.transpose()?;
 
 				let source_link = new_fns.get_mut(&&link.from).ok_or_else(|| {
-					ChainBuildError::UndefinedSource(link.clone(), link.from.clone())
+					ChainBuildError::UndefinedSource(Box::new(link.clone()), link.from.clone())
 				})?;
+				source_link.mirror = mirror;
 
 				if tail {
 					source_link.state = XdpLinkState::Tail;
@@ -768,6 +1563,11 @@ pub struct Function {
 	pub path: Option<String>,
 	#[serde(default)]
 	pub disable_xdp: bool,
+	/// Always reach this NF by tail call, e.g. if it is too large for the
+	/// verifier once fused with its predecessor.
+	#[serde(default)]
+	pub disable_fusion: bool,
+	/// Only allow this NF to access the first `slice` bytes of each packet.
 	pub slice: Option<usize>,
 	#[serde(default)]
 	pub maps: BTreeMap<String, LocalMap>,
@@ -784,6 +1584,8 @@ pub enum LocalMap {
 pub struct Map {
 	pub r#type: MapType,
 	pub size: u64,
+	/// Entries inserted into the map when its chain is installed.
+	pub entries: Option<MapEntries>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -798,31 +1600,857 @@ impl MapType {
 	pub fn define_xdp(
 		&self,
 		target: &mut String,
+		backend: EbpfBackend,
 		def_name: &str,
 		map_i: usize,
 		map_sz: u64,
 		canon_name: &str,
 	) -> std::fmt::Result {
-		writeln!(target, "#[map(link_section = \"maps\")]")?;
-		write!(target, "static mut {def_name}: ")?;
+		// aya maps take flags alongside their size, and are safe to share.
+		let (decl, extra_args) = match backend {
+			EbpfBackend::Redbpf => {
+				writeln!(target, "#[map(link_section = \"maps\")]")?;
+				("static mut", "")
+			},
+			EbpfBackend::Aya => {
+				writeln!(target, "#[map]")?;
+				("static", ", 0")
+			},
+		};
+		write!(target, "{decl} {def_name}: ")?;
 
 		match self {
 			Self::Array => writeln!(
 				target,
-				"Array<{2}::NfValTy{0}> = Array::with_max_entries({1});",
-				map_i, map_sz, canon_name
+				"Array<{2}::NfValTy{0}> = Array::with_max_entries({1}{3});",
+				map_i, map_sz, canon_name, extra_args
 			),
 			Self::HashMap => writeln!(
 				target,
-				"HashMap<{2}::NfKeyTy{0}, {2}::NfValTy{0}> = HashMap::with_max_entries({1});",
-				map_i, map_sz, canon_name
+				"HashMap<{2}::NfKeyTy{0}, {2}::NfValTy{0}> = HashMap::with_max_entries({1}{3});",
+				map_i, map_sz, canon_name, extra_args
 			),
 		}
 	}
 }
 
+/// Toolchain used to compile a chain's eBPF NFs.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EbpfBackend {
+	/// `cargo bpf` from redbpf, which requires Rust 1.59.
+	#[default]
+	Redbpf,
+	/// `aya-ebpf`, linked by `bpf-linker` using a current nightly toolchain.
+	Aya,
+}
+
+/// Rust target used for aya builds.
+const AYA_TARGET: &str = "bpfel-unknown-none";
+
+impl EbpfBackend {
+	/// Feature enabled on each NF crate (and thereby `nf`).
+	pub fn nf_feature(self) -> &'static str {
+		match self {
+			Self::Redbpf => "xdp",
+			Self::Aya => "aya",
+		}
+	}
+
+	/// Rustup toolchain used for builds.
+	fn toolchain(self) -> &'static str {
+		match self {
+			Self::Redbpf => "+1.59",
+			Self::Aya => "+nightly",
+		}
+	}
+
+	fn bpf_target(self) -> &'static str {
+		match self {
+			Self::Redbpf => "bpf",
+			Self::Aya => AYA_TARGET,
+		}
+	}
+
+	/// Whether builds depend on the target kernel's vmlinux BTF.
+	fn uses_vmlinux(self) -> bool {
+		self == Self::Redbpf
+	}
+
+	fn cargo_toml(self) -> &'static [u8] {
+		match self {
+			Self::Redbpf => include_bytes!("../include/Cargo.xdp.in.toml"),
+			Self::Aya => include_bytes!("../include/Cargo.aya.in.toml"),
+		}
+	}
+
+	/// Prefix given to each map when passing it to an NF.
+	fn map_ref(self) -> &'static str {
+		match self {
+			Self::Redbpf => "&mut ",
+			Self::Aya => "&",
+		}
+	}
+
+	/// Source of the program run when an NF ends the chain.
+	///
+	/// tc hooks are only supported by aya (see [`Chain::check_hook`]).
+	fn wrapper(
+		self,
+		hook: Hook,
+		canon_name: &str,
+		packet: &str,
+		map_defs: &str,
+		map_struct_def: &str,
+		map_param: &str,
+	) -> String {
+		match (self, hook) {
+			(Self::Redbpf, _) => format!(
+				include_str!("../include/xdp_wrapper.in.rs"),
+				canon_name, packet, map_defs, map_struct_def, map_param
+			),
+			(Self::Aya, Hook::Xdp) => format!(
+				include_str!("../include/aya_wrapper.in.rs"),
+				canon_name, packet, map_defs, map_struct_def, map_param
+			),
+			(Self::Aya, _) => format!(
+				include_str!("../include/aya_tc_wrapper.in.rs"),
+				canon_name,
+				packet,
+				map_defs,
+				map_struct_def,
+				map_param,
+				tc_tx_action(hook)
+			),
+		}
+	}
+
+	/// Source of the program run when an NF hands packets on to others.
+	#[allow(clippy::too_many_arguments)]
+	fn chain_wrapper(
+		self,
+		hook: Hook,
+		canon_name: &str,
+		packet: &str,
+		needed_slots: usize,
+		map_defs: &str,
+		map_struct_def: &str,
+		map_param: &str,
+		split: bool,
+		mirror: Option<u32>,
+		acts: Option<&[u8]>,
+	) -> String {
+		let split = if split {
+			"let out = split_branch(out);"
+		} else {
+			""
+		};
+
+		// Only XDP chains mirror (see `Chain::make_concrete`). The copy is sent
+		// alongside the packet, which then takes its action as normal.
+		let mirror = mirror
+			.map(|sample| format!("if mirror_sampled({sample}) {{\n\t\tmirror(&ctx);\n\t}}"))
+			.unwrap_or_default();
+
+		// Baked actions are matched on directly, so that those this NF never takes
+		// are compiled out. A tail call then fails only if its target was moved
+		// to userland for a client (see `placement::adapt_chain`), and so is
+		// upcalled instead.
+		let baked = acts.map(|acts| {
+			let arms: String = acts
+				.iter()
+				.enumerate()
+				.map(|(i, kind)| format!("{i} => Some({kind}), "))
+				.collect();
+
+			format!("match out {{ {arms}_ => None }}")
+		});
+		let (acts, tailcall_failed) = match (self, baked) {
+			(Self::Redbpf, None) => (
+				"unsafe { acts_map.get(out) }".into(),
+				"Ok(XdpAction::Aborted)",
+			),
+			(Self::Aya, None) => (
+				"ACTS_MAP.get(out).copied()".into(),
+				"xdp_action::XDP_ABORTED",
+			),
+			(Self::Redbpf, Some(baked)) => (baked, "Ok(upcall(&ctx, out))"),
+			(Self::Aya, Some(baked)) => (baked, "upcall(&ctx, out)"),
+		};
+
+		match (self, hook) {
+			(Self::Redbpf, _) => format!(
+				include_str!("../include/xdp_wrapper_chain.in.rs"),
+				canon_name,
+				packet,
+				needed_slots,
+				map_defs,
+				map_struct_def,
+				map_param,
+				split,
+				mirror,
+				acts,
+				tailcall_failed
+			),
+			(Self::Aya, Hook::Xdp) => format!(
+				include_str!("../include/aya_wrapper_chain.in.rs"),
+				canon_name,
+				packet,
+				needed_slots,
+				map_defs,
+				map_struct_def,
+				map_param,
+				split,
+				mirror,
+				acts,
+				tailcall_failed
+			),
+			(Self::Aya, _) => format!(
+				include_str!("../include/aya_tc_wrapper_chain.in.rs"),
+				canon_name,
+				packet,
+				needed_slots,
+				map_defs,
+				map_struct_def,
+				map_param,
+				tc_tx_action(hook),
+				split,
+				acts
+			),
+		}
+	}
+
+	/// Command building each of `bins` into `target_dir`.
+	fn build_command(self, target_dir: &Path, bins: &[String]) -> Command {
+		let mut cmd = Command::new("cargo");
+
+		match self {
+			Self::Redbpf => {
+				cmd.args([self.toolchain(), "bpf", "build", "--target-dir"])
+					.arg(target_dir)
+					.args(bins);
+			},
+			Self::Aya => {
+				cmd.args([
+					self.toolchain(),
+					"build",
+					"--release",
+					"-Z",
+					"build-std=core",
+				])
+				.args(["--target", AYA_TARGET, "--target-dir"])
+				.arg(target_dir);
+
+				for bin in bins {
+					cmd.args(["--bin", bin]);
+				}
+			},
+		}
+
+		cmd
+	}
+
+	/// Location of the ELF built for `bin_name`.
+	fn elf_path(self, target_dir: &Path, bin_name: &str) -> PathBuf {
+		match self {
+			Self::Redbpf => target_dir
+				.join("bpf/programs")
+				.join(bin_name)
+				.join(format!("{bin_name}.elf")),
+			Self::Aya => target_dir.join(AYA_TARGET).join("release").join(bin_name),
+		}
+	}
+}
+
+/// Finds every crate reached via `path` dependencies of the crate in
+/// `crate_dir` (i.e., `nf` and `nf-macros`), other than itself.
+async fn path_dependencies(crate_dir: &Path) -> Result<Vec<PathBuf>, IoError> {
+	let root = fs::canonicalize(crate_dir).await?;
+	let mut out = BTreeSet::new();
+	let mut to_visit = vec![root.clone()];
+
+	while let Some(dir) = to_visit.pop() {
+		let manifest: toml::Value = toml::from_slice(&fs::read(dir.join("Cargo.toml")).await?)
+			.map_err(|e| IoError::new(ErrorKind::InvalidData, e))?;
+
+		for table in ["dependencies", "build-dependencies"] {
+			let deps = manifest.get(table).and_then(toml::Value::as_table);
+
+			for dep in deps.into_iter().flat_map(|deps| deps.values()) {
+				let path = match dep.get("path").and_then(toml::Value::as_str) {
+					Some(path) => path,
+					None => continue,
+				};
+
+				let dep_dir = fs::canonicalize(dir.join(path)).await?;
+				if dep_dir != root && out.insert(dep_dir.clone()) {
+					to_visit.push(dep_dir);
+				}
+			}
+		}
+	}
+
+	Ok(out.into_iter().collect())
+}
+
+/// Version of the `rustc` chosen by `toolchain` (e.g., `+nightly`) and `envs`,
+/// so that cached NFs are rebuilt after toolchain upgrades.
+async fn rustc_version(
+	toolchain: Option<&str>,
+	envs: &BTreeMap<String, String>,
+) -> Result<Vec<u8>, CompileError> {
+	let toolchain_name = toolchain.unwrap_or_default().to_string();
+
+	let o = Command::new("rustc")
+		.args(toolchain)
+		.arg("-V")
+		.envs(envs)
+		.output()
+		.await
+		.map_err(|e| CompileError::RustcVersion(toolchain_name.clone(), e))?;
+
+	if !o.status.success() {
+		let stderr = String::from_utf8_lossy(&o.stderr).into_owned();
+		return Err(CompileError::RustcVersion(
+			toolchain_name,
+			IoError::other(stderr),
+		));
+	}
+
+	Ok(o.stdout)
+}
+
+/// NFs inlined into the program of another, given the runs found by
+/// [`Chain::fused_runs`].
+pub fn fused_members(runs: &BTreeMap<String, Vec<String>>) -> HashSet<&str> {
+	runs.values().flatten().map(String::as_str).collect()
+}
+
+/// Expression passing packet `pkt` to NF `name`, limited to its first
+/// `slice` bytes if set.
+fn packet_view(name: &str, pkt: &str, slice: Option<usize>) -> String {
+	match slice {
+		Some(len) => format!("{}::Limited::new({pkt}, {len})", name.replace('-', "_")),
+		None => pkt.into(),
+	}
+}
+
+/// Expression returned by a tc program to transmit a packet.
+///
+/// On egress the packet simply continues to the driver; on ingress it is sent
+/// back out of the interface it arrived on, as `XDP_TX` would.
+fn tc_tx_action(hook: Hook) -> &'static str {
+	match hook {
+		Hook::TcIngress => "unsafe { bpf_redirect((*ctx.skb.skb).ifindex, 0) as i32 }",
+		_ => "TC_ACT_OK as i32",
+	}
+}
+
 #[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "RawLink")]
 pub struct Link {
 	pub from: String,
 	pub to: Vec<String>,
+	/// Share of packets sent to each of `to`, if this link splits traffic at
+	/// random rather than following the output of `from`.
+	pub weights: Option<Vec<u32>>,
+	/// Observer NF sent a copy of some packets leaving `from`.
+	pub mirror: Option<Mirror>,
+}
+
+impl Link {
+	pub fn is_split(&self) -> bool {
+		self.weights.is_some()
+	}
+}
+
+/// Fraction of packets mirrored by a link which names only its mirror NF.
+pub const DEFAULT_MIRROR_SAMPLE: f64 = 0.01;
+
+#[derive(Clone, Debug)]
+pub struct Mirror {
+	pub nf: String,
+	/// Chance that each packet is mirrored, as a fraction of `u32::MAX`.
+	pub sample: u32,
+}
+
+impl Mirror {
+	/// Mirrors the fraction `sample` (in `0.0..=1.0`) of packets to `nf`.
+	fn new(nf: String, sample: f64) -> Self {
+		Self {
+			nf,
+			sample: (sample * f64::from(u32::MAX)) as u32,
+		}
+	}
+}
+
+/// A [`Link`] as written in `chain.toml`, whose targets may each be given as
+/// a name or as `{ nf = .., weight = .. }`.
+#[derive(Deserialize)]
+struct RawLink {
+	from: String,
+	to: Vec<LinkTarget>,
+	mirror: Option<MirrorTarget>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LinkTarget {
+	Name(String),
+	Weighted { nf: String, weight: u32 },
+}
+
+/// A mirror NF, given as a name or as `{ nf = .., sample = .. }`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MirrorTarget {
+	Name(String),
+	Sampled { nf: String, sample: f64 },
+}
+
+impl TryFrom<RawLink> for Link {
+	type Error = String;
+
+	fn try_from(raw: RawLink) -> Result<Self, Self::Error> {
+		let mut to = vec![];
+		let mut weights = vec![];
+
+		for target in raw.to {
+			match target {
+				LinkTarget::Name(nf) => to.push(nf),
+				LinkTarget::Weighted { nf, weight } => {
+					to.push(nf);
+					weights.push(weight);
+				},
+			}
+		}
+
+		let weights = match weights.len() {
+			0 => None,
+			n if n == to.len() => Some(weights),
+			_ =>
+				return Err(format!(
+					"link from {} must give a weight for all or none of its targets",
+					raw.from
+				)),
+		};
+
+		if let Some(weights) = &weights {
+			if weights
+				.iter()
+				.try_fold(0u32, |acc, w| acc.checked_add(*w))
+				.is_none()
+			{
+				return Err(format!(
+					"weights of link from {} must total at most {}",
+					raw.from,
+					u32::MAX
+				));
+			}
+		}
+
+		let mirror = match raw.mirror {
+			None => None,
+			Some(MirrorTarget::Name(nf)) => Some(Mirror::new(nf, DEFAULT_MIRROR_SAMPLE)),
+			Some(MirrorTarget::Sampled { nf, sample }) if (0.0..=1.0).contains(&sample) =>
+				Some(Mirror::new(nf, sample)),
+			Some(MirrorTarget::Sampled { .. }) =>
+				return Err(format!(
+					"link from {} must sample between 0 and 1 of packets to its mirror",
+					raw.from
+				)),
+		};
+
+		Ok(Self {
+			from: raw.from,
+			to,
+			weights,
+			mirror,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Renders the chain program of an XDP NF which mirrors every packet.
+	fn mirroring_wrapper(backend: EbpfBackend, acts: Option<&[u8]>) -> String {
+		backend.chain_wrapper(
+			Hook::Xdp,
+			"nf",
+			"&ctx",
+			2,
+			"",
+			"",
+			"",
+			false,
+			Some(u32::MAX),
+			acts,
+		)
+	}
+
+	/// Asserts that `src` copies each mirrored packet, then still chooses its
+	/// verdict from the NF's action table, and that `verdict` is among them.
+	fn assert_mirror_keeps_verdict(src: &str, verdict: &str) {
+		let mirror = src.find("mirror(&ctx);").expect("mirror not sent");
+		let acts = mirror + src[mirror..].find("match ").expect("no action match");
+
+		assert!(
+			!src[mirror..acts].contains("return"),
+			"mirrored packets skip their action"
+		);
+		assert!(src[acts..].contains(verdict), "verdict not taken");
+	}
+
+	#[test]
+	fn redbpf_mirror_keeps_tx() {
+		let src = mirroring_wrapper(EbpfBackend::Redbpf, None);
+
+		assert_mirror_keeps_verdict(&src, "Some(0) => Ok(XdpAction::Tx)");
+	}
+
+	#[test]
+	fn aya_mirror_keeps_tx_and_pass() {
+		let src = mirroring_wrapper(EbpfBackend::Aya, None);
+
+		assert_mirror_keeps_verdict(&src, "Some(0) => xdp_action::XDP_TX");
+		assert_mirror_keeps_verdict(&src, "Some(5) => xdp_action::XDP_PASS");
+	}
+
+	#[test]
+	fn baked_mirror_keeps_pass() {
+		let src = mirroring_wrapper(EbpfBackend::Aya, Some(&[5, 5]));
+
+		assert_mirror_keeps_verdict(&src, "0 => Some(5)");
+		assert_mirror_keeps_verdict(&src, "Some(5) => xdp_action::XDP_PASS");
+	}
+
+	#[test]
+	fn baked_kinds_match_concrete_links() {
+		let chain: Chain = toml::from_str(
+			r#"
+			[functions.a]
+			[functions.b]
+			disable_xdp = true
+			[functions.c]
+
+			[[links]]
+			from = "rx"
+			to = ["a"]
+			[[links]]
+			from = "a"
+			to = ["b", "c", "!c", "tx", "drop", "pass", "abort"]
+			[[links]]
+			from = "b"
+			to = ["tx"]
+			[[links]]
+			from = "c"
+			to = ["tx"]
+			"#,
+		)
+		.unwrap();
+		let fn_map: HashMap<_, _> = chain
+			.functions
+			.keys()
+			.map(|name| (name.clone(), Uuid::new_v4()))
+			.collect();
+
+		let links = chain
+			.make_concrete(&fn_map, &mut Default::default())
+			.unwrap();
+		let a = links.iter().find(|link| link.uuid == fn_map["a"]).unwrap();
+		let concrete: Vec<u8> = match &a.state {
+			XdpLinkState::Body(acts) => acts.iter().map(LinkAction::to_kind).collect(),
+			XdpLinkState::Tail => panic!("a should have a body"),
+		};
+
+		assert_eq!(chain.target_kinds(&chain.links[1]), concrete);
+	}
+
+	fn fused_runs(src: &str) -> Vec<(String, Vec<String>)> {
+		let chain: Chain = toml::from_str(src).unwrap();
+
+		chain.fused_runs().into_iter().collect()
+	}
+
+	fn run(head: &str, members: &[&str]) -> (String, Vec<String>) {
+		(
+			head.into(),
+			members.iter().map(|member| member.to_string()).collect(),
+		)
+	}
+
+	#[test]
+	fn fusion_follows_straight_lines() {
+		let runs = fused_runs(
+			r#"
+			fuse = true
+			[functions.a]
+			[functions.b]
+			[functions.c]
+
+			[[links]]
+			from = "rx"
+			to = ["a"]
+			[[links]]
+			from = "a"
+			to = ["b"]
+			[[links]]
+			from = "b"
+			to = ["c"]
+			[[links]]
+			from = "c"
+			to = ["tx"]
+			"#,
+		);
+
+		assert_eq!(runs, [run("a", &["b", "c"])]);
+	}
+
+	#[test]
+	fn fusion_is_opt_in() {
+		let runs = fused_runs(
+			r#"
+			[functions.a]
+			[functions.b]
+
+			[[links]]
+			from = "rx"
+			to = ["a"]
+			[[links]]
+			from = "a"
+			to = ["b"]
+			[[links]]
+			from = "b"
+			to = ["tx"]
+			"#,
+		);
+
+		assert!(runs.is_empty());
+	}
+
+	#[test]
+	fn fusion_stops_at_branches_and_merges() {
+		// `a` branches to `b` and `c`, which both lead to `d`.
+		let runs = fused_runs(
+			r#"
+			fuse = true
+			[functions.a]
+			[functions.b]
+			[functions.c]
+			[functions.d]
+			[functions.e]
+
+			[[links]]
+			from = "rx"
+			to = ["a"]
+			[[links]]
+			from = "a"
+			to = ["b", "c"]
+			[[links]]
+			from = "b"
+			to = ["d"]
+			[[links]]
+			from = "c"
+			to = ["d"]
+			[[links]]
+			from = "d"
+			to = ["e"]
+			[[links]]
+			from = "e"
+			to = ["drop"]
+			"#,
+		);
+
+		assert_eq!(runs, [run("d", &["e"])]);
+	}
+
+	#[test]
+	fn fusion_stops_at_upcalls_userland_and_disable_fusion() {
+		let runs = fused_runs(
+			r#"
+			fuse = true
+			[functions.a]
+			[functions.b]
+			[functions.c]
+			disable_fusion = true
+			[functions.d]
+			[functions.e]
+			disable_xdp = true
+			[functions.f]
+
+			[[links]]
+			from = "rx"
+			to = ["a"]
+			[[links]]
+			from = "a"
+			to = ["b"]
+			[[links]]
+			from = "b"
+			to = ["c"]
+			[[links]]
+			from = "c"
+			to = ["d"]
+			[[links]]
+			from = "d"
+			to = ["e"]
+			[[links]]
+			from = "e"
+			to = ["!f"]
+			[[links]]
+			from = "f"
+			to = ["tx"]
+			"#,
+		);
+
+		// `c` cannot be inlined, but still heads its own run.
+		assert_eq!(runs, [run("a", &["b"]), run("c", &["d"])]);
+	}
+
+	#[test]
+	fn fusion_counts_forced_upcalls_as_incoming() {
+		// `c` is reached from `b`, and by upcall from `a`.
+		let runs = fused_runs(
+			r#"
+			fuse = true
+			[functions.a]
+			[functions.b]
+			[functions.c]
+
+			[[links]]
+			from = "rx"
+			to = ["a"]
+			[[links]]
+			from = "a"
+			to = ["b", "!c"]
+			[[links]]
+			from = "b"
+			to = ["c"]
+			[[links]]
+			from = "c"
+			to = ["tx"]
+			"#,
+		);
+
+		assert!(runs.is_empty());
+	}
+
+	#[test]
+	fn fusion_stops_at_mirrors() {
+		let runs = fused_runs(
+			r#"
+			fuse = true
+			[functions.a]
+			[functions.b]
+			[functions.c]
+			[functions.m]
+
+			[[links]]
+			from = "rx"
+			to = ["a"]
+			[[links]]
+			from = "a"
+			to = ["b"]
+			mirror = "m"
+			[[links]]
+			from = "b"
+			to = ["c"]
+			[[links]]
+			from = "c"
+			to = ["tx"]
+			"#,
+		);
+
+		assert_eq!(runs, [run("b", &["c"])]);
+	}
+
+	fn parse_link(src: &str) -> Result<Link, String> {
+		toml::from_str(src).map_err(|e| e.to_string())
+	}
+
+	#[test]
+	fn links_take_weights_for_all_targets_or_none() {
+		let link = parse_link(
+			r#"
+			from = "a"
+			to = [{ nf = "b", weight = 3 }, { nf = "c", weight = 1 }]
+			"#,
+		)
+		.unwrap();
+		assert_eq!(link.to, ["b", "c"]);
+		assert_eq!(link.weights, Some(vec![3, 1]));
+
+		let err = parse_link(
+			r#"
+			from = "a"
+			to = [{ nf = "b", weight = 3 }, "c"]
+			"#,
+		)
+		.unwrap_err();
+		assert!(err.contains("all or none"), "{err}");
+	}
+
+	#[test]
+	fn links_reject_weight_overflow() {
+		let err = parse_link(
+			r#"
+			from = "a"
+			to = [{ nf = "b", weight = 4294967295 }, { nf = "c", weight = 1 }]
+			"#,
+		)
+		.unwrap_err();
+		assert!(err.contains("must total at most"), "{err}");
+
+		let link = parse_link(
+			r#"
+			from = "a"
+			to = [{ nf = "b", weight = 4294967294 }, { nf = "c", weight = 1 }]
+			"#,
+		)
+		.unwrap();
+		assert!(link.is_split());
+	}
+
+	#[test]
+	fn links_sample_mirrors_in_range() {
+		let link = parse_link(
+			r#"
+			from = "a"
+			to = ["b"]
+			mirror = { nf = "m", sample = 1.0 }
+			"#,
+		)
+		.unwrap();
+		assert_eq!(link.mirror.unwrap().sample, u32::MAX);
+
+		let sampled = |sample: &str| {
+			parse_link(&format!(
+				"from = \"a\"\nto = [\"b\"]\nmirror = {{ nf = \"m\", sample = {sample} }}"
+			))
+		};
+
+		let mirror = sampled("0.0").unwrap().mirror.unwrap();
+		assert_eq!((mirror.nf.as_str(), mirror.sample), ("m", 0));
+		let half = sampled("0.5").unwrap().mirror.unwrap().sample;
+		assert_eq!(half, u32::MAX / 2);
+
+		for sample in ["-0.1", "1.5", "nan", "inf"] {
+			let err = sampled(sample).unwrap_err();
+			assert!(err.contains("between 0 and 1"), "{sample}: {err}");
+		}
+	}
+
+	#[test]
+	fn unsampled_mirror_takes_default() {
+		let link: Link = toml::from_str("from = \"a\"\nto = [\"b\"]\nmirror = \"m\"").unwrap();
+		let mirror = link.mirror.unwrap();
+
+		assert_eq!(mirror.nf, "m");
+		assert_eq!(
+			mirror.sample,
+			(DEFAULT_MIRROR_SAMPLE * f64::from(u32::MAX)) as u32
+		);
+		assert!(mirror.sample < u32::MAX);
+	}
 }
@@ -1,30 +1,96 @@
-use clap::{Parser, ValueEnum};
+use std::{collections::BTreeMap, io::ErrorKind, net::SocketAddr, path::PathBuf};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use tokio::fs;
 
 #[derive(Clone, Parser)]
 #[clap(author, version, about, long_about = None)]
 pub struct Cli {
-	#[clap(default_value_t = String::from("."), value_parser)]
-	/// Path to a folder containing a `chain.toml` configuration.
-	pub path: String,
+	#[command(subcommand)]
+	pub command: Option<Command>,
+
+	#[clap(default_values_t = [String::from(".")], value_parser, global = true)]
+	/// Paths to folders, each containing a `chain.toml` configuration.
+	///
+	/// Each chain is named after its folder. Clients which do not ask for a chain
+	/// by name are served the first chain listed here, unless the configuration
+	/// file's `[policy]` says otherwise.
+	pub paths: Vec<String>,
 
 	#[clap(default_value_t = String::from("127.0.0.1:8080"), value_parser, long)]
 	/// Connection string to bind the WebSocket server to.
 	pub conn_string: String,
 
-	#[clap(value_parser, long)]
-	/// Target architecture to build user-space NFs for (e.g., "aarch64-unknown-linux-gnu").
+	#[clap(default_value_t = String::from("chainsmith.toml"), value_parser, long, global = true)]
+	/// Path to a chainsmith configuration file listing targets and their toolchains.
 	///
-	/// If specified, this should be a rust compiler tuple. Otherwise, this implicitly
-	/// matches the host.
+	/// If this file does not exist, chains are built for the host and
+	/// `aarch64-unknown-linux-gnu`.
+	pub config: String,
+
+	#[clap(value_parser, long, global = true)]
+	/// Build only this target architecture (e.g., "aarch64-unknown-linux-gnu").
+	///
+	/// If specified, this should be a rust compiler tuple. Otherwise, every target in
+	/// the configuration file is built.
 	pub target: Option<String>,
 
-	#[clap(value_parser, long)]
-	/// Vmlinux BTF path to use when building eBPF NFs.
+	#[clap(value_parser, long, global = true)]
+	/// Vmlinux BTF path to use when building eBPF NFs for `--target`.
 	///
 	/// This sets the `ENV_VMLINUX_PATH` environment variable used in downstream calls
-	/// to `cargo bpf`.
+	/// to `cargo bpf`, overriding any path given in the configuration file.
 	pub vmlinux: Option<String>,
 
+	#[clap(value_parser, long, global = true)]
+	/// Directory used to store compiled NF binaries between runs.
+	///
+	/// Artifacts are keyed on a hash of each NF's sources (including `nf` and
+	/// `nf-macros`), generated wrapper, features, target and its toolchain settings,
+	/// rustc version, and vmlinux, so unchanged NFs are not rebuilt. Defaults to
+	/// `cache` within the chain directory.
+	///
+	/// Kernel BTF uploaded by clients is stored in its `btf` subdirectory (or in
+	/// `cache/btf` if unset).
+	pub cache_dir: Option<String>,
+
+	#[clap(value_parser, long, global = true)]
+	/// PKCS#8 Ed25519 key used to sign every chain served or bundled.
+	///
+	/// Clients pinning the matching public key (`pulley --trusted-key`) refuse
+	/// chains which are unsigned or were signed by any other key. A key can be
+	/// created with `chainsmith gen-key`.
+	pub signing_key: Option<PathBuf>,
+
+	#[clap(action, long)]
+	/// Rebuild chains whenever a `chain.toml` or any NF crate changes.
+	///
+	/// Connected clients are sent each successfully rebuilt chain for their target.
+	/// If a rebuild fails, the previous chain continues to be served.
+	pub watch: bool,
+
+	#[clap(value_parser, long)]
+	/// Address to serve the local HTTP/JSON admin API on (e.g., "127.0.0.1:8081").
+	///
+	/// The API lists connected clients and build status, triggers rebuilds, and
+	/// fans out map updates to clients. It is unauthenticated, so should only be
+	/// bound to a loopback or otherwise trusted interface.
+	pub admin_addr: Option<SocketAddr>,
+
+	#[clap(default_value_t = 5_000, value_parser, long)]
+	/// Time (ns) an NF may take per packet before placements proposed via the
+	/// admin API move it to userland.
+	///
+	/// This applies to the cost measured by `pulley --profile` in eBPF or in
+	/// userland, whichever the NF currently runs in.
+	pub placement_max_ns: u64,
+
+	#[clap(default_value_t = 100_000, value_parser, long)]
+	/// Number of instructions the kernel verifier may process when loading an NF
+	/// before placements proposed via the admin API move it to userland.
+	pub placement_max_insns: u32,
+
 	#[arg(value_enum, default_value_t = TlsMode::NoTls, long)]
 	/// Configures how `chainsmith` and `pulley` authenticate with one another.
 	///
@@ -35,6 +101,29 @@ pub struct Cli {
 	pub tls_mode: TlsMode,
 }
 
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command {
+	/// Build every chain once and write each to an offline bundle, rather than
+	/// serving them.
+	///
+	/// Bundles can be installed with `pulley --bundle <file>`.
+	Build {
+		#[clap(value_parser, long)]
+		/// File to write the bundle to.
+		///
+		/// If several chains or targets are built, one bundle is written for each,
+		/// named `<stem>.<chain>.<target>.<extension>`.
+		out: PathBuf,
+	},
+	/// Generate a new chain signing key, and print its public key for use with
+	/// `pulley --trusted-key`.
+	GenKey {
+		#[clap(value_parser, long)]
+		/// File to write the PKCS#8 private key to.
+		out: PathBuf,
+	},
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
 #[clap(rename_all = "kebab_case")]
 pub enum TlsMode {
@@ -57,3 +146,225 @@ impl TlsMode {
 		matches!(self, Self::PufTls)
 	}
 }
+
+/// Contents of a `chainsmith.toml` configuration file.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+	#[serde(default)]
+	pub targets: BTreeMap<String, TargetConfig>,
+	#[serde(default)]
+	pub policy: Policy,
+}
+
+impl Config {
+	pub async fn load(path: &str) -> anyhow::Result<Self> {
+		match fs::read(path).await {
+			Ok(bytes) => Ok(toml::from_slice(&bytes)?),
+			Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// Returns the set of targets to build, with toolchain defaults filled in.
+	///
+	/// `--target` and `--vmlinux` narrow and override the file's contents.
+	pub fn selected_targets(&self, cli: &Cli) -> BTreeMap<String, TargetConfig> {
+		let mut out = if let Some(target) = &cli.target {
+			let cfg = self.targets.get(target).cloned().unwrap_or_default();
+			let mut out = BTreeMap::new();
+			out.insert(target.clone(), cfg);
+			out
+		} else {
+			self.targets.clone()
+		};
+
+		for (target, cfg) in out.iter_mut() {
+			if cli.target.is_some() && cli.vmlinux.is_some() {
+				cfg.vmlinux = cli.vmlinux.clone();
+			}
+
+			cfg.fill_defaults(target);
+		}
+
+		out
+	}
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		let mut targets = BTreeMap::new();
+
+		targets.insert(env!("TARGET").to_string(), TargetConfig::default());
+		targets.insert(
+			"aarch64-unknown-linux-gnu".to_string(),
+			TargetConfig {
+				vmlinux: Some("support-files/vmlinux".to_string()),
+				..Default::default()
+			},
+		);
+
+		Self {
+			targets,
+			policy: Policy::default(),
+		}
+	}
+}
+
+/// Rules deciding which chain each client is served.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Policy {
+	/// Chain served to clients which neither name a chain nor have an assignment.
+	///
+	/// If unset, chainsmith uses the first chain given on the command line.
+	pub default: Option<String>,
+	/// Chains assigned to clients, keyed by the hex SHA-256 fingerprint of their
+	/// TLS certificate.
+	///
+	/// Assigned clients are only ever served their assigned chain.
+	#[serde(default)]
+	pub clients: BTreeMap<String, String>,
+	/// Clients which may upload their kernel's BTF, and be served chains built
+	/// against it.
+	#[serde(default)]
+	pub btf_uploads: BtfUploads,
+}
+
+/// Which clients chainsmith builds chains for against their own kernel's BTF.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BtfUploads {
+	/// Every client.
+	Any,
+	/// Clients presenting a TLS certificate.
+	#[default]
+	Identified,
+	/// Clients assigned a chain in `[policy.clients]`.
+	Assigned,
+	/// No clients: all are served their target's usual build.
+	None,
+}
+
+impl Policy {
+	/// Picks the chain to serve a client with the given certificate
+	/// fingerprint, which may have asked for a chain by name.
+	pub fn resolve<'a>(
+		&'a self,
+		identity: Option<&str>,
+		requested: Option<&'a str>,
+	) -> Result<&'a str, String> {
+		let assigned = identity.and_then(|id| self.clients.get(id));
+
+		match (assigned, requested) {
+			(Some(assigned), Some(requested)) if assigned != requested => Err(format!(
+				"chain {requested} not permitted: client is assigned {assigned}."
+			)),
+			(Some(assigned), _) => Ok(assigned),
+			(None, Some(requested)) => Ok(requested),
+			(None, None) => self
+				.default
+				.as_deref()
+				.ok_or_else(|| "no chain requested, and server has no default.".into()),
+		}
+	}
+
+	/// Checks whether the client with the given certificate fingerprint may
+	/// upload its kernel's BTF.
+	pub fn accepts_btf(&self, identity: Option<&str>) -> bool {
+		match self.btf_uploads {
+			BtfUploads::Any => true,
+			BtfUploads::Identified => identity.is_some(),
+			BtfUploads::Assigned => identity.is_some_and(|id| self.clients.contains_key(id)),
+			BtfUploads::None => false,
+		}
+	}
+}
+
+/// Toolchain settings used to build NFs for one target triple.
+///
+/// Unset fields are filled in from defaults for well-known triples.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TargetConfig {
+	/// Vmlinux BTF path to use when building eBPF NFs.
+	pub vmlinux: Option<String>,
+	/// Linker used for userland NF dylibs.
+	pub linker: Option<String>,
+	/// C compiler used for native dependencies (i.e., libbpf).
+	pub cc: Option<String>,
+	/// Sysroot containing the target's libc, libelf, and zlib.
+	pub sysroot: Option<String>,
+	/// Extra flags passed to rustc when building userland NFs.
+	#[serde(default)]
+	pub rustflags: Vec<String>,
+}
+
+impl TargetConfig {
+	fn fill_defaults(&mut self, target: &str) {
+		if target == env!("TARGET") {
+			return;
+		}
+
+		let gcc_prefix = match target {
+			"aarch64-unknown-linux-gnu" => Some("aarch64-linux-gnu"),
+			"aarch64-unknown-linux-musl" => Some("aarch64-linux-musl"),
+			"armv7-unknown-linux-gnueabihf" => Some("arm-linux-gnueabihf"),
+			"armv7-unknown-linux-musleabihf" => Some("arm-linux-musleabihf"),
+			"riscv64gc-unknown-linux-gnu" => Some("riscv64-linux-gnu"),
+			"riscv64gc-unknown-linux-musl" => Some("riscv64-linux-musl"),
+			"x86_64-unknown-linux-gnu" => Some("x86_64-linux-gnu"),
+			"x86_64-unknown-linux-musl" => Some("x86_64-linux-musl"),
+			_ => None,
+		};
+
+		if let Some(prefix) = gcc_prefix {
+			self.linker.get_or_insert_with(|| format!("{prefix}-gcc"));
+			self.cc.get_or_insert_with(|| format!("{prefix}-gcc"));
+		}
+
+		// musl targets link the C runtime statically by default, which
+		// prevents building NFs as dylibs.
+		let no_crt_static = "-C target-feature=-crt-static";
+		if target.contains("musl") && !self.rustflags.iter().any(|f| f == no_crt_static) {
+			self.rustflags.push(no_crt_static.to_string());
+		}
+	}
+
+	/// Environment variables needed for `cargo build --target {target}`.
+	pub fn cargo_env(&self, target: &str) -> BTreeMap<String, String> {
+		let mut out = BTreeMap::new();
+
+		let cargo_triple = target.to_uppercase().replace('-', "_");
+		let cc_triple = target.replace('-', "_");
+
+		let mut rustflags = self.rustflags.clone();
+
+		if let Some(linker) = &self.linker {
+			out.insert(
+				format!("CARGO_TARGET_{cargo_triple}_LINKER"),
+				linker.clone(),
+			);
+		}
+
+		if let Some(cc) = &self.cc {
+			out.insert(format!("CC_{cc_triple}"), cc.clone());
+		}
+
+		if let Some(sysroot) = &self.sysroot {
+			out.insert(
+				format!("CFLAGS_{cc_triple}"),
+				format!("--sysroot={sysroot}"),
+			);
+			out.insert("PKG_CONFIG_SYSROOT_DIR".into(), sysroot.clone());
+			out.insert("PKG_CONFIG_ALLOW_CROSS".into(), "1".into());
+			rustflags.push(format!("-C link-arg=--sysroot={sysroot}"));
+		}
+
+		if !rustflags.is_empty() {
+			out.insert(
+				format!("CARGO_TARGET_{cargo_triple}_RUSTFLAGS"),
+				rustflags.join(" "),
+			);
+		}
+
+		out
+	}
+}
@@ -0,0 +1,332 @@
+use std::{
+	fmt::{Display, Formatter, Result as FmtResult},
+	net::Ipv4Addr,
+	path::Path,
+};
+
+use protocol::{MapDatum, MapEntry};
+use serde::Deserialize;
+use syn::{Expr, ExprLit, Lit, Type};
+use tokio::fs;
+use toml::Value;
+
+use super::error::MapEntryError;
+
+/// Initial contents of a map, given inline or in a separate file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum MapEntries {
+	Inline(Vec<RawEntry>),
+	/// Path (relative to `chain.toml`) to either a CSV file, or a TOML file
+	/// containing an `entries` list.
+	///
+	/// CSV rows list every scalar of the key, followed by every scalar of the value.
+	File(String),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RawEntry {
+	pub key: Value,
+	pub value: Value,
+}
+
+#[derive(Deserialize)]
+struct EntryFile {
+	entries: Vec<RawEntry>,
+}
+
+/// A single untyped entry, before being checked against its map's types.
+pub enum RawRow {
+	Toml(RawEntry),
+	Csv(Vec<String>),
+}
+
+impl MapEntries {
+	pub async fn load(&self, chain_toml_parent_dir: &Path) -> Result<Vec<RawRow>, MapEntryError> {
+		let path = match self {
+			Self::Inline(entries) =>
+				return Ok(entries.iter().cloned().map(RawRow::Toml).collect()),
+			Self::File(path) => path,
+		};
+
+		let contents = fs::read_to_string(chain_toml_parent_dir.join(path))
+			.await
+			.map_err(|e| MapEntryError::ReadFile(path.clone(), e))?;
+
+		if path.ends_with(".csv") {
+			Ok(contents
+				.lines()
+				.map(str::trim)
+				.filter(|line| !(line.is_empty() || line.starts_with('#')))
+				.map(|line| RawRow::Csv(line.split(',').map(str::to_string).collect()))
+				.collect())
+		} else {
+			let file: EntryFile =
+				toml::from_str(&contents).map_err(|e| MapEntryError::ParseFile(path.clone(), e))?;
+
+			Ok(file.entries.into_iter().map(RawRow::Toml).collect())
+		}
+	}
+}
+
+impl RawRow {
+	pub fn parse(&self, key_ty: &DatumType, val_ty: &DatumType) -> Result<MapEntry, String> {
+		match self {
+			Self::Toml(entry) => Ok(MapEntry {
+				key: key_ty.parse_toml(&entry.key)?,
+				value: val_ty.parse_toml(&entry.value)?,
+			}),
+			Self::Csv(fields) => {
+				let mut fields = fields.iter().map(String::as_str);
+
+				let out = MapEntry {
+					key: key_ty.parse_fields(&mut fields)?,
+					value: val_ty.parse_fields(&mut fields)?,
+				};
+
+				match fields.next() {
+					Some(_) => Err("too many fields".into()),
+					None => Ok(out),
+				}
+			},
+		}
+	}
+}
+
+/// Layout of a map key or value type, as written in an NF's `#[maps]` struct.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DatumType {
+	Bool,
+	U8,
+	U16,
+	U32,
+	U64,
+	I8,
+	I16,
+	I32,
+	I64,
+	Array(Box<DatumType>, usize),
+}
+
+impl DatumType {
+	/// Returns `None` for types whose layout chainsmith cannot know.
+	pub fn from_syn(ty: &Type) -> Option<Self> {
+		match ty {
+			Type::Path(p) => Some(match p.path.get_ident()?.to_string().as_str() {
+				"bool" => Self::Bool,
+				"u8" => Self::U8,
+				"u16" => Self::U16,
+				"u32" => Self::U32,
+				"u64" => Self::U64,
+				"i8" => Self::I8,
+				"i16" => Self::I16,
+				"i32" => Self::I32,
+				"i64" => Self::I64,
+				_ => return None,
+			}),
+			Type::Array(a) => {
+				let len = if let Expr::Lit(ExprLit {
+					lit: Lit::Int(len), ..
+				}) = &a.len
+				{
+					len.base10_parse().ok()?
+				} else {
+					return None;
+				};
+
+				Some(Self::Array(Box::new(Self::from_syn(&a.elem)?), len))
+			},
+			Type::Paren(p) => Self::from_syn(&p.elem),
+			Type::Group(g) => Self::from_syn(&g.elem),
+			_ => None,
+		}
+	}
+
+	pub fn parse_toml(&self, val: &Value) -> Result<MapDatum, String> {
+		match (self, val) {
+			(Self::Array(elem, len), Value::Array(vals)) => {
+				if vals.len() != *len {
+					return Err(format!("expected {self}, found {} elements", vals.len()));
+				}
+
+				vals.iter()
+					.map(|val| elem.parse_toml(val))
+					.collect::<Result<_, _>>()
+					.map(MapDatum::Array)
+			},
+			(Self::Bool, Value::Boolean(b)) => Ok(MapDatum::Bool(*b)),
+			(Self::Array(..) | Self::Bool, _) => Err(format!("expected {self}, found `{val}`")),
+			(_, Value::Integer(i)) => self.datum_from_int((*i).into()),
+			(_, Value::String(s)) => self.parse_str(s),
+			_ => Err(format!("expected {self}, found `{val}`")),
+		}
+	}
+
+	/// Parses a scalar from text.
+	///
+	/// Integers may be decimal or `0x`-prefixed hex. `u32`s may also be given
+	/// as dotted IPv4 addresses, which are read in network order (i.e., as with
+	/// `u32::from_be_bytes` on the address's octets).
+	pub fn parse_str(&self, s: &str) -> Result<MapDatum, String> {
+		let s = s.trim();
+
+		match self {
+			Self::Bool => s
+				.parse()
+				.map(MapDatum::Bool)
+				.map_err(|_| format!("expected bool, found `{s}`")),
+			Self::Array(..) => Err(format!("expected {self}, found `{s}`")),
+			Self::U32 if s.contains('.') => s
+				.parse::<Ipv4Addr>()
+				.map(|ip| MapDatum::U32(ip.into()))
+				.map_err(|_| format!("`{s}` is not a valid IPv4 address")),
+			_ => {
+				let int = if let Some(hex) = s.strip_prefix("0x") {
+					i128::from_str_radix(hex, 16)
+				} else {
+					s.parse()
+				}
+				.map_err(|_| format!("expected {self}, found `{s}`"))?;
+
+				self.datum_from_int(int)
+			},
+		}
+	}
+
+	/// Parses one datum from consecutive CSV fields, one field per scalar.
+	pub fn parse_fields<'a>(
+		&self,
+		fields: &mut impl Iterator<Item = &'a str>,
+	) -> Result<MapDatum, String> {
+		match self {
+			Self::Array(elem, len) => (0..*len)
+				.map(|_| elem.parse_fields(fields))
+				.collect::<Result<_, _>>()
+				.map(MapDatum::Array),
+			_ => self.parse_str(fields.next().ok_or("too few fields")?),
+		}
+	}
+
+	fn datum_from_int(&self, int: i128) -> Result<MapDatum, String> {
+		let out = match self {
+			Self::U8 => u8::try_from(int).map(MapDatum::U8),
+			Self::U16 => u16::try_from(int).map(MapDatum::U16),
+			Self::U32 => u32::try_from(int).map(MapDatum::U32),
+			Self::U64 => u64::try_from(int).map(MapDatum::U64),
+			Self::I8 => i8::try_from(int).map(MapDatum::I8),
+			Self::I16 => i16::try_from(int).map(MapDatum::I16),
+			Self::I32 => i32::try_from(int).map(MapDatum::I32),
+			Self::I64 => i64::try_from(int).map(MapDatum::I64),
+			Self::Bool | Self::Array(..) => return Err(format!("expected {self}, found `{int}`")),
+		};
+
+		out.map_err(|_| format!("`{int}` is out of range for {self}"))
+	}
+}
+
+impl Display for DatumType {
+	fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+		match self {
+			Self::Bool => write!(f, "bool"),
+			Self::U8 => write!(f, "u8"),
+			Self::U16 => write!(f, "u16"),
+			Self::U32 => write!(f, "u32"),
+			Self::U64 => write!(f, "u64"),
+			Self::I8 => write!(f, "i8"),
+			Self::I16 => write!(f, "i16"),
+			Self::I32 => write!(f, "i32"),
+			Self::I64 => write!(f, "i64"),
+			Self::Array(elem, len) => write!(f, "[{elem}; {len}]"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn toml_value(src: &str) -> Value {
+		toml::from_str::<RawEntry>(&format!("key = {src}\nvalue = 0"))
+			.unwrap()
+			.key
+	}
+
+	fn csv_row(line: &str) -> RawRow {
+		RawRow::Csv(line.split(',').map(str::to_string).collect())
+	}
+
+	#[test]
+	fn toml_scalars() {
+		assert_eq!(
+			DatumType::Bool.parse_toml(&toml_value("true")),
+			Ok(MapDatum::Bool(true))
+		);
+		assert_eq!(
+			DatumType::I16.parse_toml(&toml_value("-3")),
+			Ok(MapDatum::I16(-3))
+		);
+		assert_eq!(
+			DatumType::U8.parse_toml(&toml_value("\"0xff\"")),
+			Ok(MapDatum::U8(0xff))
+		);
+		assert_eq!(
+			DatumType::U32.parse_toml(&toml_value("\"10.0.0.1\"")),
+			Ok(MapDatum::U32(0x0a00_0001))
+		);
+	}
+
+	#[test]
+	fn toml_rejects_mismatches() {
+		assert!(DatumType::U8.parse_toml(&toml_value("256")).is_err());
+		assert!(DatumType::U64.parse_toml(&toml_value("-1")).is_err());
+		assert!(DatumType::Bool.parse_toml(&toml_value("1")).is_err());
+		assert!(DatumType::U16
+			.parse_toml(&toml_value("\"10.0.0.1\""))
+			.is_err());
+		assert!(DatumType::U32
+			.parse_toml(&toml_value("\"10.0.0.256\""))
+			.is_err());
+		assert!(DatumType::U32.parse_toml(&toml_value("1.5")).is_err());
+	}
+
+	#[test]
+	fn toml_arrays() {
+		let ty = DatumType::Array(Box::new(DatumType::U8), 2);
+
+		assert_eq!(
+			ty.parse_toml(&toml_value("[1, \"0x02\"]")),
+			Ok(MapDatum::Array(vec![MapDatum::U8(1), MapDatum::U8(2)]))
+		);
+		assert!(ty.parse_toml(&toml_value("[1]")).is_err());
+		assert!(ty.parse_toml(&toml_value("1")).is_err());
+	}
+
+	#[test]
+	fn csv_rows_fill_key_then_value() {
+		let key_ty = DatumType::Array(Box::new(DatumType::U16), 2);
+		let val_ty = DatumType::U32;
+
+		assert_eq!(
+			csv_row("1, 0x10,192.168.0.1").parse(&key_ty, &val_ty),
+			Ok(MapEntry {
+				key: MapDatum::Array(vec![MapDatum::U16(1), MapDatum::U16(16)]),
+				value: MapDatum::U32(0xc0a8_0001),
+			})
+		);
+	}
+
+	#[test]
+	fn csv_rows_need_every_field() {
+		let (key_ty, val_ty) = (DatumType::U8, DatumType::Bool);
+
+		assert_eq!(
+			csv_row("1").parse(&key_ty, &val_ty),
+			Err("too few fields".into())
+		);
+		assert_eq!(
+			csv_row("1,true,2").parse(&key_ty, &val_ty),
+			Err("too many fields".into())
+		);
+		assert!(csv_row("1,yes").parse(&key_ty, &val_ty).is_err());
+	}
+}
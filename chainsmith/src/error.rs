@@ -1,7 +1,9 @@
 use std::{io::Error as IoError, process::Output};
 
+use goblin::error::Error as GoblinError;
 use syn::Error as SynError;
 use thiserror::Error;
+use toml::de::Error as TomlError;
 
 use crate::chain::Link;
 
@@ -33,6 +35,14 @@ pub enum WriteXdpError {
 		given_branches: usize,
 		needed_branches: usize,
 	},
+	#[error(
+		"NF {nf} splits traffic by weight, so must have one output rather than {needed_branches}"
+	)]
+	SplitBranches { nf: String, needed_branches: usize },
+	#[error("NF {0} splits traffic by weight, which is not supported alongside `fuse_userland`")]
+	FusedUserlandSplit(String),
+	#[error("NF {0} mirrors packets, which is not supported alongside `fuse_userland`")]
+	FusedUserlandMirror(String),
 }
 
 #[derive(Debug, Error)]
@@ -43,6 +53,51 @@ pub enum CompileError {
 	DoCompile(Output),
 	#[error("failed to read compiled binary {0}")]
 	ReadElf(String, #[source] IoError),
+	#[error("failed to read sources for NF {0}")]
+	ReadSource(String, #[source] IoError),
+	#[error("failed to read vmlinux BTF from {0}")]
+	ReadVmlinux(String, #[source] IoError),
+	#[error("failed to store compiled binary {0} in build cache")]
+	CacheWrite(String, #[source] IoError),
+	#[error("failed to query version of rustc {0}")]
+	RustcVersion(String, #[source] IoError),
+	#[error("failed to parse compiled eBPF program of NF {0}")]
+	ParseEbpf(String, #[source] GoblinError),
+}
+
+#[derive(Debug, Error)]
+pub enum MapEntryError {
+	#[error("failed to read map entries from {0}")]
+	ReadFile(String, #[source] IoError),
+	#[error("failed to parse map entries in {0}")]
+	ParseFile(String, #[source] TomlError),
+	#[error("NF `{nf}` has no map `{map}` in its map struct")]
+	UndeclaredMap { nf: String, map: String },
+	#[error("NF `{nf}` map `{map}` must have bool, integer, or array key and value types to be given entries")]
+	UnsupportedType { nf: String, map: String },
+	#[error("entry {index} of NF `{nf}` map `{map}` is invalid: {reason}")]
+	BadEntry {
+		nf: String,
+		map: String,
+		index: usize,
+		reason: String,
+	},
+	#[error("NF `{nf}` map `{map}` is given more entries than its size")]
+	TooManyEntries { nf: String, map: String },
+}
+
+#[derive(Debug, Error)]
+pub enum MapUpdateError {
+	#[error("no chain named `{0}`")]
+	UnknownChain(String),
+	#[error("chain `{chain}` has no NF `{nf}` with a map `{map}` of known key/value types")]
+	UnknownMap {
+		chain: String,
+		nf: String,
+		map: String,
+	},
+	#[error("entry {index} is invalid: {reason}")]
+	BadEntry { index: usize, reason: String },
 }
 
 #[derive(Debug, Error)]
@@ -52,7 +107,17 @@ pub enum ChainBuildError {
 	#[error("chain had more than one link from the special `rx` NF")]
 	TooManyRxHandlers,
 	#[error("link from {1}: source {1} unknown")]
-	UndefinedSource(Link, String),
+	UndefinedSource(Box<Link>, String),
 	#[error("link from {}: target {} unknown", .0.from, .1)]
-	UndefinedTarget(Link, String),
+	UndefinedTarget(Box<Link>, String),
+	#[error("tc hooks are only supported by the aya backend")]
+	TcNeedsAya,
+	#[error("NF `{0}` would be upcalled, but tc chains must run entirely in eBPF")]
+	TcUpcall(String),
+	#[error("NF `{0}` mirrors packets, but only XDP chains can send copies to userland")]
+	TcMirror(String),
+	#[error("traffic from rx cannot be split by weight: link it to an NF which splits it instead")]
+	RxSplit,
+	#[error("traffic from rx cannot be mirrored: mirror it from the root NF instead")]
+	RxMirror,
 }
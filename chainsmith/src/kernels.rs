@@ -0,0 +1,140 @@
+use std::{
+	collections::HashMap,
+	io::Error as IoError,
+	path::PathBuf,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+};
+
+use protocol::{Digest, ServerToClient};
+use tokio::{
+	fs,
+	sync::{Mutex, OnceCell},
+};
+
+use crate::{BuiltChain, ChainBuilder, ChainSet};
+
+/// Distinguishes in-flight BTF uploads from the same kernel.
+static PART_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies a build of one chain and target against one kernel's BTF.
+type KernelBuildKey = (String, String, Digest);
+
+struct KernelBuild {
+	/// The target-wide build this was derived from, used to detect rebuilds.
+	base: Arc<ServerToClient>,
+	/// Set once the build completes. Clients needing the same build wait on this,
+	/// and failed builds leave it empty for the next client to retry.
+	built: OnceCell<Arc<BuiltChain>>,
+}
+
+/// Stores the BTF uploaded by clients, and builds of each chain against the
+/// BTF of every kernel which has requested it.
+///
+/// Builds are cached per BTF digest until the chain is next rebuilt.
+#[derive(Clone)]
+pub struct KernelBuilds {
+	builder: Arc<ChainBuilder>,
+	btf_dir: PathBuf,
+	builds: Arc<Mutex<HashMap<KernelBuildKey, Arc<KernelBuild>>>>,
+}
+
+impl KernelBuilds {
+	/// Stores BTF in `btf` below `--cache-dir` (or `cache` in the working directory).
+	pub async fn open(builder: Arc<ChainBuilder>) -> Result<Self, IoError> {
+		let btf_dir = builder
+			.config
+			.cache_dir
+			.as_ref()
+			.map(PathBuf::from)
+			.unwrap_or_else(|| PathBuf::from("cache"))
+			.join("btf");
+		fs::create_dir_all(&btf_dir).await?;
+
+		Ok(Self {
+			builder,
+			btf_dir,
+			builds: Default::default(),
+		})
+	}
+
+	fn btf_path(&self, digest: &Digest) -> PathBuf {
+		self.btf_dir.join(hex(digest))
+	}
+
+	/// Checks whether the BTF with `digest` has already been uploaded.
+	pub async fn has_btf(&self, digest: &Digest) -> bool {
+		fs::metadata(self.btf_path(digest)).await.is_ok()
+	}
+
+	/// Stores an uploaded BTF, which must already match `digest`.
+	pub async fn put_btf(&self, digest: &Digest, btf: &[u8]) -> Result<(), IoError> {
+		let final_path = self.btf_path(digest);
+		let part = PART_COUNTER.fetch_add(1, Ordering::Relaxed);
+		let tmp_path = self.btf_dir.join(format!("{}.{part}.part", hex(digest)));
+
+		fs::write(&tmp_path, btf).await?;
+		fs::rename(&tmp_path, &final_path).await
+	}
+
+	/// Returns the chain `name` for `target`, built against the stored BTF with
+	/// `digest`.
+	///
+	/// The chain is compiled on first use, and again after each rebuild of
+	/// `chains`. Concurrent requests for the same build share one compilation,
+	/// while builds for other chains, targets, or kernels proceed independently.
+	pub async fn get(
+		&self,
+		chains: &ChainSet,
+		name: &str,
+		target: &str,
+		digest: &Digest,
+	) -> anyhow::Result<Arc<BuiltChain>> {
+		let base = chains
+			.get(name)
+			.and_then(|targets| targets.get(target))
+			.map(|build| build.message.clone())
+			.ok_or_else(|| anyhow::anyhow!("no build of chain {name} for target {target}"))?;
+
+		let key = (name.to_string(), target.to_string(), *digest);
+		let build = {
+			let mut builds = self.builds.lock().await;
+
+			match builds.get(&key) {
+				Some(build) if Arc::ptr_eq(&build.base, &base) => build.clone(),
+				_ => {
+					let build = Arc::new(KernelBuild {
+						base,
+						built: OnceCell::new(),
+					});
+					builds.insert(key, build.clone());
+					build
+				},
+			}
+		};
+
+		build
+			.built
+			.get_or_try_init(|| async {
+				let built = self
+					.builder
+					.build_with_btf(name, target, &self.btf_path(digest), &btf_id(digest))
+					.await?;
+
+				anyhow::Ok(Arc::new(built))
+			})
+			.await
+			.cloned()
+	}
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Short name for a BTF, used in build directory names.
+fn btf_id(digest: &Digest) -> String {
+	hex(&digest[..8])
+}
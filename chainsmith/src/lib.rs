@@ -1,36 +1,347 @@
+pub mod admin;
+pub mod cache;
 pub mod chain;
 pub mod config;
+pub mod entries;
 pub mod error;
+pub mod kernels;
+pub mod placement;
+pub mod server;
+pub mod updates;
+pub mod watch;
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+	collections::{BTreeMap, HashMap},
+	path::{Path, PathBuf},
+	sync::Arc,
+};
 
-use chain::Chain;
-use config::Cli;
-use protocol::{Chain as PChain, Function, ServerToClient, XdpLink};
+use cache::BuildCache;
+use chain::{Chain, MapTypes, USER_CHAIN_CRATE};
+use config::{Cli, TargetConfig};
+use error::CompileError;
+use futures_util::future;
+use placement::EbpfRequirements;
+use protocol::{
+	Bundle,
+	Chain as PChain,
+	ChainManifest,
+	Digest,
+	Features,
+	Function,
+	Hook,
+	KernelInfo,
+	ServerToClient,
+	UserlandChain,
+	XdpLink,
+};
+use ring::{
+	rand::SystemRandom,
+	signature::{Ed25519KeyPair, KeyPair},
+};
 use tokio::fs;
 use uuid::Uuid;
 
-pub async fn compile_chain(config: &Cli) -> anyhow::Result<ChainData> {
+/// The most recent build of a chain for one target.
+pub struct BuiltChain {
+	pub message: Arc<ServerToClient>,
+	/// The chain without NF payloads, for clients which fetch these separately.
+	pub manifest: Arc<ServerToClient>,
+	/// Compressed payload of each NF, indexed by digest.
+	pub payloads: HashMap<Digest, Vec<u8>>,
+	pub name_to_uuid: HashMap<String, Uuid>,
+	pub map_types: MapTypes,
+	/// Kernel features needed by each NF run in XDP.
+	pub requirements: HashMap<Uuid, EbpfRequirements>,
+	/// Key used to re-sign the chain when it is adapted to a client's kernel.
+	pub signer: Option<Arc<Ed25519KeyPair>>,
+}
+
+impl BuiltChain {
+	/// The full chain, as built.
+	pub fn chain(&self) -> &PChain {
+		match &*self.message {
+			ServerToClient::Chain(chain) => chain,
+			_ => unreachable!("built chains are always stored as full chains"),
+		}
+	}
+
+	/// Returns the chain (or its manifest) to send to a client running on `kernel`.
+	///
+	/// NFs needing eBPF features the kernel lacks are moved to userland, in which
+	/// case the chain is re-signed. Chains using protocol features outside the
+	/// client's `features` are refused.
+	pub fn message_for(
+		&self,
+		kernel: &KernelInfo,
+		features: Features,
+		manifest: bool,
+	) -> Result<Arc<ServerToClient>, String> {
+		let missing = self.chain().required_features().difference(features);
+		if missing != Features::empty() {
+			return Err(format!(
+				"chain uses protocol features the client lacks: {}.",
+				missing.names().join(", ")
+			));
+		}
+
+		let mut chain = match placement::adapt_chain(self.chain(), &self.requirements, kernel)? {
+			Some(chain) => chain,
+			None =>
+				return Ok(if manifest {
+					self.manifest.clone()
+				} else {
+					self.message.clone()
+				}),
+		};
+
+		chain.signature = None;
+		if let Some(key) = &self.signer {
+			chain.sign(key);
+		}
+
+		Ok(Arc::new(if manifest {
+			ServerToClient::ChainManifest(ChainManifest::new(&chain, &self.payloads))
+		} else {
+			ServerToClient::Chain(chain)
+		}))
+	}
+}
+
+/// The most recent chain built for each target tuple.
+pub type TargetChains = HashMap<String, BuiltChain>;
+
+/// The most recent builds of every chain, indexed by chain name.
+pub type ChainSet = Arc<HashMap<String, TargetChains>>;
+
+/// Named chain directories, in the order given on the command line.
+pub type ChainDirs = Vec<(String, PathBuf)>;
+
+/// Everything needed to (re)build the full set of served chains.
+pub struct ChainBuilder {
+	pub config: Cli,
+	pub chain_dirs: ChainDirs,
+	pub targets: BTreeMap<String, TargetConfig>,
+	/// Key used to sign each built chain, if any.
+	pub signer: Option<Arc<Ed25519KeyPair>>,
+}
+
+impl ChainBuilder {
+	pub async fn build_all(&self) -> anyhow::Result<ChainSet> {
+		compile_all(
+			&self.config,
+			&self.chain_dirs,
+			&self.targets,
+			self.signer.as_ref(),
+		)
+		.await
+	}
+
+	/// Builds the chain `name` for `target`, compiling its eBPF NFs against the
+	/// vmlinux BTF at `vmlinux` rather than that of the target.
+	///
+	/// Sources are generated and compiled in working directories named after
+	/// `btf_id`, so that builds for different kernels do not interfere.
+	pub async fn build_with_btf(
+		&self,
+		name: &str,
+		target: &str,
+		vmlinux: &Path,
+		btf_id: &str,
+	) -> anyhow::Result<BuiltChain> {
+		let chain_dir = self
+			.chain_dirs
+			.iter()
+			.find(|(other, _)| other == name)
+			.map(|(_, dir)| dir)
+			.ok_or_else(|| anyhow::anyhow!("no chain named {name}"))?;
+		let mut target_cfg = self
+			.targets
+			.get(target)
+			.cloned()
+			.ok_or_else(|| anyhow::anyhow!("target {target} unsupported"))?;
+		target_cfg.vmlinux = Some(vmlinux.to_string_lossy().into_owned());
+
+		eprintln!("--- Preparing chain {name} for target {target} (BTF {btf_id})");
+
+		let work_dir = format!("{target}-{btf_id}");
+		let built = compile_chain(&self.config, chain_dir, target, &target_cfg, &work_dir)
+			.await?
+			.into_built(self.signer.as_ref())?;
+
+		eprintln!("--- Finished chain {name} for target {target} (BTF {btf_id})");
+
+		Ok(built)
+	}
+}
+
+/// Locates each chain directory given on the command line, naming each after
+/// its folder.
+pub async fn find_chain_dirs(config: &Cli) -> anyhow::Result<ChainDirs> {
+	let mut out: ChainDirs = vec![];
+
+	for path in &config.paths {
+		let dir = fs::canonicalize(path).await?;
+		let name = dir
+			.file_name()
+			.map(|name| name.to_string_lossy().into_owned())
+			.ok_or_else(|| anyhow::anyhow!("chain path {path} has no folder name"))?;
+
+		if out.iter().any(|(other, _)| *other == name) {
+			anyhow::bail!("two chain folders share the name {name}");
+		}
+
+		out.push((name, dir));
+	}
+
+	Ok(out)
+}
+
+/// Builds every chain for every selected target concurrently.
+///
+/// If a `signer` is given, each chain is signed so that clients can verify it.
+pub async fn compile_all(
+	config: &Cli,
+	chain_dirs: &ChainDirs,
+	targets: &BTreeMap<String, TargetConfig>,
+	signer: Option<&Arc<Ed25519KeyPair>>,
+) -> anyhow::Result<ChainSet> {
+	let builds = chain_dirs.iter().flat_map(|(name, chain_dir)| {
+		targets.iter().map(move |(target, target_cfg)| async move {
+			eprintln!("--- Preparing chain {name} for target {target}");
+
+			let chain_data = compile_chain(config, chain_dir, target, target_cfg, target)
+				.await?
+				.into_built(signer)?;
+
+			eprintln!("--- Finished chain {name} for target {target}");
+
+			anyhow::Ok((name, target.clone(), chain_data))
+		})
+	});
+
+	let mut chain_datas: HashMap<String, TargetChains> = HashMap::new();
+	for (name, target, chain_data) in future::try_join_all(builds).await? {
+		chain_datas
+			.entry(name.clone())
+			.or_default()
+			.insert(target, chain_data);
+	}
+
+	Ok(Arc::new(chain_datas))
+}
+
+/// Reads a PKCS#8-encoded Ed25519 key used to sign chains.
+pub async fn load_signing_key(path: &Path) -> anyhow::Result<Ed25519KeyPair> {
+	let pkcs8 = fs::read(path).await?;
+
+	Ed25519KeyPair::from_pkcs8(&pkcs8)
+		.map_err(|e| anyhow::anyhow!("invalid signing key {}: {e}", path.display()))
+}
+
+/// Generates a new Ed25519 signing key, writing it to `out` in PKCS#8 form.
+pub async fn generate_signing_key(out: &Path) -> anyhow::Result<Ed25519KeyPair> {
+	let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+		.map_err(|_| anyhow::anyhow!("failed to generate signing key"))?;
+
+	fs::write(out, pkcs8.as_ref()).await?;
+
+	load_signing_key(out).await
+}
+
+/// Hex encoding of a signing key's public half, as pinned by `pulley --trusted-key`.
+pub fn public_key_hex(key: &Ed25519KeyPair) -> String {
+	key.public_key()
+		.as_ref()
+		.iter()
+		.map(|byte| format!("{byte:02x}"))
+		.collect()
+}
+
+/// Writes each chain in `chains` to an offline bundle.
+///
+/// If only one chain was built for one target, the bundle is written to `out`.
+/// Otherwise, each bundle's chain and target names are inserted before the
+/// extension of `out`. Returns the paths written.
+pub async fn write_bundles(chains: &ChainSet, out: &Path) -> anyhow::Result<Vec<PathBuf>> {
+	let single = chains.len() == 1 && chains.values().all(|targets| targets.len() == 1);
+	let mut written = vec![];
+
+	for (name, targets) in chains.iter() {
+		for (target, built) in targets {
+			let chain = built.chain().clone();
+
+			let path = if single {
+				out.to_path_buf()
+			} else {
+				let stem = out.file_stem().unwrap_or_default().to_string_lossy();
+				let file_name = match out.extension() {
+					Some(ext) => format!("{stem}.{name}.{target}.{}", ext.to_string_lossy()),
+					None => format!("{stem}.{name}.{target}"),
+				};
+				out.with_file_name(file_name)
+			};
+
+			let bundle = Bundle {
+				name: name.clone(),
+				target: target.clone(),
+				chain,
+			};
+
+			fs::write(&path, bundle.to_bytes()).await?;
+			written.push(path);
+		}
+	}
+
+	Ok(written)
+}
+
+/// Compiles the chain in `chain_dir` for `target`.
+///
+/// Generated sources and build output are kept below `tmp/{work_dir}` and
+/// `target/{work_dir}` respectively.
+pub async fn compile_chain(
+	config: &Cli,
+	chain_dir: &Path,
+	target: &str,
+	target_cfg: &TargetConfig,
+	work_dir: &str,
+) -> anyhow::Result<ChainData> {
 	const TMP_DIR: &str = "tmp";
 	const XDP_DIR: &str = "xdp";
 	const USR_DIR: &str = "user";
-	let mut base_dir = fs::canonicalize(config.path.clone()).await?;
-	// base_binaries_dir.push(TMP_DIR);
-	base_dir.push("chain.toml");
+	const CACHE_DIR: &str = "cache";
+	const TARGET_DIR: &str = "target";
+	let base_dir = chain_dir.to_path_buf();
+	let chain = Chain::load(&base_dir).await?;
+	chain.check_hook()?;
 
-	let config_bytes = fs::read(&base_dir).await?;
-	let chain: Chain = toml::from_slice(&config_bytes)?;
+	let cache_dir = config
+		.cache_dir
+		.as_ref()
+		.map(PathBuf::from)
+		.unwrap_or_else(|| base_dir.join(CACHE_DIR));
+	let cache = BuildCache::open(cache_dir).await?;
 
-	base_dir.pop();
+	// Targets are built concurrently, so each needs its own source and build dirs.
+	let mut target_dir = base_dir.clone();
+	target_dir.push(TARGET_DIR);
+	target_dir.push(work_dir);
 
 	// Remove old temp data.
+	// Compiled artifacts outlive this in `cache`, so only changed NFs are rebuilt.
 	let mut tmp_dir = base_dir.clone();
 	tmp_dir.push(TMP_DIR);
+	tmp_dir.push(work_dir);
 	let _ = fs::remove_dir_all(&tmp_dir).await;
-	let _ = fs::create_dir(&tmp_dir).await;
+	fs::create_dir_all(&tmp_dir).await?;
 
 	// Analyse chain + and find output variants for packet processing NFs.
 	let nf_return_types = chain.get_nf_return_types(base_dir.clone()).await?;
+	let nf_sources = chain.digest_nf_sources(base_dir.clone()).await?;
+	let mut map_entries = chain
+		.resolve_map_entries(&base_dir, &nf_return_types)
+		.await?;
 
 	// --- XDP ---
 	// Create XDP variants.
@@ -52,8 +363,14 @@ pub async fn compile_chain(config: &Cli) -> anyhow::Result<ChainData> {
 		.write_xdp_programs(&nf_return_types, &mut src_path)
 		.await?;
 
-	let (mut binaries, mut name_to_uuid) = chain
-		.compile_xdp_binaries(src_path, &config.vmlinux)
+	let ebpfs = chain
+		.compile_xdp_binaries(
+			src_path,
+			&target_dir,
+			&target_cfg.vmlinux,
+			&cache,
+			&nf_sources,
+		)
 		.await?;
 	eprintln!("Built eBPF binaries.");
 
@@ -68,34 +385,109 @@ pub async fn compile_chain(config: &Cli) -> anyhow::Result<ChainData> {
 	chain
 		.write_userland_programs(&nf_return_types, &mut usr_dir)
 		.await?;
-	chain
-		.compile_userland_binaries(usr_dir, &mut binaries, &mut name_to_uuid, &config.target)
+	let mut dylibs = chain
+		.compile_userland_binaries(
+			usr_dir,
+			&target_dir,
+			target,
+			target_cfg,
+			&cache,
+			&nf_sources,
+		)
 		.await?;
 
+	// A fused userland build replaces those of each NF.
+	let user_chain = if chain.fuse_userland {
+		dylibs.remove(USER_CHAIN_CRATE)
+	} else {
+		None
+	};
+
 	// --- USER ---
 
+	let (mut binaries, name_to_uuid) = chain.assemble_functions(ebpfs, dylibs);
+
+	let userland = user_chain.map(|elf| {
+		let program = Function::new(USER_CHAIN_CRATE, Some(elf), None);
+		let userland = UserlandChain {
+			program: program.uuid,
+			nfs: chain
+				.functions
+				.keys()
+				.map(|name| name_to_uuid[name])
+				.collect(),
+		};
+
+		binaries.insert(program.uuid, program);
+		userland
+	});
+
 	dbg!(&name_to_uuid);
-	let links = chain.make_concrete(&name_to_uuid)?;
+	let links = chain.make_concrete(&name_to_uuid, &mut map_entries)?;
 	dbg!(&links);
 
 	Ok(ChainData {
+		hook: chain.hook,
+		userland,
 		binaries,
 		name_to_uuid,
 		links,
+		map_types: chain.map_types(&nf_return_types),
 	})
 }
 
 pub struct ChainData {
+	pub hook: Hook,
+	pub userland: Option<UserlandChain>,
 	pub binaries: HashMap<Uuid, Function>,
 	pub name_to_uuid: HashMap<String, Uuid>,
 	pub links: Vec<XdpLink>,
+	pub map_types: MapTypes,
 }
 
 impl ChainData {
-	pub fn into_single_message(self) -> Arc<ServerToClient> {
-		Arc::new(ServerToClient::Chain(PChain {
+	pub fn into_chain(self, signer: Option<&Ed25519KeyPair>) -> PChain {
+		let mut chain = PChain {
+			hook: self.hook,
 			links: self.links,
+			userland: self.userland,
 			nfs: self.binaries,
-		}))
+			signature: None,
+		};
+
+		if let Some(key) = signer {
+			chain.sign(key);
+		}
+
+		chain
+	}
+
+	pub fn into_built(
+		self,
+		signer: Option<&Arc<Ed25519KeyPair>>,
+	) -> Result<BuiltChain, CompileError> {
+		let name_to_uuid = self.name_to_uuid.clone();
+		let map_types = self.map_types.clone();
+		let chain = self.into_chain(signer.map(|key| &**key));
+		let requirements = placement::chain_requirements(&chain)?;
+
+		let mut payloads = HashMap::new();
+		for nf in chain.nfs.values() {
+			payloads
+				.entry(nf.digest)
+				.or_insert_with(|| protocol::compress_nf(nf));
+		}
+
+		Ok(BuiltChain {
+			manifest: Arc::new(ServerToClient::ChainManifest(ChainManifest::new(
+				&chain, &payloads,
+			))),
+			message: Arc::new(ServerToClient::Chain(chain)),
+			payloads,
+			name_to_uuid,
+			map_types,
+			requirements,
+			signer: signer.cloned(),
+		})
 	}
 }
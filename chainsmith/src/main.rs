@@ -1,10 +1,25 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+	net::SocketAddr,
+	sync::{Arc, Mutex},
+};
 
-use chainsmith::config::{Cli, TlsMode};
+use chainsmith::{
+	admin::AdminState,
+	config::{Cli, Command, Config, Policy, TlsMode},
+	kernels::KernelBuilds,
+	placement::PlacementLimits,
+	server,
+	updates::MapUpdateHub,
+	watch::BuildStatus,
+	ChainBuilder,
+	ChainSet,
+};
 use clap::Parser;
-use futures_util::{SinkExt, StreamExt};
 use protocol::*;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::{
+	net::{TcpListener, TcpStream},
+	sync::{mpsc, watch},
+};
 use tokio_rustls::{
 	rustls::{
 		server::AllowAnyAuthenticatedClient, //ClientCertVerifier,
@@ -15,30 +30,103 @@ use tokio_rustls::{
 	TlsAcceptor,
 };
 
-static SUPPORTED_ARCHES: phf::Map<&'static str, Option<&'static str>> = phf::phf_map! {
-	"x86_64-unknown-linux-gnu" => None,
-	"aarch64-unknown-linux-gnu" => Some("support-files/vmlinux"),
-};
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
 	let config = Cli::parse();
 
-	let mut chain_datas = HashMap::with_capacity(SUPPORTED_ARCHES.len());
-	for (target, vmlinux) in &SUPPORTED_ARCHES {
-		eprintln!("--- Preparing target {target}");
+	if let Some(Command::GenKey { out }) = &config.command {
+		let key = chainsmith::generate_signing_key(out).await?;
+		println!("Wrote signing key to {}.", out.display());
+		println!("Public key: {}", chainsmith::public_key_hex(&key));
 
-		let mut l_config = config.clone();
-		l_config.vmlinux = vmlinux.map(|v| v.to_string());
-		l_config.target = Some(target.to_string());
+		return Ok(());
+	}
 
-		let chain_data = chainsmith::compile_chain(&l_config)
-			.await?
-			.into_single_message();
+	let signer = match &config.signing_key {
+		Some(path) => {
+			let key = chainsmith::load_signing_key(path).await?;
+			println!(
+				"Signing chains with public key {}.",
+				chainsmith::public_key_hex(&key)
+			);
+			Some(Arc::new(key))
+		},
+		None => None,
+	};
+	let file_config = Config::load(&config.config).await?;
+	let targets = file_config.selected_targets(&config);
+	let chain_dirs = chainsmith::find_chain_dirs(&config).await?;
+
+	let mut policy = file_config.policy;
+	if policy.default.is_none() {
+		policy.default = chain_dirs.first().map(|(name, _)| name.clone());
+	}
+	let policy = Arc::new(policy);
+
+	let builder = ChainBuilder {
+		config: config.clone(),
+		chain_dirs,
+		targets,
+		signer,
+	};
+	let chain_datas = builder.build_all().await?;
+
+	if let Some(Command::Build { out }) = &config.command {
+		for path in chainsmith::write_bundles(&chain_datas, out).await? {
+			println!("Wrote bundle {}.", path.display());
+		}
 
-		chain_datas.insert(*target, chain_data);
+		return Ok(());
+	}
+
+	let builder = Arc::new(builder);
+	let kernel_builds = KernelBuilds::open(builder.clone()).await?;
+
+	let (chains_tx, chains_rx) = watch::channel(chain_datas);
+	let map_updates = MapUpdateHub::default();
+	let build_status = Arc::new(Mutex::new(BuildStatus::initial()));
+	let (rebuild_tx, rebuild_rx) = mpsc::unbounded_channel();
+
+	if let Some(admin_addr) = config.admin_addr {
+		let state = AdminState {
+			chains: chains_rx.clone(),
+			hub: map_updates.clone(),
+			status: build_status.clone(),
+			rebuild: rebuild_tx.clone(),
+			chain_dirs: builder.chain_dirs.clone(),
+			limits: PlacementLimits {
+				max_ns: config.placement_max_ns,
+				max_verified_insns: config.placement_max_insns,
+			},
+		};
+
+		tokio::spawn(async move {
+			if let Err(e) = chainsmith::admin::serve(admin_addr, state).await {
+				eprintln!("Admin API stopped: {e:?}");
+			}
+		});
+	}
+	drop(rebuild_tx);
+
+	if config.watch || config.admin_addr.is_some() {
+		let watch = config.watch;
+		tokio::spawn(async move {
+			let rebuilt = chainsmith::watch::rebuild_chains(
+				builder,
+				chains_tx,
+				build_status,
+				rebuild_rx,
+				watch,
+			)
+			.await;
+
+			if let Err(e) = rebuilt {
+				eprintln!("Stopped rebuilding chains: {e:?}");
+			}
+		});
+	} else {
+		drop(chains_tx);
 	}
-	let chain_datas = Arc::new(chain_datas);
 
 	// start simple WS server or something.
 	let socket = TcpListener::bind(config.conn_string)
@@ -110,14 +198,24 @@ async fn main() -> anyhow::Result<()> {
 			tokio::spawn(handle_connection(
 				stream,
 				addr,
-				chain_datas.clone(),
+				chains_rx.clone(),
+				policy.clone(),
+				map_updates.clone(),
+				kernel_builds.clone(),
 				tls_config.clone(),
 			));
 		}
 	} else {
 		while let Ok((stream, addr)) = socket.accept().await {
 			println!("New Non-TLS Conn.");
-			tokio::spawn(handle_connection_no_tls(stream, addr, chain_datas.clone()));
+			tokio::spawn(handle_connection_no_tls(
+				stream,
+				addr,
+				chains_rx.clone(),
+				policy.clone(),
+				map_updates.clone(),
+				kernel_builds.clone(),
+			));
 		}
 	}
 
@@ -127,7 +225,10 @@ async fn main() -> anyhow::Result<()> {
 async fn handle_connection(
 	raw_stream: TcpStream,
 	addr: SocketAddr,
-	c_dat: Arc<HashMap<&str, Arc<ServerToClient>>>,
+	chains: watch::Receiver<ChainSet>,
+	policy: Arc<Policy>,
+	map_updates: MapUpdateHub,
+	kernel_builds: KernelBuilds,
 	tls: Arc<ServerConfig>,
 ) {
 	let tls = TlsAcceptor::from(tls);
@@ -140,65 +241,53 @@ async fn handle_connection(
 	let stream = stream.expect("Failed TLS handshake!");
 	// let stream = raw_stream;
 
+	let identity = stream
+		.get_ref()
+		.1
+		.peer_certificates()
+		.and_then(|certs| certs.first())
+		.map(server::cert_identity);
+
+	if let Some(id) = &identity {
+		println!("Client {addr} has certificate fingerprint {id}.");
+	}
+
 	let ws_stream = tokio_tungstenite::accept_async(stream)
 		.await
 		.unwrap_or_else(|_| panic!("Failed WS handshake with {addr}!"));
 
-	let (mut ws_tx, mut ws_rx) = ws_stream.split();
-
-	while let Some(Ok(msg)) = ws_rx.next().await {
-		// msg \in tungstenite::protocol::Message
-		match protocol::deser::<ClientToServer>(&msg) {
-			Ok(Some(ClientToServer::RequestChain(target))) => {
-				let msg = if let Some(c_dat) = c_dat.get(target.as_str()) {
-					ser(&**c_dat)
-				} else {
-					ser(&ServerToClient::RequestChainError(format!(
-						"Could not fetch user chains: target {target} unsupported."
-					)))
-				};
-
-				ws_tx.send(msg).await.expect("Connection died?");
-			},
-			Ok(None) => {},
-			Err(e) => {
-				eprintln!("Error decoding message from {addr}: {e:?}");
-				break;
-			},
-		}
-	}
+	server::handle_client(
+		ws_stream,
+		addr,
+		identity,
+		policy,
+		chains,
+		map_updates,
+		kernel_builds,
+	)
+	.await;
 }
 
 async fn handle_connection_no_tls(
 	stream: TcpStream,
 	addr: SocketAddr,
-	c_dat: Arc<HashMap<&str, Arc<ServerToClient>>>,
+	chains: watch::Receiver<ChainSet>,
+	policy: Arc<Policy>,
+	map_updates: MapUpdateHub,
+	kernel_builds: KernelBuilds,
 ) {
 	let ws_stream = tokio_tungstenite::accept_async(stream)
 		.await
 		.unwrap_or_else(|_| panic!("Failed WS handshake with {addr}!"));
 
-	let (mut ws_tx, mut ws_rx) = ws_stream.split();
-
-	while let Some(Ok(msg)) = ws_rx.next().await {
-		// msg \in tungstenite::protocol::Message
-		match protocol::deser::<ClientToServer>(&msg) {
-			Ok(Some(ClientToServer::RequestChain(target))) => {
-				let msg = if let Some(c_dat) = c_dat.get(target.as_str()) {
-					ser(&**c_dat)
-				} else {
-					ser(&ServerToClient::RequestChainError(format!(
-						"Could not fetch user chains: target {target} unsupported."
-					)))
-				};
-
-				ws_tx.send(msg).await.expect("Connection died?");
-			},
-			Ok(None) => {},
-			Err(e) => {
-				eprintln!("Error decoding message from {addr}: {e:?}");
-				break;
-			},
-		}
-	}
+	server::handle_client(
+		ws_stream,
+		addr,
+		None,
+		policy,
+		chains,
+		map_updates,
+		kernel_builds,
+	)
+	.await;
 }
@@ -0,0 +1,738 @@
+use std::{
+	collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+	io::ErrorKind,
+	path::Path,
+};
+
+use goblin::elf::{
+	section_header::SHF_EXECINSTR,
+	sym::{STT_FILE, STT_SECTION},
+	Elf,
+};
+use protocol::{Chain, KernelInfo, LinkAction, NfProfile, XdpLinkState};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::{chain::Chain as ChainConfig, error::CompileError};
+
+/// File in each chain directory recording NFs moved between eBPF and userland
+/// via the admin API, which overrides their `disable_xdp` in `chain.toml`.
+pub const PLACEMENT_FILE: &str = "placement.toml";
+
+/// eBPF `call` opcode (`BPF_JMP | BPF_CALL`).
+const BPF_CALL: u8 = 0x85;
+
+/// Kernel features relied on by the eBPF program of one NF.
+#[derive(Clone, Debug, Default)]
+pub struct EbpfRequirements {
+	/// IDs of every helper called.
+	pub helpers: BTreeSet<u32>,
+	/// IDs of every map type declared.
+	pub map_types: BTreeSet<u32>,
+}
+
+impl EbpfRequirements {
+	/// Scans a compiled eBPF object for the helpers it calls and the types of
+	/// the maps it declares.
+	///
+	/// Maps are found via the symbol table, since wrappers place every map
+	/// definition in one `maps` section (or, under redbpf, one `maps/*` section
+	/// each), and each definition opens with its map type.
+	pub fn from_elf(bytes: &[u8]) -> Result<Self, goblin::error::Error> {
+		let mut out = Self::default();
+		let elf = Elf::parse(bytes)?;
+
+		let read_u32 = |b: &[u8]| {
+			let b = [b[0], b[1], b[2], b[3]];
+			if elf.little_endian {
+				u32::from_le_bytes(b)
+			} else {
+				u32::from_be_bytes(b)
+			}
+		};
+		let section_data = |index: usize| {
+			let section = elf.section_headers.get(index)?;
+			bytes.get(section.file_range()?)
+		};
+
+		for section in &elf.section_headers {
+			if section.sh_flags & u64::from(SHF_EXECINSTR) == 0 {
+				continue;
+			}
+
+			let data = match section.file_range().and_then(|range| bytes.get(range)) {
+				Some(data) => data,
+				None => continue,
+			};
+
+			for insn in data.chunks_exact(8) {
+				// Calls with a non-zero source register are to BPF functions or kfuncs.
+				if insn[0] == BPF_CALL && insn[1] >> 4 == 0 {
+					out.helpers.insert(read_u32(&insn[4..]));
+				}
+			}
+		}
+
+		for sym in elf.syms.iter() {
+			if matches!(sym.st_type(), STT_SECTION | STT_FILE) {
+				continue;
+			}
+
+			let in_maps = elf
+				.section_headers
+				.get(sym.st_shndx)
+				.and_then(|section| elf.shdr_strtab.get_at(section.sh_name))
+				.map(|name| name == "maps" || name.starts_with("maps/"))
+				.unwrap_or_default();
+			if !in_maps {
+				continue;
+			}
+
+			let map_type = section_data(sym.st_shndx)
+				.and_then(|data| data.get(sym.st_value as usize..)?.get(..4));
+			if let Some(map_type) = map_type {
+				out.map_types.insert(read_u32(map_type));
+			}
+		}
+
+		Ok(out)
+	}
+
+	/// Lists every requirement `kernel` does not meet.
+	pub fn missing(&self, kernel: &KernelInfo) -> Vec<String> {
+		let helpers = self
+			.helpers
+			.difference(&kernel.helpers)
+			.map(|id| format!("helper {id}"));
+		let map_types = self
+			.map_types
+			.difference(&kernel.map_types)
+			.map(|id| format!("map type {id}"));
+
+		helpers.chain(map_types).collect()
+	}
+}
+
+/// Finds the eBPF requirements of every NF which `chain` runs in XDP.
+pub fn chain_requirements(chain: &Chain) -> Result<HashMap<Uuid, EbpfRequirements>, CompileError> {
+	let mut out = HashMap::new();
+
+	for link in chain.links.iter().filter(|link| !link.disable_xdp) {
+		let ebpf = match chain.nfs.get(&link.uuid).and_then(|nf| nf.ebpf.as_ref()) {
+			Some(ebpf) => ebpf,
+			None => continue,
+		};
+		let prog = match link.state {
+			XdpLinkState::Tail => &ebpf.end,
+			XdpLinkState::Body(_) => &ebpf.link,
+		};
+
+		let reqs = EbpfRequirements::from_elf(prog)
+			.map_err(|e| CompileError::ParseEbpf(link.uuid.to_string(), e))?;
+		out.insert(link.uuid, reqs);
+	}
+
+	Ok(out)
+}
+
+/// Adjusts `chain` to run on a client's `kernel`.
+///
+/// NFs whose eBPF programs need features the kernel lacks are moved to userland
+/// (as though marked `disable_xdp`), and the links to them become upcalls. NFs
+/// which declare maps cannot be moved, as their maps are defined in (and only
+/// loaded with) their eBPF programs.
+/// Returns `None` if no changes are needed, or an error if the chain cannot run
+/// on `kernel` at all.
+///
+/// Kernels are only probed for XDP support, and tc chains have no upcall path,
+/// so these are left as built.
+pub fn adapt_chain(
+	chain: &Chain,
+	requirements: &HashMap<Uuid, EbpfRequirements>,
+	kernel: &KernelInfo,
+) -> Result<Option<Chain>, String> {
+	if !kernel.probed || chain.hook.is_tc() {
+		return Ok(None);
+	}
+
+	if !kernel.xdp {
+		return Err(format!(
+			"kernel {} cannot load XDP programs.",
+			kernel.release
+		));
+	}
+
+	let mut moved = HashSet::new();
+	for link in &chain.links {
+		let missing = match requirements.get(&link.uuid) {
+			Some(reqs) => reqs.missing(kernel),
+			None => continue,
+		};
+
+		if missing.is_empty() {
+			continue;
+		}
+
+		let has_userland = chain
+			.nfs
+			.get(&link.uuid)
+			.map(|nf| nf.elf.is_some())
+			.unwrap_or_default()
+			|| chain
+				.userland
+				.as_ref()
+				.map(|userland| userland.nfs.contains(&link.uuid))
+				.unwrap_or_default();
+
+		// Maps are only created by loading an NF's eBPF program, so NFs declaring
+		// any (themselves, or fused into their program) would lose their state.
+		let has_maps = [link.uuid]
+			.iter()
+			.chain(&link.fused)
+			.filter_map(|id| chain.links.iter().find(|link| link.uuid == *id))
+			.any(|link| !link.map_names.is_empty());
+
+		if link.root || !has_userland || has_maps {
+			return Err(format!(
+				"NF {} needs {}, unsupported by kernel {}, and cannot run in userland{}.",
+				link.uuid,
+				missing.join(", "),
+				kernel.release,
+				if has_maps { " without its maps" } else { "" },
+			));
+		}
+
+		eprintln!(
+			"Moving NF {} to userland: kernel {} lacks {}.",
+			link.uuid,
+			kernel.release,
+			missing.join(", "),
+		);
+		moved.insert(link.uuid);
+	}
+
+	if moved.is_empty() {
+		return Ok(None);
+	}
+
+	let mut chain = chain.clone();
+	for link in chain.links.iter_mut() {
+		if moved.contains(&link.uuid) {
+			link.disable_xdp = true;
+		}
+
+		if let XdpLinkState::Body(actions) = &mut link.state {
+			for action in actions.iter_mut() {
+				if let LinkAction::Tailcall(id) = action {
+					if moved.contains(id) {
+						*action = LinkAction::Upcall(*id);
+					}
+				}
+			}
+		}
+	}
+
+	Ok(Some(chain))
+}
+
+/// Where an NF processes packets.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Placement {
+	Xdp,
+	Userland,
+}
+
+/// NFs moved between eBPF and userland, as stored in [`PLACEMENT_FILE`].
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PlacementOverrides {
+	#[serde(default)]
+	pub nfs: BTreeMap<String, Placement>,
+}
+
+impl PlacementOverrides {
+	/// Reads the overrides stored in `chain_dir`, if any.
+	pub async fn load(chain_dir: &Path) -> anyhow::Result<Self> {
+		match fs::read(chain_dir.join(PLACEMENT_FILE)).await {
+			Ok(bytes) => Ok(toml::from_slice(&bytes)?),
+			Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// Writes these overrides to `chain_dir`.
+	pub async fn store(&self, chain_dir: &Path) -> anyhow::Result<()> {
+		fs::write(chain_dir.join(PLACEMENT_FILE), toml::to_string(self)?).await?;
+
+		Ok(())
+	}
+
+	/// Sets `disable_xdp` on each NF in `chain` named here.
+	///
+	/// NFs no longer in the chain are ignored.
+	pub fn apply(&self, chain: &mut ChainConfig) {
+		for (name, placement) in &self.nfs {
+			if let Some(function) = chain.functions.get_mut(name) {
+				function.disable_xdp = *placement == Placement::Userland;
+			}
+		}
+	}
+}
+
+/// Costs of one NF, combined from the latest profile of every client running
+/// its chain.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct NfCosts {
+	/// Number of clients which reported this NF.
+	pub clients: usize,
+	/// Highest mean time (in ns) taken by the NF's eBPF program on a test packet.
+	pub xdp_ns: Option<u64>,
+	/// Most instructions processed by the verifier on loading the NF's eBPF program.
+	pub verified_insns: Option<u32>,
+	pub user_packets: u64,
+	pub user_ns: u64,
+	pub upcalls: u64,
+}
+
+impl NfCosts {
+	pub fn add(&mut self, profile: &NfProfile) {
+		self.clients += 1;
+		self.xdp_ns = self.xdp_ns.max(profile.xdp_ns);
+		self.verified_insns = self.verified_insns.max(profile.verified_insns);
+		self.user_packets += profile.user_packets;
+		self.user_ns += profile.user_ns;
+		self.upcalls += profile.upcalls;
+	}
+
+	/// Mean time (in ns) the NF took to process each packet in userland.
+	pub fn user_ns_per_packet(&self) -> Option<u64> {
+		self.user_ns.checked_div(self.user_packets)
+	}
+
+	/// Whether any cost has been measured for this NF.
+	pub fn is_measured(&self) -> bool {
+		self.xdp_ns.is_some() || self.verified_insns.is_some() || self.user_packets != 0
+	}
+}
+
+/// Costs above which an NF is placed in userland.
+#[derive(Clone, Copy, Debug)]
+pub struct PlacementLimits {
+	/// Most time (in ns) an NF may take per packet, in eBPF or userland.
+	pub max_ns: u64,
+	/// Most instructions the verifier may process when loading an NF.
+	pub max_verified_insns: u32,
+}
+
+impl PlacementLimits {
+	/// Explains which limit `costs` exceeds, if any.
+	fn exceeded_by(&self, costs: &NfCosts) -> Option<String> {
+		if let Some(insns) = costs
+			.verified_insns
+			.filter(|n| *n > self.max_verified_insns)
+		{
+			return Some(format!(
+				"verifier processed {insns} instructions, above {}",
+				self.max_verified_insns
+			));
+		}
+
+		if let Some(ns) = costs.xdp_ns.filter(|ns| *ns > self.max_ns) {
+			return Some(format!(
+				"took {ns}ns per packet in eBPF, above {}ns",
+				self.max_ns
+			));
+		}
+
+		if let Some(ns) = costs.user_ns_per_packet().filter(|ns| *ns > self.max_ns) {
+			return Some(format!(
+				"took {ns}ns per packet in userland, above {}ns",
+				self.max_ns
+			));
+		}
+
+		None
+	}
+}
+
+/// Proposed placement of one NF.
+#[derive(Clone, Debug, Serialize)]
+pub struct NfPlacement {
+	pub current: Placement,
+	pub proposed: Placement,
+	pub reason: String,
+	pub costs: Option<NfCosts>,
+}
+
+/// Proposes where to run each NF in `chain`, given its measured `costs`.
+///
+/// Every NF runs in eBPF, so that as few packets as possible are upcalled, unless
+/// it exceeds `limits`. Root NFs, NFs at tc hooks, NFs only reached as mirrors,
+/// and NFs without any measured costs are left where they are. Links marked with
+/// `!` still upcall to their target wherever it is placed.
+///
+/// The costs of a fused program are measured as a whole, against its first NF.
+/// Fused NFs are then left where they are, rather than one being moved for the
+/// cost of all.
+pub fn propose_placement(
+	chain: &ChainConfig,
+	costs: &HashMap<String, NfCosts>,
+	limits: &PlacementLimits,
+) -> BTreeMap<String, NfPlacement> {
+	let roots: HashSet<&str> = chain
+		.links
+		.iter()
+		.filter(|link| link.from == "rx")
+		.flat_map(|link| link.to.iter())
+		.map(|to| to.trim_start_matches('!'))
+		.collect();
+	let linked: HashSet<&str> = chain
+		.links
+		.iter()
+		.flat_map(|link| link.to.iter())
+		.map(|to| to.trim_start_matches('!'))
+		.collect();
+	let runs = chain.fused_runs();
+
+	chain
+		.functions
+		.iter()
+		.map(|(name, function)| {
+			let mirror_only = chain.is_mirror(name) && !linked.contains(name.as_str());
+			let current = if function.disable_xdp || mirror_only {
+				Placement::Userland
+			} else {
+				Placement::Xdp
+			};
+			let costs = costs.get(name).filter(|costs| costs.is_measured());
+
+			let (proposed, reason) = if roots.contains(name.as_str()) {
+				(current, "root NF is always placed by `chain.toml`".into())
+			} else if chain.hook.is_tc() {
+				(current, "tc chains run entirely in eBPF".into())
+			} else if mirror_only {
+				(current, "mirror NFs always run in userland".into())
+			} else if let Some(head) = runs
+				.iter()
+				.find(|(_, run)| run.contains(name))
+				.map(|(head, _)| head)
+			{
+				(
+					current,
+					format!("fused into the program of {head}, which is measured instead"),
+				)
+			} else if let Some(costs) = costs {
+				match limits.exceeded_by(costs) {
+					Some(reason) if runs.contains_key(name) => (
+						current,
+						format!(
+							"fused program {reason}; set `disable_fusion` on the NFs after it to \
+							 measure each alone"
+						),
+					),
+					Some(reason) => (Placement::Userland, reason),
+					None => (
+						Placement::Xdp,
+						"within limits, so run in eBPF to avoid upcalls".into(),
+					),
+				}
+			} else {
+				(current, "no costs measured yet".into())
+			};
+
+			let placement = NfPlacement {
+				current,
+				proposed,
+				reason,
+				costs: costs.cloned(),
+			};
+
+			(name.clone(), placement)
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use protocol::XdpLink;
+
+	use super::*;
+
+	fn limits() -> PlacementLimits {
+		PlacementLimits {
+			max_ns: 1_000,
+			max_verified_insns: 1_000_000,
+		}
+	}
+
+	fn xdp_costs(xdp_ns: u64) -> NfCosts {
+		NfCosts {
+			clients: 1,
+			xdp_ns: Some(xdp_ns),
+			verified_insns: Some(100),
+			..Default::default()
+		}
+	}
+
+	/// Each action of `state` as its kind and target NF, or `None` for a tail.
+	fn acts(state: &XdpLinkState) -> Option<Vec<(u8, Option<Uuid>)>> {
+		match state {
+			XdpLinkState::Body(acts) => Some(
+				acts.iter()
+					.map(|act| (act.to_kind(), act.next_nf()))
+					.collect(),
+			),
+			XdpLinkState::Tail => None,
+		}
+	}
+
+	fn upcall(nf: Uuid) -> (u8, Option<Uuid>) {
+		(LinkAction::Upcall(nf).to_kind(), Some(nf))
+	}
+
+	fn tailcall(nf: Uuid) -> (u8, Option<Uuid>) {
+		(LinkAction::Tailcall(nf).to_kind(), Some(nf))
+	}
+
+	fn user_costs(user_ns_per_packet: u64) -> NfCosts {
+		NfCosts {
+			clients: 1,
+			user_packets: 10,
+			user_ns: 10 * user_ns_per_packet,
+			upcalls: 10,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn proposals_move_nfs_and_their_links() {
+		let mut chain: ChainConfig = toml::from_str(
+			r#"
+			[functions.root]
+			[functions.a]
+			[functions.b]
+			disable_xdp = true
+			[functions.c]
+			[functions.m]
+
+			[[links]]
+			from = "rx"
+			to = ["root"]
+			[[links]]
+			from = "root"
+			to = ["a", "!b"]
+			mirror = "m"
+			[[links]]
+			from = "a"
+			to = ["tx"]
+			[[links]]
+			from = "b"
+			to = ["c"]
+			[[links]]
+			from = "c"
+			to = ["drop"]
+			"#,
+		)
+		.unwrap();
+		let costs = HashMap::from([
+			("root".into(), xdp_costs(5_000)),
+			("a".into(), xdp_costs(5_000)),
+			("b".into(), user_costs(10)),
+			("m".into(), user_costs(10)),
+		]);
+
+		let proposal = propose_placement(&chain, &costs, &limits());
+
+		let proposed = |name: &str| (proposal[name].current, proposal[name].proposed);
+		assert_eq!(proposed("root"), (Placement::Xdp, Placement::Xdp));
+		assert_eq!(proposed("a"), (Placement::Xdp, Placement::Userland));
+		assert_eq!(proposed("b"), (Placement::Userland, Placement::Xdp));
+		assert_eq!(proposed("c"), (Placement::Xdp, Placement::Xdp));
+		assert_eq!(proposed("m"), (Placement::Userland, Placement::Userland));
+		assert!(proposal["m"].reason.contains("mirror"));
+
+		let overrides = PlacementOverrides {
+			nfs: proposal
+				.iter()
+				.filter(|(_, nf)| nf.proposed != nf.current)
+				.map(|(name, nf)| (name.clone(), nf.proposed))
+				.collect(),
+		};
+		overrides.apply(&mut chain);
+
+		let fn_map: HashMap<_, _> = chain
+			.functions
+			.keys()
+			.map(|name| (name.clone(), Uuid::new_v4()))
+			.collect();
+		let links = chain
+			.make_concrete(&fn_map, &mut Default::default())
+			.unwrap();
+		let state = |name: &str| {
+			links
+				.iter()
+				.find(|link| link.uuid == fn_map[name])
+				.and_then(|link| acts(&link.state))
+		};
+
+		// `!` links upcall wherever their target runs.
+		assert_eq!(
+			state("root"),
+			Some(vec![upcall(fn_map["a"]), upcall(fn_map["b"])])
+		);
+		assert_eq!(state("b"), Some(vec![tailcall(fn_map["c"])]));
+	}
+
+	/// A chain of `root -> a -> b`, with both links tail calls.
+	fn kernel_chain() -> (Chain, [Uuid; 3]) {
+		let ids = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+		let link = |i: usize, state| XdpLink {
+			uuid: ids[i],
+			state,
+			root: i == 0,
+			disable_xdp: false,
+			fused: vec![],
+			weights: vec![],
+			mirror: None,
+			map_names: vec![],
+			map_entries: Default::default(),
+		};
+		let nf = |i: usize| {
+			let mut nf = protocol::Function::new(&i.to_string(), Some(vec![]), None);
+			nf.uuid = ids[i];
+			(ids[i], nf)
+		};
+
+		let chain = Chain {
+			hook: Default::default(),
+			links: vec![
+				link(0, XdpLinkState::Body(vec![LinkAction::Tailcall(ids[1])])),
+				link(
+					1,
+					XdpLinkState::Body(vec![LinkAction::Tailcall(ids[2]), LinkAction::Tx]),
+				),
+				link(2, XdpLinkState::Tail),
+			],
+			userland: None,
+			nfs: (0..3).map(nf).collect(),
+			signature: None,
+		};
+
+		(chain, ids)
+	}
+
+	fn kernel() -> KernelInfo {
+		KernelInfo {
+			release: "test".into(),
+			probed: true,
+			xdp: true,
+			helpers: BTreeSet::from([1, 2]),
+			map_types: BTreeSet::from([1, 2]),
+			..Default::default()
+		}
+	}
+
+	fn needs_helper(helper: u32) -> EbpfRequirements {
+		EbpfRequirements {
+			helpers: BTreeSet::from([helper]),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn adapted_chains_upcall_moved_nfs() {
+		let (chain, ids) = kernel_chain();
+		let reqs = HashMap::from([(ids[1], needs_helper(1)), (ids[2], needs_helper(99))]);
+
+		let adapted = adapt_chain(&chain, &reqs, &kernel()).unwrap().unwrap();
+
+		let moved: Vec<_> = adapted.links.iter().map(|link| link.disable_xdp).collect();
+		assert_eq!(moved, [false, false, true]);
+		assert_eq!(
+			acts(&adapted.links[1].state),
+			Some(vec![upcall(ids[2]), (LinkAction::Tx.to_kind(), None)])
+		);
+		assert_eq!(acts(&adapted.links[0].state), Some(vec![tailcall(ids[1])]));
+	}
+
+	#[test]
+	fn adapted_chains_keep_supported_nfs() {
+		let (chain, ids) = kernel_chain();
+		let reqs = HashMap::from([(ids[2], needs_helper(2))]);
+
+		assert!(adapt_chain(&chain, &reqs, &kernel()).unwrap().is_none());
+
+		let unprobed = KernelInfo::default();
+		let reqs = HashMap::from([(ids[2], needs_helper(99))]);
+		assert!(adapt_chain(&chain, &reqs, &unprobed).unwrap().is_none());
+	}
+
+	#[test]
+	fn adapted_chains_cannot_move_root_or_ebpf_only_nfs() {
+		let (mut chain, ids) = kernel_chain();
+		let reqs = HashMap::from([(ids[0], needs_helper(99))]);
+		assert!(adapt_chain(&chain, &reqs, &kernel()).is_err());
+
+		chain.nfs.get_mut(&ids[2]).unwrap().elf = None;
+		let reqs = HashMap::from([(ids[2], needs_helper(99))]);
+		assert!(adapt_chain(&chain, &reqs, &kernel()).is_err());
+	}
+
+	#[test]
+	fn adapted_chains_cannot_move_nfs_with_maps() {
+		let (mut chain, ids) = kernel_chain();
+		chain.links[2].map_names = vec!["blocked_ips".into()];
+		let reqs = HashMap::from([(ids[2], needs_helper(99))]);
+
+		let err = adapt_chain(&chain, &reqs, &kernel()).unwrap_err();
+		assert!(err.contains("without its maps"), "{err}");
+	}
+
+	#[test]
+	fn fused_nfs_are_not_evicted_for_their_program() {
+		let chain: ChainConfig = toml::from_str(
+			r#"
+			fuse = true
+
+			[functions.root]
+			disable_xdp = true
+			[functions.a]
+			[functions.b]
+			[functions.c]
+			disable_fusion = true
+
+			[[links]]
+			from = "rx"
+			to = ["root"]
+			[[links]]
+			from = "root"
+			to = ["a"]
+			[[links]]
+			from = "a"
+			to = ["b"]
+			[[links]]
+			from = "b"
+			to = ["c"]
+			[[links]]
+			from = "c"
+			to = ["tx"]
+			"#,
+		)
+		.unwrap();
+		let costs = HashMap::from([
+			("a".into(), xdp_costs(5_000)),
+			("c".into(), xdp_costs(5_000)),
+		]);
+
+		let placement = propose_placement(&chain, &costs, &limits());
+
+		assert_eq!(placement["a"].proposed, Placement::Xdp);
+		assert!(placement["a"].reason.contains("disable_fusion"));
+		assert_eq!(placement["b"].proposed, Placement::Xdp);
+		assert!(placement["b"]
+			.reason
+			.contains("fused into the program of a"));
+		assert_eq!(placement["c"].proposed, Placement::Userland);
+	}
+}
@@ -0,0 +1,549 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use futures_util::{future::OptionFuture, Sink, SinkExt, Stream, StreamExt};
+use protocol::{
+	check_version,
+	ser,
+	ClientHello,
+	ClientToServer,
+	Digest,
+	Features,
+	KernelInfo,
+	MapUpdate,
+	ServerHello,
+	ServerToClient,
+	CHUNK_SIZE,
+	MIN_PROTOCOL_VERSION,
+	PROTOCOL_VERSION,
+};
+use ring::digest::{digest, SHA256};
+use tokio::{
+	io::{AsyncRead, AsyncWrite},
+	sync::{oneshot, watch},
+	task::JoinHandle,
+};
+use tokio_rustls::rustls::Certificate;
+use tokio_tungstenite::{
+	tungstenite::{Error as WsError, Message},
+	WebSocketStream,
+};
+
+use crate::{
+	config::Policy,
+	kernels::KernelBuilds,
+	updates::{AckSender, MapUpdateHub},
+	BuiltChain,
+	ChainSet,
+};
+
+/// Identifies a client by the hex SHA-256 fingerprint of its TLS certificate.
+pub fn cert_identity(cert: &Certificate) -> String {
+	digest(&SHA256, &cert.0)
+		.as_ref()
+		.iter()
+		.map(|byte| format!("{byte:02x}"))
+		.collect()
+}
+
+/// The chain (and target) a client is running, and whether it is sent full
+/// chains or only manifests.
+struct Subscription {
+	chain: String,
+	target: String,
+	manifest: bool,
+	/// The build of this chain against the client's BTF, if any.
+	build: Option<Arc<BuiltChain>>,
+}
+
+/// A chain requested by (or rebuilt for) a client, awaiting its build against
+/// the client's BTF.
+struct PendingBuild {
+	sub: Subscription,
+	/// The chains current when the build was requested.
+	chains: ChainSet,
+	/// Whether the chain is pushed to the client following a rebuild.
+	rebuild: bool,
+	build: JoinHandle<Option<Arc<BuiltChain>>>,
+}
+
+impl PendingBuild {
+	/// Builds the chain `sub` names against `btf` in the background, so that the
+	/// client's other messages and map updates are served meanwhile.
+	fn spawn(
+		kernels: &KernelBuilds,
+		btf: Option<Digest>,
+		chains: ChainSet,
+		sub: Subscription,
+		rebuild: bool,
+	) -> Self {
+		let kernels = kernels.clone();
+		let build_chains = chains.clone();
+		let (name, target) = (sub.chain.clone(), sub.target.clone());
+
+		let build = tokio::spawn(async move {
+			kernel_build(&kernels, btf.as_ref(), &build_chains, &name, &target).await
+		});
+
+		Self {
+			sub,
+			chains,
+			rebuild,
+			build,
+		}
+	}
+}
+
+/// Serves chain requests from a single client until it disconnects.
+///
+/// Once a client has requested a chain, any rebuild of that chain for its
+/// target, and any map updates made to it, are pushed over the same connection.
+pub async fn handle_client<S>(
+	ws_stream: WebSocketStream<S>,
+	addr: SocketAddr,
+	identity: Option<String>,
+	policy: Arc<Policy>,
+	mut chains: watch::Receiver<ChainSet>,
+	hub: MapUpdateHub,
+	kernels: KernelBuilds,
+) where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	let (mut ws_tx, mut ws_rx) = ws_stream.split();
+	let mut subscription: Option<Subscription> = None;
+	let mut updates_open = true;
+
+	let accepts_btf = policy.accepts_btf(identity.as_deref());
+	let handshake = handshake(&mut ws_tx, &mut ws_rx, addr, &kernels, accepts_btf);
+	let (hello, btf) = match handshake.await {
+		Some(hello) => hello,
+		None => return,
+	};
+	let features = hello.features.intersection(Features::ALL);
+	let kernel = hello.kernel.clone();
+
+	let (registration, mut map_updates) = hub.register(addr, identity.clone(), hello);
+	let mut pending_acks: HashMap<u64, AckSender> = HashMap::new();
+	let mut next_update_id = 0u64;
+	let mut pending_build: Option<PendingBuild> = None;
+	// Last chain (or manifest) sent to the client.
+	let mut last_chain: Option<Message> = None;
+
+	loop {
+		tokio::select! {
+			msg = ws_rx.next() => {
+				// msg \in tungstenite::protocol::Message
+				let msg = match msg {
+					Some(Ok(msg)) => msg,
+					_ => break,
+				};
+
+				let msg = match protocol::deser::<ClientToServer>(&msg) {
+					Ok(Some(msg)) => msg,
+					Ok(None) => continue,
+					Err(e) => {
+						eprintln!("Error decoding message from {addr}: {e:?}");
+						break;
+					},
+				};
+
+				let (requested, target, manifest) = match msg {
+					ClientToServer::RequestChain(target) => (None, target, false),
+					ClientToServer::RequestNamedChain { name, target } => (Some(name), target, false),
+					// Clients may only be sent manifests if they can fetch NFs.
+					ClientToServer::RequestManifest { name, target } =>
+						(name, target, features.contains(Features::CHUNKED_TRANSFER)),
+					ClientToServer::Hello(_) | ClientToServer::KernelBtf(_) => continue,
+					ClientToServer::Profile(profile) => {
+						registration.report_profile(profile);
+						continue;
+					},
+					ClientToServer::MapUpdateAck { id, result } => {
+						if let Some(ack) = pending_acks.remove(&id) {
+							let _ = ack.send(result);
+						}
+						continue;
+					},
+					ClientToServer::RequestNf { digest, offset } => {
+						let current = chains.borrow().clone();
+						let build = subscription.as_ref().and_then(|sub| match &sub.build {
+							Some(build) => Some(&**build),
+							None => current.get(&sub.chain)?.get(&sub.target),
+						});
+
+						if send_nf(&mut ws_tx, build, &digest, offset).await.is_err() {
+							break;
+						}
+						continue;
+					},
+				};
+
+				match policy.resolve(identity.as_deref(), requested.as_deref()) {
+					Ok(name) => {
+						let sub = Subscription {
+							chain: name.to_string(),
+							target,
+							manifest,
+							build: None,
+						};
+						let current = chains.borrow_and_update().clone();
+
+						// Any build for an earlier request still completes (and is
+						// cached), but is no longer sent.
+						pending_build = Some(PendingBuild::spawn(&kernels, btf, current, sub, false));
+					},
+					Err(e) => {
+						let msg = ser(&ServerToClient::RequestChainError(format!(
+							"Could not fetch user chains: {e}"
+						)));
+
+						if ws_tx.send(msg).await.is_err() {
+							break;
+						}
+					},
+				}
+			},
+			built = OptionFuture::from(pending_build.as_mut().map(|pending| &mut pending.build)),
+				if pending_build.is_some() =>
+			{
+				let PendingBuild { mut sub, chains: current, rebuild, .. } =
+					pending_build.take().expect("Only polled while a build is pending.");
+				sub.build = built.and_then(Result::ok).flatten();
+
+				registration.subscribe(
+					&sub.chain,
+					&sub.target,
+					sub.build.as_ref().map(|build| build.name_to_uuid.clone()),
+				);
+
+				let chain = chain_message(
+					&current,
+					sub.build.as_deref(),
+					&sub.chain,
+					&sub.target,
+					&kernel,
+					features,
+					sub.manifest,
+				);
+				if rebuild {
+					println!("Pushing rebuilt chain {} ({}) to {addr}.", sub.chain, sub.target);
+				}
+
+				// Fresh chains hold only their initial map entries, so every update
+				// accepted for the chain is replayed onto them. Clients skip
+				// manifests identical to the last, whose maps already hold these.
+				let replay = match &chain {
+					Ok(msg) => !(sub.manifest && last_chain.as_ref() == Some(msg)),
+					Err(_) => false,
+				};
+				subscription = Some(sub);
+
+				let msg = match chain {
+					Ok(msg) => {
+						last_chain = Some(msg.clone());
+						msg
+					},
+					Err(e) => ser(&ServerToClient::RequestChainError(format!(
+						"Could not fetch user chains: {e}"
+					))),
+				};
+
+				if ws_tx.send(msg).await.is_err() {
+					break;
+				}
+
+				if replay {
+					let updates = registration.replay(&current);
+					let sent = replay_map_updates(
+						&mut ws_tx,
+						addr,
+						updates,
+						&mut next_update_id,
+						&mut pending_acks,
+					);
+
+					if sent.await.is_err() {
+						break;
+					}
+				}
+			},
+			changed = chains.changed(), if updates_open => {
+				// Sender is dropped when chainsmith can't rebuild chains.
+				if changed.is_err() {
+					updates_open = false;
+					continue;
+				}
+
+				// Rebuild whichever chain the client most recently requested.
+				let sub = pending_build
+					.as_ref()
+					.map(|pending| &pending.sub)
+					.or(subscription.as_ref())
+					.map(|sub| Subscription {
+						chain: sub.chain.clone(),
+						target: sub.target.clone(),
+						manifest: sub.manifest,
+						build: None,
+					});
+
+				if let Some(sub) = sub {
+					let current = chains.borrow_and_update().clone();
+					pending_build = Some(PendingBuild::spawn(&kernels, btf, current, sub, true));
+				}
+			},
+			Some(update) = map_updates.recv() => {
+				let id = next_update_id;
+				next_update_id += 1;
+
+				let msg = ser(&ServerToClient::MapUpdate { id, update: update.update });
+				pending_acks.insert(id, update.ack);
+
+				if ws_tx.send(msg).await.is_err() {
+					break;
+				}
+			},
+		}
+	}
+}
+
+/// Waits for a client's hello, and replies with the server's own if the client
+/// speaks a compatible protocol version.
+///
+/// If the client can have chains built against its kernel's BTF, and policy
+/// `accepts_btf` from it, this is fetched from the client unless already stored,
+/// and its digest returned alongside the hello. Incompatible clients are sent an
+/// explanation, and `None` is returned.
+async fn handshake<S, R>(
+	ws_tx: &mut S,
+	ws_rx: &mut R,
+	addr: SocketAddr,
+	kernels: &KernelBuilds,
+	accepts_btf: bool,
+) -> Option<(ClientHello, Option<Digest>)>
+where
+	S: Sink<Message> + Unpin,
+	R: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+	let hello = loop {
+		let msg = match ws_rx.next().await {
+			Some(Ok(msg)) => msg,
+			_ => return None,
+		};
+
+		match protocol::deser::<ClientToServer>(&msg) {
+			Ok(Some(ClientToServer::Hello(hello))) =>
+				break check_version(hello.version, hello.min_version).map(|_| hello),
+			Ok(Some(_)) => {
+				// Clients predating the hello exchange open with a chain request.
+				break Err(check_version(0, 0).unwrap_err());
+			},
+			Ok(None) => continue,
+			Err(e) => break Err(format!("could not decode client hello: {e}.")),
+		}
+	};
+
+	match hello {
+		Ok(hello) => {
+			let btf = hello
+				.kernel
+				.btf_digest
+				.filter(|_| accepts_btf && hello.features.contains(Features::KERNEL_BTF));
+			let request_btf = match &btf {
+				Some(digest) => !kernels.has_btf(digest).await,
+				None => false,
+			};
+
+			let reply = ServerToClient::Hello(ServerHello {
+				version: PROTOCOL_VERSION,
+				min_version: MIN_PROTOCOL_VERSION,
+				features: Features::ALL,
+				request_btf,
+			});
+
+			ws_tx.send(ser(&reply)).await.ok()?;
+
+			let btf = match btf {
+				Some(digest) if request_btf => receive_btf(ws_rx, addr, kernels, digest).await?,
+				btf => btf,
+			};
+
+			Some((hello, btf))
+		},
+		Err(e) => {
+			eprintln!("Refusing client {addr}: {e}");
+			let _ = ws_tx.send(ser(&ServerToClient::RequestChainError(e))).await;
+
+			None
+		},
+	}
+}
+
+/// Receives and stores a client's kernel BTF, returning its digest if valid.
+///
+/// Returns `None` if the client disconnects, or `Some(None)` if its upload could
+/// not be used.
+async fn receive_btf<R>(
+	ws_rx: &mut R,
+	addr: SocketAddr,
+	kernels: &KernelBuilds,
+	digest: Digest,
+) -> Option<Option<Digest>>
+where
+	R: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+	let data = loop {
+		let msg = match ws_rx.next().await {
+			Some(Ok(msg)) => msg,
+			_ => return None,
+		};
+
+		match protocol::deser::<ClientToServer>(&msg) {
+			Ok(Some(ClientToServer::KernelBtf(data))) => break data,
+			Ok(None) => continue,
+			_ => {
+				eprintln!("Client {addr} did not upload its BTF.");
+				return Some(None);
+			},
+		}
+	};
+
+	let stored = match protocol::decompress_btf(&digest, &data) {
+		Ok(btf) => kernels
+			.put_btf(&digest, &btf)
+			.await
+			.map_err(anyhow::Error::from),
+		Err(e) => Err(e.into()),
+	};
+
+	match stored {
+		Ok(()) => Some(Some(digest)),
+		Err(e) => {
+			eprintln!("Could not store BTF from client {addr}: {e:?}");
+			Some(None)
+		},
+	}
+}
+
+/// Builds chain `name` for `target` against a client's BTF, if it has one.
+///
+/// Failed builds are logged, and the client is served the target's usual build.
+async fn kernel_build(
+	kernels: &KernelBuilds,
+	btf: Option<&Digest>,
+	chains: &ChainSet,
+	name: &str,
+	target: &str,
+) -> Option<Arc<BuiltChain>> {
+	let digest = btf?;
+	chains.get(name)?.get(target)?;
+
+	match kernels.get(chains, name, target, digest).await {
+		Ok(build) => Some(build),
+		Err(e) => {
+			eprintln!("Failed to build chain {name} ({target}) against client BTF: {e:?}");
+			None
+		},
+	}
+}
+
+/// Streams the compressed payload of the NF with `digest` from `build`, starting
+/// `offset` bytes in.
+async fn send_nf<S>(
+	ws_tx: &mut S,
+	build: Option<&BuiltChain>,
+	digest: &Digest,
+	offset: u64,
+) -> Result<(), S::Error>
+where
+	S: Sink<Message> + Unpin,
+{
+	let payload = match build.and_then(|build| build.payloads.get(digest)) {
+		Some(payload) => payload,
+		None => {
+			let msg = ServerToClient::RequestChainError(
+				"requested NF is not part of the client's current chain.".into(),
+			);
+			return ws_tx.send(ser(&msg)).await;
+		},
+	};
+
+	let total = payload.len() as u64;
+	let start = offset.min(total) as usize;
+
+	for (i, chunk) in payload[start..].chunks(CHUNK_SIZE).enumerate() {
+		let msg = ServerToClient::NfChunk {
+			digest: *digest,
+			offset: (start + i * CHUNK_SIZE) as u64,
+			total,
+			data: chunk.to_vec(),
+		};
+
+		ws_tx.send(ser(&msg)).await?;
+	}
+
+	Ok(())
+}
+
+/// Serialises the chain `name` for `target`, adapted to the client's `kernel`,
+/// if the client supports every protocol feature it uses.
+///
+/// A `build` made specifically for the client is used in place of the one in `chains`.
+fn chain_message(
+	chains: &ChainSet,
+	build: Option<&BuiltChain>,
+	name: &str,
+	target: &str,
+	kernel: &KernelInfo,
+	features: Features,
+	manifest: bool,
+) -> Result<Message, String> {
+	let c_dat = match build {
+		Some(build) => Ok(build),
+		None => chains
+			.get(name)
+			.ok_or_else(|| format!("no chain named {name}."))
+			.and_then(|targets| {
+				targets
+					.get(target)
+					.ok_or_else(|| format!("target {target} unsupported."))
+			}),
+	}
+	.and_then(|c_dat| c_dat.message_for(kernel, features, manifest));
+
+	c_dat.map(|msg| ser(&*msg))
+}
+
+/// Sends the client each of `updates`, replayed from those accepted earlier for
+/// its chain.
+///
+/// Failures are logged, as no operator awaits these acknowledgements.
+async fn replay_map_updates<S>(
+	ws_tx: &mut S,
+	addr: SocketAddr,
+	updates: Vec<MapUpdate>,
+	next_update_id: &mut u64,
+	pending_acks: &mut HashMap<u64, AckSender>,
+) -> Result<(), S::Error>
+where
+	S: Sink<Message> + Unpin,
+{
+	for update in updates {
+		let id = *next_update_id;
+		*next_update_id += 1;
+
+		let (ack, ack_rx) = oneshot::channel();
+		pending_acks.insert(id, ack);
+
+		let map = update.map.clone();
+		tokio::spawn(async move {
+			if let Ok(Err(e)) = ack_rx.await {
+				eprintln!("Client {addr} failed to replay update to map {map}: {e}");
+			}
+		});
+
+		ws_tx
+			.send(ser(&ServerToClient::MapUpdate { id, update }))
+			.await?;
+	}
+
+	Ok(())
+}
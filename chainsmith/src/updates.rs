@@ -0,0 +1,444 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	net::SocketAddr,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+		Mutex,
+	},
+	time::Duration,
+};
+
+use protocol::{ChainProfile, ClientHello, Features, KernelInfo, MapOp, MapUpdate};
+use serde::{Deserialize, Serialize};
+use tokio::{
+	sync::{mpsc, oneshot},
+	time::Instant,
+};
+use toml::Value;
+use uuid::Uuid;
+
+use crate::{
+	entries::{DatumType, RawEntry, RawRow},
+	error::MapUpdateError,
+	placement::NfCosts,
+	ChainSet,
+};
+
+/// An untyped [`MapOp`], as given by an operator.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RawMapOp {
+	Insert(Vec<RawEntry>),
+	Update(Vec<RawEntry>),
+	Delete(Vec<Value>),
+	Replace(Vec<RawEntry>),
+}
+
+impl RawMapOp {
+	fn parse(&self, key_ty: &DatumType, val_ty: &DatumType) -> Result<MapOp, MapUpdateError> {
+		let entries = |entries: &[RawEntry]| {
+			entries
+				.iter()
+				.enumerate()
+				.map(|(index, entry)| {
+					RawRow::Toml(entry.clone())
+						.parse(key_ty, val_ty)
+						.map_err(|reason| MapUpdateError::BadEntry { index, reason })
+				})
+				.collect::<Result<Vec<_>, _>>()
+		};
+
+		Ok(match self {
+			Self::Insert(e) => MapOp::Insert(entries(e)?),
+			Self::Update(e) => MapOp::Update(entries(e)?),
+			Self::Replace(e) => MapOp::Replace(entries(e)?),
+			Self::Delete(keys) => MapOp::Delete(
+				keys.iter()
+					.enumerate()
+					.map(|(index, key)| {
+						key_ty
+							.parse_toml(key)
+							.map_err(|reason| MapUpdateError::BadEntry { index, reason })
+					})
+					.collect::<Result<_, _>>()?,
+			),
+		})
+	}
+}
+
+/// Receives the outcome of a map update on one client.
+pub type AckReceiver = oneshot::Receiver<Result<(), String>>;
+pub(crate) type AckSender = oneshot::Sender<Result<(), String>>;
+
+/// Outcome of a map update on one client.
+#[derive(Clone, Debug)]
+pub struct MapUpdateAck {
+	pub client: SocketAddr,
+	pub result: Result<(), String>,
+}
+
+/// A map update handed to one client's connection.
+pub(crate) struct ClientMapUpdate {
+	pub update: MapUpdate,
+	pub ack: AckSender,
+}
+
+/// A connected client, and the chain it is running.
+#[derive(Clone, Debug, Serialize)]
+pub struct ClientInfo {
+	pub addr: SocketAddr,
+	/// Hex SHA-256 fingerprint of the client's TLS certificate, if it presented one.
+	pub identity: Option<String>,
+	pub chain: Option<String>,
+	pub target: Option<String>,
+	/// Protocol version spoken by the client.
+	pub version: u32,
+	/// Optional protocol features supported by the client.
+	pub features: Vec<&'static str>,
+	/// Kernel the client runs on, and its eBPF support.
+	pub kernel: KernelInfo,
+}
+
+struct ClientEntry {
+	addr: SocketAddr,
+	identity: Option<String>,
+	hello: ClientHello,
+	subscription: Option<(String, String)>,
+	/// IDs of each NF in a chain built specifically for this client.
+	nf_ids: Option<HashMap<String, Uuid>>,
+	/// Costs last reported for each NF in the client's chain.
+	profile: Option<ChainProfile>,
+	tx: mpsc::UnboundedSender<ClientMapUpdate>,
+}
+
+/// Every update accepted for one map, to be replayed onto fresh copies of it.
+struct MapHistory {
+	key_ty: DatumType,
+	val_ty: DatumType,
+	ops: Vec<MapOp>,
+}
+
+impl MapHistory {
+	fn push(&mut self, op: MapOp) {
+		// Replacing a map's contents makes every earlier update moot.
+		if matches!(op, MapOp::Replace(_)) {
+			self.ops.clear();
+		}
+
+		self.ops.push(op);
+	}
+}
+
+/// Map updates accepted for each chain, keyed by NF and map name.
+type ChainHistory = HashMap<String, BTreeMap<(String, String), MapHistory>>;
+
+/// Tracks connected clients, and routes map updates from operators to every
+/// client running the affected chain.
+///
+/// Accepted updates are also kept, so that they can be replayed onto each
+/// chain installed afterwards (see [`ClientRegistration::replay`]).
+#[derive(Clone, Default)]
+pub struct MapUpdateHub {
+	clients: Arc<Mutex<HashMap<u64, ClientEntry>>>,
+	history: Arc<Mutex<ChainHistory>>,
+	next_id: Arc<AtomicU64>,
+}
+
+/// Keeps a client listed in its [`MapUpdateHub`] until dropped.
+pub(crate) struct ClientRegistration {
+	hub: MapUpdateHub,
+	id: u64,
+}
+
+impl ClientRegistration {
+	/// Records which chain (and target) this client is now running.
+	///
+	/// `nf_ids` must be given if the client runs its own build of the chain,
+	/// rather than the one shared by its target.
+	pub fn subscribe(&self, chain: &str, target: &str, nf_ids: Option<HashMap<String, Uuid>>) {
+		if let Some(client) = self.hub.clients.lock().unwrap().get_mut(&self.id) {
+			client.subscription = Some((chain.into(), target.into()));
+			client.nf_ids = nf_ids;
+			client.profile = None;
+		}
+	}
+
+	/// Records the costs this client last measured for its chain.
+	pub fn report_profile(&self, profile: ChainProfile) {
+		if let Some(client) = self.hub.clients.lock().unwrap().get_mut(&self.id) {
+			client.profile = Some(profile);
+		}
+	}
+
+	/// Lists every map update accepted for this client's chain, in order, to be
+	/// replayed once it installs a fresh build holding only initial map entries.
+	///
+	/// Updates to maps which are gone, or whose key or value types have changed
+	/// since, are skipped.
+	pub fn replay(&self, chains: &ChainSet) -> Vec<MapUpdate> {
+		let clients = self.hub.clients.lock().unwrap();
+		let client = match clients.get(&self.id) {
+			Some(client) if client.hello.features.contains(Features::MAP_UPDATES) => client,
+			_ => return vec![],
+		};
+		let (chain, target) = match &client.subscription {
+			Some(subscription) => subscription,
+			None => return vec![],
+		};
+
+		let builds = chains.get(chain);
+		let map_types = builds
+			.and_then(|builds| builds.values().next())
+			.map(|build| &build.map_types);
+		let nf_ids = client
+			.nf_ids
+			.as_ref()
+			.or_else(|| builds?.get(target).map(|b| &b.name_to_uuid));
+
+		let kept = self.hub.history.lock().unwrap();
+		let mut out = vec![];
+
+		for ((nf, map), history) in kept.get(chain).into_iter().flatten() {
+			let same_types = map_types
+				.and_then(|types| types.get(nf)?.get(map))
+				.map(|(key_ty, val_ty)| *key_ty == history.key_ty && *val_ty == history.val_ty)
+				.unwrap_or_default();
+			let uuid = match nf_ids.and_then(|ids| ids.get(nf)) {
+				Some(uuid) if same_types => *uuid,
+				_ => continue,
+			};
+
+			out.extend(history.ops.iter().map(|op| MapUpdate {
+				nf: uuid,
+				map: map.clone(),
+				op: op.clone(),
+			}));
+		}
+
+		out
+	}
+}
+
+impl Drop for ClientRegistration {
+	fn drop(&mut self) {
+		self.hub.clients.lock().unwrap().remove(&self.id);
+	}
+}
+
+impl MapUpdateHub {
+	pub(crate) fn register(
+		&self,
+		addr: SocketAddr,
+		identity: Option<String>,
+		hello: ClientHello,
+	) -> (ClientRegistration, mpsc::UnboundedReceiver<ClientMapUpdate>) {
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		let (tx, rx) = mpsc::unbounded_channel();
+
+		self.clients.lock().unwrap().insert(
+			id,
+			ClientEntry {
+				addr,
+				identity,
+				hello,
+				subscription: None,
+				nf_ids: None,
+				profile: None,
+				tx,
+			},
+		);
+
+		(
+			ClientRegistration {
+				hub: self.clone(),
+				id,
+			},
+			rx,
+		)
+	}
+
+	/// Lists all connected clients.
+	pub fn clients(&self) -> Vec<ClientInfo> {
+		let mut out: Vec<_> = self
+			.clients
+			.lock()
+			.unwrap()
+			.values()
+			.map(|client| {
+				let (chain, target) = client.subscription.clone().unzip();
+
+				ClientInfo {
+					addr: client.addr,
+					identity: client.identity.clone(),
+					chain,
+					target,
+					version: client.hello.version,
+					features: client.hello.features.names(),
+					kernel: client.hello.kernel.clone(),
+				}
+			})
+			.collect();
+
+		out.sort_by_key(|client| client.addr);
+
+		out
+	}
+
+	/// Combines the costs last reported by every client running `chain`, by NF name.
+	pub fn costs(&self, chains: &ChainSet, chain: &str) -> HashMap<String, NfCosts> {
+		let builds = chains.get(chain);
+		let mut out: HashMap<String, NfCosts> = HashMap::new();
+
+		for client in self.clients.lock().unwrap().values() {
+			let (target, profile) = match (&client.subscription, &client.profile) {
+				(Some((c_chain, target)), Some(profile)) if c_chain == chain => (target, profile),
+				_ => continue,
+			};
+
+			let nf_ids = client
+				.nf_ids
+				.as_ref()
+				.or_else(|| builds?.get(target).map(|b| &b.name_to_uuid));
+			let names: HashMap<&Uuid, &String> = nf_ids
+				.into_iter()
+				.flatten()
+				.map(|(name, uuid)| (uuid, name))
+				.collect();
+
+			for nf in &profile.nfs {
+				if let Some(name) = names.get(&nf.nf) {
+					out.entry((*name).clone()).or_default().add(nf);
+				}
+			}
+		}
+
+		out
+	}
+
+	/// Type-checks `op` against the declared types of `map`, then sends it to
+	/// every client running `chain`.
+	///
+	/// `op` is kept for replay onto builds of `chain` installed later, whether on
+	/// these clients or others which have yet to connect.
+	///
+	/// Returns a pending acknowledgement for each client the update was sent to.
+	pub fn submit(
+		&self,
+		chains: &ChainSet,
+		chain: &str,
+		nf: &str,
+		map: &str,
+		op: &RawMapOp,
+	) -> Result<Vec<(SocketAddr, AckReceiver)>, MapUpdateError> {
+		let builds = chains
+			.get(chain)
+			.ok_or_else(|| MapUpdateError::UnknownChain(chain.into()))?;
+
+		// Map types come from NF sources, so are identical for all targets.
+		let (key_ty, val_ty) = builds
+			.values()
+			.next()
+			.and_then(|build| build.map_types.get(nf))
+			.and_then(|maps| maps.get(map))
+			.ok_or_else(|| MapUpdateError::UnknownMap {
+				chain: chain.into(),
+				nf: nf.into(),
+				map: map.into(),
+			})?;
+
+		let op = op.parse(key_ty, val_ty)?;
+
+		{
+			let mut history = self.history.lock().unwrap();
+			let history = history
+				.entry(chain.into())
+				.or_default()
+				.entry((nf.into(), map.into()))
+				.or_insert_with(|| MapHistory {
+					key_ty: key_ty.clone(),
+					val_ty: val_ty.clone(),
+					ops: vec![],
+				});
+
+			// Earlier updates to a map whose types have changed can no longer apply.
+			if history.key_ty != *key_ty || history.val_ty != *val_ty {
+				history.key_ty = key_ty.clone();
+				history.val_ty = val_ty.clone();
+				history.ops.clear();
+			}
+			history.push(op.clone());
+		}
+
+		let mut out = vec![];
+
+		for client in self.clients.lock().unwrap().values() {
+			let target = match &client.subscription {
+				Some((c_chain, target)) if c_chain == chain => target,
+				_ => continue,
+			};
+
+			let nf_ids = client
+				.nf_ids
+				.as_ref()
+				.or_else(|| builds.get(target).map(|b| &b.name_to_uuid));
+
+			let uuid = match nf_ids.and_then(|ids| ids.get(nf)) {
+				Some(uuid) => *uuid,
+				None => continue,
+			};
+
+			let (ack, ack_rx) = oneshot::channel();
+
+			if !client.hello.features.contains(Features::MAP_UPDATES) {
+				let _ = ack.send(Err("client does not support map updates".into()));
+				out.push((client.addr, ack_rx));
+				continue;
+			}
+
+			let update = ClientMapUpdate {
+				update: MapUpdate {
+					nf: uuid,
+					map: map.into(),
+					op: op.clone(),
+				},
+				ack,
+			};
+
+			if client.tx.send(update).is_ok() {
+				out.push((client.addr, ack_rx));
+			}
+		}
+
+		Ok(out)
+	}
+
+	/// Submits a map update, and waits up to `timeout` for every client to
+	/// acknowledge it.
+	pub async fn apply(
+		&self,
+		chains: &ChainSet,
+		chain: &str,
+		nf: &str,
+		map: &str,
+		op: &RawMapOp,
+		timeout: Duration,
+	) -> Result<Vec<MapUpdateAck>, MapUpdateError> {
+		let pending = self.submit(chains, chain, nf, map, op)?;
+		let deadline = Instant::now() + timeout;
+
+		let mut out = Vec::with_capacity(pending.len());
+
+		for (client, ack_rx) in pending {
+			let result = match tokio::time::timeout_at(deadline, ack_rx).await {
+				Ok(Ok(result)) => result,
+				Ok(Err(_)) => Err("client disconnected".into()),
+				Err(_) => Err("timed out".into()),
+			};
+
+			out.push(MapUpdateAck { client, result });
+		}
+
+		Ok(out)
+	}
+}
@@ -0,0 +1,192 @@
+use std::{
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Serialize;
+use tokio::{
+	fs,
+	sync::{mpsc, watch},
+};
+
+use crate::{cache::IGNORED_DIRS, chain::Chain, ChainBuilder, ChainDirs, ChainSet};
+
+/// Time to wait for further changes (e.g., editor save bursts) before rebuilding.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Outcome of chainsmith's most recent chain builds.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BuildStatus {
+	/// Whether a rebuild is currently running.
+	pub building: bool,
+	/// Number of builds attempted, including the initial build.
+	pub attempts: u64,
+	/// Completion time of the last successful build, in seconds since the UNIX epoch.
+	pub last_success: Option<u64>,
+	/// Completion time of the last failed build, in seconds since the UNIX epoch.
+	pub last_failure: Option<u64>,
+	/// Error from the most recent build, if it failed.
+	pub error: Option<String>,
+}
+
+impl BuildStatus {
+	fn record(&mut self, result: Result<(), String>) {
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|d| d.as_secs())
+			.ok();
+
+		self.building = false;
+		self.attempts += 1;
+
+		match result {
+			Ok(()) => {
+				self.last_success = now;
+				self.error = None;
+			},
+			Err(e) => {
+				self.last_failure = now;
+				self.error = Some(e);
+			},
+		}
+	}
+
+	/// Status after chainsmith's initial build.
+	pub fn initial() -> Self {
+		let mut out = Self::default();
+		out.record(Ok(()));
+		out
+	}
+}
+
+/// Rebuilds all chains on each request received over `requests`, and, if `watch`
+/// is set, whenever any `chain.toml` or NF crate changes. Each successful build
+/// is published to `chains`.
+///
+/// Failed builds are logged and recorded in `status`, and the last good chain
+/// remains published.
+pub async fn rebuild_chains(
+	builder: Arc<ChainBuilder>,
+	chains: watch::Sender<ChainSet>,
+	status: Arc<Mutex<BuildStatus>>,
+	mut requests: mpsc::UnboundedReceiver<()>,
+	watch: bool,
+) -> anyhow::Result<()> {
+	let roots = Arc::new(Mutex::new(vec![]));
+
+	let (ev_tx, mut ev_rx) = mpsc::unbounded_channel();
+	let mut watcher = if watch {
+		let ev_roots = roots.clone();
+		let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+			let roots = ev_roots.lock().unwrap();
+
+			if let Ok(ev) = res {
+				if ev.paths.iter().any(|p| !is_build_output(p, &roots)) {
+					let _ = ev_tx.send(());
+				}
+			}
+		})?;
+
+		rewatch(&mut watcher, &roots, &builder.chain_dirs).await?;
+		println!(
+			"Watching {} chain(s) for changes.",
+			builder.chain_dirs.len()
+		);
+
+		Some(watcher)
+	} else {
+		drop(ev_tx);
+		None
+	};
+
+	loop {
+		tokio::select! {
+			Some(()) = ev_rx.recv() => {
+				tokio::time::sleep(DEBOUNCE).await;
+				println!("Change detected, rebuilding chains...");
+			},
+			Some(()) = requests.recv() => println!("Rebuild requested, rebuilding chains..."),
+			else => break,
+		}
+
+		while ev_rx.try_recv().is_ok() {}
+		while requests.try_recv().is_ok() {}
+
+		status.lock().unwrap().building = true;
+
+		let result = match builder.build_all().await {
+			Ok(new_chains) => {
+				chains.send_replace(new_chains);
+				println!("Rebuilt chains.");
+				Ok(())
+			},
+			Err(e) => {
+				eprintln!("Rebuild failed, still serving previous chains: {e:?}");
+				Err(format!("{e:#}"))
+			},
+		};
+
+		status.lock().unwrap().record(result);
+
+		// NFs may have been added, removed, or moved.
+		if let Some(watcher) = watcher.as_mut() {
+			if let Err(e) = rewatch(watcher, &roots, &builder.chain_dirs).await {
+				eprintln!("Failed to update watched NF crates: {e:?}");
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// Watches each chain directory and all NF crates they currently reference.
+async fn rewatch(
+	watcher: &mut RecommendedWatcher,
+	roots: &Mutex<Vec<PathBuf>>,
+	chain_dirs: &ChainDirs,
+) -> anyhow::Result<()> {
+	let mut wanted = vec![];
+	for (_, chain_dir) in chain_dirs {
+		let chain = Chain::load(chain_dir).await?;
+
+		wanted.push(chain_dir.clone());
+		for crate_dir in chain.crate_dirs(chain_dir) {
+			let crate_dir = fs::canonicalize(crate_dir).await?;
+			if !wanted.contains(&crate_dir) {
+				wanted.push(crate_dir);
+			}
+		}
+	}
+
+	// The event handler also takes this lock, so it must not be held while
+	// (un)registering watches.
+	let current = roots.lock().unwrap().clone();
+
+	for old in current.iter().filter(|old| !wanted.contains(old)) {
+		let _ = watcher.unwatch(old);
+	}
+
+	for new in wanted.iter().filter(|new| !current.contains(new)) {
+		watcher.watch(new, RecursiveMode::Recursive)?;
+	}
+
+	*roots.lock().unwrap() = wanted;
+
+	Ok(())
+}
+
+/// Checks whether `path` lies in a build or cache directory below any watched
+/// root, so that chainsmith's own output does not trigger rebuilds.
+fn is_build_output(path: &Path, roots: &[PathBuf]) -> bool {
+	roots
+		.iter()
+		.filter_map(|root| path.strip_prefix(root).ok())
+		.any(|rel_path| {
+			rel_path.components().any(|c| {
+				let c = c.as_os_str().to_string_lossy();
+				c.starts_with('.') || IGNORED_DIRS.contains(&c.as_ref())
+			})
+		})
+}
@@ -4,6 +4,7 @@ pub use nf_macros::*;
 
 pub mod example_map;
 pub mod map;
+pub mod meta;
 pub mod packet;
 pub mod random;
 
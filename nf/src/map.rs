@@ -1,6 +1,8 @@
 #[cfg(feature = "libbpf-rs")]
 use core::{ffi::c_void, mem::MaybeUninit};
 
+#[cfg(feature = "aya-ebpf")]
+use aya_ebpf::maps::{Array as AyaArray, HashMap as AyaHashMap};
 #[cfg(feature = "libbpf-rs")]
 use libbpf_rs::{libbpf_sys, Map as HostMap, MapFlags};
 #[cfg(feature = "redbpf-probes")]
@@ -135,3 +137,31 @@ impl<K, V> Map<K, V> for &mut HashMap<K, V> {
 		self.set(key, value)
 	}
 }
+
+#[cfg(feature = "aya-ebpf")]
+impl<V> Map<u32, V> for &AyaArray<V> {
+	#[inline]
+	fn get(&mut self, key: &u32) -> Option<V> {
+		AyaArray::get_ptr(*self, *key).map(|val| unsafe { core::ptr::read(val) })
+	}
+
+	#[inline]
+	fn put(&mut self, key: &u32, value: &V) {
+		if let Some(slot) = AyaArray::get_ptr_mut(*self, *key) {
+			unsafe { core::ptr::copy_nonoverlapping(value, slot, 1) }
+		}
+	}
+}
+
+#[cfg(feature = "aya-ebpf")]
+impl<K, V> Map<K, V> for &AyaHashMap<K, V> {
+	#[inline]
+	fn get(&mut self, key: &K) -> Option<V> {
+		AyaHashMap::get_ptr(*self, key).map(|val| unsafe { core::ptr::read(val) })
+	}
+
+	#[inline]
+	fn put(&mut self, key: &K, value: &V) {
+		let _ = AyaHashMap::insert(*self, key, value, 0);
+	}
+}
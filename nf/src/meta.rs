@@ -0,0 +1,54 @@
+//! A small scratch area carried alongside each packet, which NFs can use to
+//! pass results (e.g., a classification or parsed header offsets) on to
+//! later NFs in the chain.
+//!
+//! In eBPF, this lives in XDP's `data_meta` region, which is reserved and zeroed
+//! before the first NF runs. Upcalled packets carry it into userland, after the
+//! program ID and action which precede it in the AF_XDP headroom.
+//!
+//! Metadata is unavailable (i.e., reads and writes return `None`) on tc hooks,
+//! or if a driver does not support XDP metadata.
+
+use core::mem;
+
+use crate::Packet;
+
+/// Bytes of metadata available to each packet.
+///
+/// XDP allows at most 32B of metadata, 8B of which are used by upcalls.
+pub const META_LEN: usize = 16;
+
+/// Types which can be stored in packet metadata.
+///
+/// # Safety
+/// Implementors must be `#[repr(C)]` (or primitive), no larger than [`META_LEN`],
+/// and valid for any bit pattern (including all zeroes, which every packet's
+/// metadata starts as).
+pub unsafe trait Meta: Copy {}
+
+macro_rules! impl_meta {
+	($($t:ty),*) => {
+		$(unsafe impl Meta for $t {})*
+	};
+}
+
+impl_meta!(u8, u16, u32, u64, i8, i16, i32, i64, [u8; 2], [u8; 4], [u8; 8], [u8; 16]);
+
+/// Reads a `T` from the start of `pkt`'s metadata.
+#[inline]
+pub fn read<T: Meta>(pkt: &mut impl Packet) -> Option<T> {
+	let bytes = pkt.meta()?.get(..mem::size_of::<T>())?;
+
+	Some(unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// Writes `val` to the start of `pkt`'s metadata, returning `None` if
+/// metadata is unavailable.
+#[inline]
+pub fn write<T: Meta>(pkt: &mut impl Packet, val: T) -> Option<()> {
+	let bytes = pkt.meta()?.get_mut(..mem::size_of::<T>())?;
+
+	unsafe { core::ptr::write_unaligned(bytes.as_mut_ptr() as *mut T, val) };
+
+	Some(())
+}
@@ -9,14 +9,19 @@
 // just do `get` and `extend` for now.
 // actually, don't need extend: drop get(n) -> stuff -> get(n+m) drops handle and &mut
 
-#[cfg(feature = "redbpf-probes")]
+#[cfg(any(feature = "redbpf-probes", feature = "aya-ebpf"))]
 use core::slice;
 
+#[cfg(feature = "aya-ebpf")]
+use aya_ebpf::programs::{TcContext as AyaTcContext, XdpContext as AyaXdpContext};
 #[cfg(feature = "redbpf-probes")]
 use redbpf_probes::{net::NetworkBuffer, xdp::XdpContext};
 
+#[cfg(any(feature = "redbpf-probes", feature = "aya-ebpf"))]
+use crate::meta::META_LEN;
+
 mod private {
-	#[cfg(feature = "redbpf-probes")]
+	#[cfg(any(feature = "redbpf-probes", feature = "aya-ebpf"))]
 	use super::*;
 
 	pub trait Sealed {}
@@ -24,7 +29,16 @@ mod private {
 	#[cfg(feature = "redbpf-probes")]
 	impl Sealed for &XdpContext {}
 
+	#[cfg(feature = "aya-ebpf")]
+	impl Sealed for &AyaXdpContext {}
+	#[cfg(feature = "aya-ebpf")]
+	impl Sealed for &AyaTcContext {}
+
 	impl Sealed for &mut [u8] {}
+
+	impl<P: super::Packet> Sealed for super::Limited<P> {}
+
+	impl Sealed for super::UserPacket<'_> {}
 }
 
 /// A consistent packet access API for userland and XDP-offloaded NFs.
@@ -50,6 +64,8 @@ pub trait Packet: private::Sealed {
 	fn slice_from(&mut self, offset: usize, len: usize) -> Option<&mut [u8]>;
 	/// Returns the number of bytes accessible to the current NF.
 	fn len(&self) -> usize;
+	/// Returns the packet's [metadata](crate::meta) area, if available.
+	fn meta(&mut self) -> Option<&mut [u8]>;
 }
 
 #[cfg(feature = "redbpf-probes")]
@@ -61,8 +77,8 @@ impl Packet for &XdpContext {
 
 	#[inline]
 	fn slice_from(&mut self, offset: usize, len: usize) -> Option<&mut [u8]> {
-		let base = self.data_start() + offset;
-		(*self).check_bounds(base, base + len).ok()?;
+		let base = self.data_start().checked_add(offset)?;
+		(*self).check_bounds(base, base.checked_add(len)?).ok()?;
 
 		let ptr = base as *mut u8;
 
@@ -73,8 +89,102 @@ impl Packet for &XdpContext {
 	fn len(&self) -> usize {
 		NetworkBuffer::len(*self)
 	}
+	#[inline]
+	fn meta(&mut self) -> Option<&mut [u8]> {
+		let (start, end) = unsafe { ((*self.ctx).data_meta as usize, (*self.ctx).data as usize) };
+		if start + META_LEN > end {
+			return None;
+		}
+
+		Some(unsafe { slice::from_raw_parts_mut(start as *mut u8, META_LEN) })
+	}
 }
 
+/// Implements [`Packet`] for an aya context exposing `data()` and `data_end()`.
+///
+/// `pull` runs before each bounds check, given the context and the offset just
+/// past the requested slice, to make those bytes directly accessible. `meta`
+/// returns the context's metadata area.
+#[cfg(feature = "aya-ebpf")]
+macro_rules! impl_aya_packet {
+	(
+		$(#[$attr:meta])*
+		$ctx_ty:ty,
+		len: |$len_ctx:ident| $len:expr,
+		pull: |$pull_ctx:ident, $end:ident| $pull:expr,
+		meta: |$meta_ctx:ident| $meta:expr $(,)?
+	) => {
+		$(#[$attr])*
+		impl Packet for &$ctx_ty {
+			#[inline]
+			fn slice(&mut self, len: usize) -> Option<&mut [u8]> {
+				self.slice_from(0, len)
+			}
+
+			#[inline]
+			fn slice_from(&mut self, offset: usize, len: usize) -> Option<&mut [u8]> {
+				{
+					let ($pull_ctx, $end) = (*self, offset.checked_add(len)?);
+					$pull;
+				}
+
+				// Pulling may move the packet, so its bounds are only read now.
+				let base = self.data().checked_add(offset)?;
+				if base.checked_add(len)? > self.data_end() {
+					return None;
+				}
+
+				let ptr = base as *mut u8;
+
+				Some(unsafe { slice::from_raw_parts_mut(ptr, len) })
+			}
+
+			#[inline]
+			fn len(&self) -> usize {
+				let $len_ctx = *self;
+				$len
+			}
+
+			#[inline]
+			fn meta(&mut self) -> Option<&mut [u8]> {
+				let $meta_ctx = *self;
+				$meta
+			}
+		}
+	};
+}
+
+#[cfg(feature = "aya-ebpf")]
+impl_aya_packet!(
+	AyaXdpContext,
+	len: |ctx| ctx.data_end() - ctx.data(),
+	pull: |_ctx, _end| {},
+	meta: |ctx| {
+		let start = ctx.metadata();
+		if start + META_LEN > ctx.data() {
+			return None;
+		}
+
+		Some(unsafe { slice::from_raw_parts_mut(start as *mut u8, META_LEN) })
+	},
+);
+
+#[cfg(feature = "aya-ebpf")]
+impl_aya_packet!(
+	/// Only the linear part of an `__sk_buff` is directly accessible, so slices
+	/// past it are first pulled in via `bpf_skb_pull_data`. Packet metadata is
+	/// not available at tc hooks.
+	AyaTcContext,
+	len: |ctx| ctx.len() as usize,
+	// Failures (e.g., slices past the packet's end) are left to the bounds check.
+	pull: |ctx, end| {
+		if end > ctx.data_end() - ctx.data() {
+			let _ = ctx.pull_data(end as u32);
+		}
+	},
+	meta: |_ctx| None,
+);
+
 impl Packet for &mut [u8] {
 	#[inline]
 	fn slice(&mut self, len: usize) -> Option<&mut [u8]> {
@@ -83,11 +193,199 @@ impl Packet for &mut [u8] {
 
 	#[inline]
 	fn slice_from(&mut self, offset: usize, len: usize) -> Option<&mut [u8]> {
-		self.get_mut(offset..(offset + len))
+		self.get_mut(offset..offset.checked_add(len)?)
 	}
 
 	#[inline]
 	fn len(&self) -> usize {
 		<[u8]>::len(self)
 	}
+	#[inline]
+	fn meta(&mut self) -> Option<&mut [u8]> {
+		None
+	}
+}
+
+/// A view of a packet which hides all bytes past the first `limit`.
+///
+/// Used to enforce an NF's `slice` from its chain config.
+pub struct Limited<P> {
+	pkt: P,
+	limit: usize,
+}
+
+impl<P: Packet> Limited<P> {
+	#[inline]
+	pub fn new(pkt: P, limit: usize) -> Self {
+		Self { pkt, limit }
+	}
+}
+
+impl<P: Packet> Packet for Limited<P> {
+	#[inline]
+	fn slice_from(&mut self, offset: usize, len: usize) -> Option<&mut [u8]> {
+		match offset.checked_add(len) {
+			Some(end) if end <= self.limit => self.pkt.slice_from(offset, len),
+			_ => None,
+		}
+	}
+
+	#[inline]
+	fn len(&self) -> usize {
+		self.pkt.len().min(self.limit)
+	}
+
+	#[inline]
+	fn meta(&mut self) -> Option<&mut [u8]> {
+		self.pkt.meta()
+	}
+}
+
+/// A packet received in userland, alongside its [metadata](crate::meta) area.
+pub struct UserPacket<'a> {
+	pkt: &'a mut [u8],
+	meta: &'a mut [u8],
+}
+
+impl<'a> UserPacket<'a> {
+	#[inline]
+	pub fn new(pkt: &'a mut [u8], meta: &'a mut [u8]) -> Self {
+		Self { pkt, meta }
+	}
+}
+
+impl Packet for UserPacket<'_> {
+	#[inline]
+	fn slice_from(&mut self, offset: usize, len: usize) -> Option<&mut [u8]> {
+		self.pkt.get_mut(offset..offset.checked_add(len)?)
+	}
+
+	#[inline]
+	fn len(&self) -> usize {
+		self.pkt.len()
+	}
+
+	#[inline]
+	fn meta(&mut self) -> Option<&mut [u8]> {
+		Some(self.meta)
+	}
+}
+
+/// Hashes the addresses, protocol, and (TCP/UDP/SCTP) ports of an IPv4 or
+/// IPv6 packet behind an Ethernet header, such that every packet of a flow
+/// has the same hash.
+///
+/// Other packets hash to 0. IPv4 fragments are hashed without their ports, as
+/// only the first fragment holds them.
+#[inline(always)]
+pub fn flow_hash(pkt: &mut impl Packet) -> u32 {
+	const ETH_LEN: usize = 14;
+
+	let ethertype = match pkt.slice_from(12, 2) {
+		Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+		None => return 0,
+	};
+
+	let (mut hash, proto, l4_offset) = match ethertype {
+		0x0800 => {
+			let ip = match pkt.slice_from(ETH_LEN, 20) {
+				Some(ip) => ip,
+				None => return 0,
+			};
+
+			let ihl = ((ip[0] & 0xf) as usize) * 4;
+			let is_frag = ip[6] & 0x3f != 0 || ip[7] != 0;
+			let hash = mix(mix(0, word(ip, 12)), word(ip, 16));
+
+			(hash, if is_frag { 0 } else { ip[9] }, ETH_LEN + ihl)
+		},
+		0x86dd => {
+			let ip = match pkt.slice_from(ETH_LEN, 40) {
+				Some(ip) => ip,
+				None => return 0,
+			};
+
+			let mut hash = 0;
+			for i in 0..8 {
+				hash = mix(hash, word(ip, 8 + 4 * i));
+			}
+
+			(hash, ip[6], ETH_LEN + 40)
+		},
+		_ => return 0,
+	};
+
+	hash = mix(hash, proto as u32);
+
+	if matches!(proto, 6 | 17 | 132) {
+		if let Some(ports) = pkt.slice_from(l4_offset, 4) {
+			hash = mix(hash, word(ports, 0));
+		}
+	}
+
+	hash
+}
+
+#[inline(always)]
+fn word(bytes: &[u8], offset: usize) -> u32 {
+	u32::from_ne_bytes([
+		bytes[offset],
+		bytes[offset + 1],
+		bytes[offset + 2],
+		bytes[offset + 3],
+	])
+}
+
+#[inline(always)]
+fn mix(hash: u32, val: u32) -> u32 {
+	let hash = (hash ^ val).wrapping_mul(0x9e37_79b1);
+	hash ^ (hash >> 16)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn limited_hides_bytes_past_limit() {
+		let mut buf = [0u8; 64];
+		let mut pkt = Limited::new(&mut buf[..], 16);
+
+		assert_eq!(pkt.len(), 16);
+		assert_eq!(pkt.slice(16).map(|s| s.len()), Some(16));
+		assert!(pkt.slice(17).is_none());
+		assert_eq!(pkt.slice_from(8, 8).map(|s| s.len()), Some(8));
+		assert!(pkt.slice_from(8, 9).is_none());
+		assert!(pkt.slice_from(16, 1).is_none());
+	}
+
+	#[test]
+	fn limited_rejects_overflowing_ranges() {
+		let mut buf = [0u8; 64];
+		let mut pkt = Limited::new(&mut buf[..], 16);
+
+		assert!(pkt.slice_from(usize::MAX, 2).is_none());
+		assert!(pkt.slice_from(1, usize::MAX).is_none());
+		assert!(pkt.slice_from(usize::MAX, usize::MAX).is_none());
+	}
+
+	#[test]
+	fn limited_is_bounded_by_packet() {
+		let mut buf = [0u8; 8];
+		let mut pkt = Limited::new(&mut buf[..], 16);
+
+		assert_eq!(pkt.len(), 8);
+		assert!(pkt.slice(9).is_none());
+	}
+
+	#[test]
+	fn slices_reject_overflowing_ranges() {
+		let mut buf = [0u8; 64];
+		let mut meta = [0u8; 8];
+
+		assert!((&mut buf[..]).slice_from(usize::MAX, 2).is_none());
+		assert!(UserPacket::new(&mut buf, &mut meta)
+			.slice_from(2, usize::MAX)
+			.is_none());
+	}
 }
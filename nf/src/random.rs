@@ -11,6 +11,12 @@ pub fn random_u32() -> u32 {
 	redbpf_probes::helpers::bpf_get_prandom_u32()
 }
 
+#[cfg(feature = "aya")]
+#[inline]
+pub fn random_u32() -> u32 {
+	unsafe { aya_ebpf::helpers::bpf_get_prandom_u32() }
+}
+
 #[cfg(feature = "user")]
 #[inline]
 pub fn random_u32() -> u32 {
@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+
+use super::*;
+
+/// A chain built for one target, stored for installation without a chainsmith
+/// server.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Bundle {
+	/// Name of the chain, as served by chainsmith.
+	pub name: String,
+	/// Rust target tuple the chain's userland NFs were built for.
+	pub target: String,
+	pub chain: Chain,
+}
+
+impl Bundle {
+	pub fn to_bytes(&self) -> Vec<u8> {
+		postcard::to_stdvec(self).expect("All local types should be postcard-friendly.")
+	}
+
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self, DeserError> {
+		postcard::from_bytes(bytes).map_err(DeserError::Fail)
+	}
+}
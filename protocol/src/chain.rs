@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -11,7 +11,52 @@ pub struct XdpLink {
 	pub state: XdpLinkState,
 	pub root: bool,
 	pub disable_xdp: bool,
+	/// NFs inlined into this NF's eBPF program, in the order they run.
+	///
+	/// Their own programs are not loaded, and the last decides the actions taken
+	/// by the fused program. Their maps are defined in this NF's program, named
+	/// with [`fused_map_prefix`].
+	pub fused: Vec<Uuid>,
+	/// If non-empty, packets leaving this NF take each branch of `state` at
+	/// random, in proportion to its weight here, rather than following the NF's
+	/// output.
+	///
+	/// Weights can be changed at runtime as though they were the entries of an
+	/// NF map named [`SPLIT_WEIGHTS_MAP`].
+	pub weights: Vec<u32>,
+	/// NF sent a copy of some packets leaving this NF, without affecting their path.
+	pub mirror: Option<Mirror>,
 	pub map_names: Vec<String>,
+	/// Initial contents of this NF's maps, keyed by map name.
+	pub map_entries: BTreeMap<String, Vec<MapEntry>>,
+}
+
+/// Name under which updates to an NF's split [weights](XdpLink::weights) are
+/// addressed, keyed by branch index.
+pub const SPLIT_WEIGHTS_MAP: &str = "split_weights";
+
+/// An observer NF run in userland on copies of packets leaving another NF.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Mirror {
+	pub nf: Uuid,
+	/// Chance that each packet is mirrored, as a fraction of `u32::MAX`.
+	pub sample: u32,
+}
+
+/// Name of the perf event array through which an NF's eBPF program sends
+/// copies of packets sampled by its [`Mirror`] to userland.
+///
+/// Each record holds the packet's length (as a native-endian `u32`) and its
+/// `nf::meta` area, followed by the packet itself.
+pub const MIRROR_MAP: &str = "mirror_map";
+
+/// Prefix of the map statics of the NF at `position` in a fused eBPF program,
+/// counting the NF which owns the program as position 0.
+pub fn fused_map_prefix(position: usize) -> String {
+	match position {
+		0 => String::new(),
+		n => format!("F{n}_"),
+	}
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -59,8 +104,72 @@ impl LinkAction {
 	}
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Point in the network stack at which a chain's root NF is attached.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Hook {
+	/// The XDP hook of the interface's driver.
+	#[default]
+	Xdp,
+	/// A `cls_bpf` filter on ingress of the interface's `clsact` qdisc.
+	TcIngress,
+	/// A `cls_bpf` filter on egress of the interface's `clsact` qdisc.
+	TcEgress,
+}
+
+impl Hook {
+	/// Returns whether this hook runs `__sk_buff`-based programs.
+	pub fn is_tc(&self) -> bool {
+		matches!(self, Self::TcIngress | Self::TcEgress)
+	}
+}
+
+/// A userland build of a whole chain, which links every NF statically.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UserlandChain {
+	/// ID of the entry in [`Chain::nfs`] holding the library.
+	///
+	/// This exports `user_chain_program` in place of each NF's `user_nf_program`.
+	pub program: Uuid,
+	/// NFs in the order the library numbers them.
+	pub nfs: Vec<Uuid>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Chain {
+	pub hook: Hook,
 	pub links: Vec<XdpLink>,
+	/// Replaces the userland builds of individual NFs, if present.
+	pub userland: Option<UserlandChain>,
 	pub nfs: HashMap<Uuid, Function>,
+	/// Ed25519 signature by the server which built this chain (see [`Chain::sign`]).
+	pub signature: Option<Vec<u8>>,
+}
+
+impl Chain {
+	/// Optional protocol features a client must support to install this chain.
+	pub fn required_features(&self) -> Features {
+		let mut features = Features::empty();
+
+		if self.hook.is_tc() {
+			features = features | Features::TC_HOOKS;
+		}
+		if self.userland.is_some() {
+			features = features | Features::USERLAND_CHAIN;
+		}
+
+		for link in &self.links {
+			if !link.fused.is_empty() {
+				features = features | Features::FUSED_NFS;
+			}
+			if !link.weights.is_empty() {
+				features = features | Features::SPLIT_LINKS;
+			}
+			if link.mirror.is_some() {
+				features = features | Features::MIRROR_LINKS;
+			}
+		}
+
+		features
+	}
 }
@@ -1,5 +1,8 @@
+use std::io::Error as IoError;
+
 use postcard::Error as PostcardError;
 use thiserror::Error;
+use uuid::Uuid;
 
 #[derive(Debug, Error)]
 pub enum DeserError {
@@ -8,3 +11,29 @@ pub enum DeserError {
 	#[error("failed to deserialise  message into needed type")]
 	Fail(#[source] PostcardError),
 }
+
+#[derive(Debug, Error)]
+pub enum SignatureError {
+	#[error("chain is not signed")]
+	Unsigned,
+	#[error("chain signature does not match the trusted key")]
+	Invalid,
+	#[error("payload of NF {0} does not match its signed digest")]
+	DigestMismatch(Uuid),
+}
+
+#[derive(Debug, Error)]
+pub enum TransferError {
+	#[error("no payload was fetched for NF {0}")]
+	MissingNf(Uuid),
+	#[error("failed to decompress payload")]
+	Decompress(#[source] IoError),
+	#[error("failed to deserialise NF payload")]
+	Deserialize(#[source] PostcardError),
+	#[error("payload of NF {0} does not match its digest")]
+	DigestMismatch(Uuid),
+	#[error("uploaded BTF does not match its digest")]
+	BtfMismatch,
+	#[error("uploaded BTF is larger than {} bytes", crate::MAX_BTF_SIZE)]
+	BtfTooLarge,
+}
@@ -1,14 +1,75 @@
+use ring::digest::{Context, SHA256, SHA256_OUTPUT_LEN};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Debug, Deserialize, Serialize)]
+/// SHA-256 digest over the compiled payloads of a [`Function`].
+pub type Digest = [u8; SHA256_OUTPUT_LEN];
+
+/// Namespace used when deriving NF UUIDs from their [`Digest`].
+pub const NF_NAMESPACE: Uuid = Uuid::from_u128(0xc0f5c3e0_7976_4912_a044_a062a1a5365d);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Function {
 	pub uuid: Uuid,
+	pub digest: Digest,
 	pub elf: Option<Vec<u8>>,
 	pub ebpf: Option<EbpfFunction>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Function {
+	/// Builds a function whose ID is derived from its compiled artifacts and
+	/// its position (`name`) in the chain.
+	///
+	/// Identical sources and build settings yield identical IDs across builds.
+	pub fn new(name: &str, elf: Option<Vec<u8>>, ebpf: Option<EbpfFunction>) -> Self {
+		let digest = Self::compute_digest(&elf, &ebpf);
+
+		let mut id_src = name.as_bytes().to_vec();
+		id_src.push(0);
+		id_src.extend_from_slice(&digest);
+
+		Self {
+			uuid: Uuid::new_v5(&NF_NAMESPACE, &id_src),
+			digest,
+			elf,
+			ebpf,
+		}
+	}
+
+	pub fn compute_digest(elf: &Option<Vec<u8>>, ebpf: &Option<EbpfFunction>) -> Digest {
+		let mut ctx = Context::new(&SHA256);
+
+		let parts = [
+			("elf", elf.as_deref()),
+			("ebpf.link", ebpf.as_ref().map(|e| &e.link[..])),
+			("ebpf.end", ebpf.as_ref().map(|e| &e.end[..])),
+		];
+
+		for (label, part) in parts {
+			ctx.update(label.as_bytes());
+			match part {
+				Some(bytes) => {
+					ctx.update(&[1]);
+					ctx.update(&(bytes.len() as u64).to_le_bytes());
+					ctx.update(bytes);
+				},
+				None => ctx.update(&[0]),
+			}
+		}
+
+		let mut out = [0u8; SHA256_OUTPUT_LEN];
+		out.copy_from_slice(ctx.finish().as_ref());
+
+		out
+	}
+
+	/// Checks that the stored digest matches this function's payloads.
+	pub fn digest_matches(&self) -> bool {
+		Self::compute_digest(&self.elf, &self.ebpf) == self.digest
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct EbpfFunction {
 	pub link: Vec<u8>,
 	pub end: Vec<u8>,
@@ -0,0 +1,223 @@
+use std::{collections::BTreeSet, ops::BitOr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Digest;
+
+/// Version of the chainsmith/pulley wire protocol spoken by this build.
+///
+/// This must be bumped whenever the layout of any existing message (or of types
+/// they contain, e.g. [`XdpLink`](crate::XdpLink)) changes, or that of the
+/// entry points, headroom, or state maps expected of the NFs in each chain.
+/// Optional additions should instead be gated on a new [`Features`] bit, which
+/// leaves older peers able to interoperate.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version this build can interoperate with.
+///
+/// This is only raised when support for older peers is dropped, and is sent in
+/// each hello so that older peers can tell whether they are still understood.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features, advertised by each side during the hello exchange.
+///
+/// Unknown bits sent by newer peers are ignored.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Features(u64);
+
+impl Features {
+	/// Runtime map updates (`MapUpdate`/`MapUpdateAck`).
+	pub const MAP_UPDATES: Self = Self(1 << 0);
+	/// Chain manifests with compressed, chunked NF downloads.
+	pub const CHUNKED_TRANSFER: Self = Self(1 << 1);
+	/// Ed25519 chain signatures.
+	pub const SIGNED_CHAINS: Self = Self(1 << 2);
+	/// eBPF NFs compiled against the client kernel's own BTF.
+	pub const KERNEL_BTF: Self = Self(1 << 3);
+	/// Per-NF cost profiles (`Profile`), reported by clients run with `--profile`.
+	pub const PROFILES: Self = Self(1 << 4);
+	/// Chains attached at tc ingress or egress, rather than XDP.
+	pub const TC_HOOKS: Self = Self(1 << 5);
+	/// eBPF programs holding several fused NFs ([`XdpLink::fused`](crate::XdpLink::fused)).
+	pub const FUSED_NFS: Self = Self(1 << 6);
+	/// Userland NFs linked into one library ([`UserlandChain`](crate::UserlandChain)).
+	pub const USERLAND_CHAIN: Self = Self(1 << 7);
+	/// Links which split traffic by weight ([`XdpLink::weights`](crate::XdpLink::weights)).
+	pub const SPLIT_LINKS: Self = Self(1 << 8);
+	/// Links which mirror packets to an observer NF ([`XdpLink::mirror`](crate::XdpLink::mirror)).
+	pub const MIRROR_LINKS: Self = Self(1 << 9);
+
+	/// Every feature supported by this build.
+	pub const ALL: Self = Self(
+		Self::MAP_UPDATES.0
+			| Self::CHUNKED_TRANSFER.0
+			| Self::SIGNED_CHAINS.0
+			| Self::KERNEL_BTF.0
+			| Self::PROFILES.0
+			| Self::TC_HOOKS.0
+			| Self::FUSED_NFS.0
+			| Self::USERLAND_CHAIN.0
+			| Self::SPLIT_LINKS.0
+			| Self::MIRROR_LINKS.0,
+	);
+
+	const NAMES: [(Self, &'static str); 10] = [
+		(Self::MAP_UPDATES, "map-updates"),
+		(Self::CHUNKED_TRANSFER, "chunked-transfer"),
+		(Self::SIGNED_CHAINS, "signed-chains"),
+		(Self::KERNEL_BTF, "kernel-btf"),
+		(Self::PROFILES, "profiles"),
+		(Self::TC_HOOKS, "tc-hooks"),
+		(Self::FUSED_NFS, "fused-nfs"),
+		(Self::USERLAND_CHAIN, "userland-chain"),
+		(Self::SPLIT_LINKS, "split-links"),
+		(Self::MIRROR_LINKS, "mirror-links"),
+	];
+
+	/// The empty set.
+	pub fn empty() -> Self {
+		Self(0)
+	}
+
+	pub fn contains(self, other: Self) -> bool {
+		self.0 & other.0 == other.0
+	}
+
+	/// Features supported by both `self` and `other`.
+	pub fn intersection(self, other: Self) -> Self {
+		Self(self.0 & other.0)
+	}
+
+	/// Features in `self` but not in `other`.
+	pub fn difference(self, other: Self) -> Self {
+		Self(self.0 & !other.0)
+	}
+
+	/// Names of every known feature in this set.
+	pub fn names(self) -> Vec<&'static str> {
+		Self::NAMES
+			.iter()
+			.filter(|(feature, _)| self.contains(*feature))
+			.map(|(_, name)| *name)
+			.collect()
+	}
+}
+
+impl BitOr for Features {
+	type Output = Self;
+
+	fn bitor(self, rhs: Self) -> Self {
+		Self(self.0 | rhs.0)
+	}
+}
+
+/// Description of the kernel a client runs on, and the eBPF features it supports.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct KernelInfo {
+	/// Kernel release, as given by `uname -r`.
+	pub release: String,
+	/// Whether the fields below were probed. If not, nothing is known about
+	/// the kernel's eBPF support.
+	pub probed: bool,
+	/// Whether XDP programs can be loaded.
+	///
+	/// Whether these run in native (driver) or generic (skb) mode is a property
+	/// of each interface rather than the kernel, and is left to the kernel when
+	/// attaching. Both modes accept the same programs and helpers, so this does
+	/// not affect which NFs a client can run in XDP.
+	pub xdp: bool,
+	/// Whether the kernel exposes its own BTF (`/sys/kernel/btf/vmlinux`).
+	pub btf: bool,
+	/// SHA-256 digest of the kernel's BTF, if it could be read.
+	pub btf_digest: Option<Digest>,
+	/// IDs (`enum bpf_func_id`) of every helper callable from XDP programs.
+	pub helpers: BTreeSet<u32>,
+	/// IDs (`enum bpf_map_type`) of every supported map type.
+	pub map_types: BTreeSet<u32>,
+}
+
+/// First message sent by a client on each connection.
+///
+/// Fields may only be appended to this (and to [`ServerHello`]), so that peers
+/// speaking any version can still read the versions and features of the other.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ClientHello {
+	pub version: u32,
+	/// Oldest protocol version the client can interoperate with.
+	pub min_version: u32,
+	pub features: Features,
+	pub kernel: KernelInfo,
+}
+
+/// Server's reply to a compatible [`ClientHello`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ServerHello {
+	pub version: u32,
+	/// Oldest protocol version the server can interoperate with.
+	pub min_version: u32,
+	pub features: Features,
+	/// Whether the server needs the client to upload its kernel's BTF, via
+	/// [`ClientToServer::KernelBtf`](crate::ClientToServer::KernelBtf).
+	pub request_btf: bool,
+}
+
+/// Checks whether a peer speaking protocol `version`, and understanding versions
+/// back to `min_version`, can interoperate with this build.
+///
+/// Newer peers are accepted only if they still understand [`PROTOCOL_VERSION`].
+pub fn check_version(version: u32, min_version: u32) -> Result<(), String> {
+	if version < MIN_PROTOCOL_VERSION {
+		Err(format!(
+			"peer speaks protocol version {version}, but at least {MIN_PROTOCOL_VERSION} is \
+			 required: please upgrade it."
+		))
+	} else if min_version > PROTOCOL_VERSION {
+		Err(format!(
+			"peer speaks protocol version {version} and requires at least {min_version}, but \
+			 this build speaks {PROTOCOL_VERSION}: please upgrade it."
+		))
+	} else {
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn check_version_accepts_compatible_peers() {
+		assert!(check_version(PROTOCOL_VERSION, MIN_PROTOCOL_VERSION).is_ok());
+		assert!(check_version(PROTOCOL_VERSION + 1, PROTOCOL_VERSION).is_ok());
+	}
+
+	#[test]
+	fn check_version_rejects_old_and_incompatible_new_peers() {
+		assert!(check_version(MIN_PROTOCOL_VERSION - 1, 0).is_err());
+		assert!(check_version(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 1).is_err());
+	}
+
+	#[test]
+	fn appended_hello_fields_are_ignored() {
+		#[derive(Serialize)]
+		struct FutureHello {
+			hello: ServerHello,
+			extra: u64,
+		}
+
+		let hello = FutureHello {
+			hello: ServerHello {
+				version: PROTOCOL_VERSION + 1,
+				min_version: PROTOCOL_VERSION,
+				features: Features::ALL,
+				request_btf: true,
+			},
+			extra: 7,
+		};
+		let bytes = postcard::to_stdvec(&hello).unwrap();
+		let parsed: ServerHello = postcard::from_bytes(&bytes).unwrap();
+
+		assert_eq!(parsed.version, PROTOCOL_VERSION + 1);
+		assert!(parsed.request_btf);
+	}
+}
@@ -1,11 +1,22 @@
+mod bundle;
 mod chain;
 mod error;
 mod function;
+mod hello;
 mod keys;
+mod map;
 mod message;
+mod profile;
+mod signature;
+mod transfer;
 
+pub use bundle::*;
 pub use chain::*;
 pub use error::*;
 pub use function::*;
+pub use hello::*;
 pub use keys::*;
+pub use map::*;
 pub use message::*;
+pub use profile::*;
+pub use transfer::*;
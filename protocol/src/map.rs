@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// A typed map key or value.
+///
+/// Clients lay these out in their own native byte order, matching the layout
+/// of the NF's key and value types on that machine.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum MapDatum {
+	Bool(bool),
+	U8(u8),
+	U16(u16),
+	U32(u32),
+	U64(u64),
+	I8(i8),
+	I16(i16),
+	I32(i32),
+	I64(i64),
+	Array(Vec<MapDatum>),
+}
+
+impl MapDatum {
+	/// Appends the native in-memory representation of this datum to `out`.
+	pub fn write_ne(&self, out: &mut Vec<u8>) {
+		match self {
+			Self::Bool(v) => out.push(u8::from(*v)),
+			Self::U8(v) => out.extend_from_slice(&v.to_ne_bytes()),
+			Self::U16(v) => out.extend_from_slice(&v.to_ne_bytes()),
+			Self::U32(v) => out.extend_from_slice(&v.to_ne_bytes()),
+			Self::U64(v) => out.extend_from_slice(&v.to_ne_bytes()),
+			Self::I8(v) => out.extend_from_slice(&v.to_ne_bytes()),
+			Self::I16(v) => out.extend_from_slice(&v.to_ne_bytes()),
+			Self::I32(v) => out.extend_from_slice(&v.to_ne_bytes()),
+			Self::I64(v) => out.extend_from_slice(&v.to_ne_bytes()),
+			Self::Array(vs) =>
+				for v in vs {
+					v.write_ne(out);
+				},
+		}
+	}
+
+	pub fn to_ne_bytes(&self) -> Vec<u8> {
+		let mut out = vec![];
+		self.write_ne(&mut out);
+
+		out
+	}
+}
+
+/// A key/value pair to insert into an NF's map when its chain is installed.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MapEntry {
+	pub key: MapDatum,
+	pub value: MapDatum,
+}
+
+/// A change to the contents of one map in a live chain.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum MapOp {
+	/// Add entries, failing if any key is already present.
+	Insert(Vec<MapEntry>),
+	/// Change entries, failing if any key is not already present.
+	Update(Vec<MapEntry>),
+	/// Remove the entries with these keys.
+	Delete(Vec<MapDatum>),
+	/// Make these the only entries in the map.
+	Replace(Vec<MapEntry>),
+}
+
+/// A [`MapOp`] addressed to a map of one NF instance.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MapUpdate {
+	pub nf: Uuid,
+	pub map: String,
+	pub op: MapOp,
+}
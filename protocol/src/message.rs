@@ -5,13 +5,55 @@ use super::*;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum ClientToServer {
+	/// Request the chain assigned to this client, built for the given target.
 	RequestChain(String),
+	/// Request a chain by name, built for the given target.
+	RequestNamedChain { name: String, target: String },
+	/// Result of applying the [`ServerToClient::MapUpdate`] with the same `id`.
+	MapUpdateAck { id: u64, result: Result<(), String> },
+	/// Request the [`ChainManifest`] of a chain built for the given target, rather
+	/// than the full chain.
+	///
+	/// If no `name` is given, the server picks which chain to send. Rebuilds are
+	/// likewise pushed as manifests.
+	RequestManifest {
+		name: Option<String>,
+		target: String,
+	},
+	/// Request the compressed payload of the NF with `digest`, starting `offset`
+	/// bytes in.
+	RequestNf { digest: Digest, offset: u64 },
+	/// Advertise this client's protocol version, features and kernel.
+	///
+	/// This must be the first message sent on each connection.
+	Hello(ClientHello),
+	/// The client kernel's BTF, compressed via [`compress_btf`], if requested in
+	/// the server's [`ServerHello`].
+	KernelBtf(Vec<u8>),
+	/// Costs measured for each NF in the client's live chain, if both sides
+	/// support [`Features::PROFILES`].
+	Profile(ChainProfile),
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum ServerToClient {
 	Chain(Chain),
 	RequestChainError(String),
+	/// Change the contents of a map in the client's live chain.
+	MapUpdate {
+		id: u64,
+		update: MapUpdate,
+	},
+	ChainManifest(ChainManifest),
+	/// Part of the compressed payload of the NF with `digest`, `total` bytes long.
+	NfChunk {
+		digest: Digest,
+		offset: u64,
+		total: u64,
+		data: Vec<u8>,
+	},
+	/// Accept a client's [`ClientToServer::Hello`].
+	Hello(ServerHello),
 }
 
 // TODO: modify below to account for falcon and AES state.
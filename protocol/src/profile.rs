@@ -0,0 +1,45 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Costs measured by a client for each NF in its live chain.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ChainProfile {
+	pub nfs: Vec<NfProfile>,
+}
+
+/// Costs measured for one NF, as identified by its ID in the chain.
+///
+/// NFs fused into another eBPF program share that program's eBPF measurements.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NfProfile {
+	pub nf: Uuid,
+	/// Mean time (in ns) the NF's eBPF program took to process a test packet.
+	pub xdp_ns: Option<u64>,
+	/// Number of instructions the verifier processed when loading the NF's
+	/// eBPF program.
+	pub verified_insns: Option<u32>,
+	/// Number of packets the NF processed in userland.
+	pub user_packets: u64,
+	/// Total time (in ns) the NF spent processing packets in userland.
+	pub user_ns: u64,
+	/// Number of packets upcalled to the NF from eBPF.
+	pub upcalls: u64,
+}
+
+impl NfProfile {
+	pub fn new(nf: Uuid) -> Self {
+		Self {
+			nf,
+			xdp_ns: None,
+			verified_insns: None,
+			user_packets: 0,
+			user_ns: 0,
+			upcalls: 0,
+		}
+	}
+
+	/// Mean time (in ns) the NF took to process each packet in userland.
+	pub fn user_ns_per_packet(&self) -> Option<u64> {
+		self.user_ns.checked_div(self.user_packets)
+	}
+}
@@ -0,0 +1,63 @@
+use ring::signature::{Ed25519KeyPair, UnparsedPublicKey, ED25519};
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::*;
+
+/// Domain separator for chain signatures, so they cannot be reused elsewhere.
+const SIGNATURE_CONTEXT: &str = "galette-chain-v1";
+
+#[derive(Serialize)]
+struct SignedContents<'a> {
+	context: &'a str,
+	hook: Hook,
+	nfs: Vec<(&'a Uuid, &'a Digest)>,
+	links: &'a [XdpLink],
+	userland: &'a Option<UserlandChain>,
+}
+
+impl Chain {
+	/// Bytes covered by a chain's signature: its hook, every NF's ID and digest,
+	/// the link table, and the layout of any userland chain program.
+	///
+	/// NF payloads are bound to the signature via their digests, which must be
+	/// checked separately (see [`Chain::verify`]).
+	fn signed_contents(&self) -> Vec<u8> {
+		let mut nfs: Vec<_> = self.nfs.iter().map(|(id, f)| (id, &f.digest)).collect();
+		nfs.sort_unstable();
+
+		postcard::to_stdvec(&SignedContents {
+			context: SIGNATURE_CONTEXT,
+			hook: self.hook,
+			nfs,
+			links: &self.links,
+			userland: &self.userland,
+		})
+		.expect("All local types should be postcard-friendly.")
+	}
+
+	/// Signs this chain's NF digests and link table, replacing any existing signature.
+	pub fn sign(&mut self, key: &Ed25519KeyPair) {
+		self.signature = Some(key.sign(&self.signed_contents()).as_ref().to_vec());
+	}
+
+	/// Checks this chain's signature against an Ed25519 `public_key`, and that each
+	/// NF's payloads match its signed digest.
+	///
+	/// This must succeed before any NF code in the chain is loaded.
+	pub fn verify(&self, public_key: &[u8]) -> Result<(), SignatureError> {
+		let signature = self.signature.as_ref().ok_or(SignatureError::Unsigned)?;
+
+		UnparsedPublicKey::new(&ED25519, public_key)
+			.verify(&self.signed_contents(), signature)
+			.map_err(|_| SignatureError::Invalid)?;
+
+		for (id, nf) in &self.nfs {
+			if nf.uuid != *id || !nf.digest_matches() {
+				return Err(SignatureError::DigestMismatch(*id));
+			}
+		}
+
+		Ok(())
+	}
+}
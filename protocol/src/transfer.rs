@@ -0,0 +1,144 @@
+use std::{collections::HashMap, io::Read};
+
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::*;
+
+/// Maximum size of each [`ServerToClient::NfChunk`] payload.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest kernel BTF accepted from a client, once decompressed.
+pub const MAX_BTF_SIZE: u64 = 64 * 1024 * 1024;
+
+/// zstd compression level used for NF payloads.
+const COMPRESSION_LEVEL: i32 = 19;
+
+/// An NF listed in a [`ChainManifest`], whose payloads are fetched separately.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NfSummary {
+	pub uuid: Uuid,
+	pub digest: Digest,
+	/// Size of the NF's compressed payload, in bytes.
+	pub size: u64,
+}
+
+/// A [`Chain`] without its NF payloads.
+///
+/// Clients fetch the compressed payload of each NF they do not already hold by
+/// digest, then [`assemble`](ChainManifest::assemble) the full chain.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChainManifest {
+	pub hook: Hook,
+	pub links: Vec<XdpLink>,
+	pub userland: Option<UserlandChain>,
+	pub nfs: Vec<NfSummary>,
+	pub signature: Option<Vec<u8>>,
+}
+
+impl ChainManifest {
+	/// Lists the NFs of `chain`, given the compressed size of each payload by digest.
+	pub fn new(chain: &Chain, payloads: &HashMap<Digest, Vec<u8>>) -> Self {
+		let mut nfs: Vec<_> = chain
+			.nfs
+			.values()
+			.map(|nf| NfSummary {
+				uuid: nf.uuid,
+				digest: nf.digest,
+				size: payloads
+					.get(&nf.digest)
+					.map(|p| p.len() as u64)
+					.unwrap_or(0),
+			})
+			.collect();
+		nfs.sort_unstable_by_key(|nf| nf.uuid);
+
+		Self {
+			hook: chain.hook,
+			links: chain.links.clone(),
+			userland: chain.userland.clone(),
+			nfs,
+			signature: chain.signature.clone(),
+		}
+	}
+
+	/// Builds the full chain from NF payloads indexed by digest.
+	///
+	/// Fails if any listed NF's payload is missing.
+	pub fn assemble(&self, payloads: &HashMap<Digest, Function>) -> Result<Chain, TransferError> {
+		let nfs = self
+			.nfs
+			.iter()
+			.map(|summary| {
+				let nf = payloads
+					.get(&summary.digest)
+					.ok_or(TransferError::MissingNf(summary.uuid))?;
+
+				// The same NF at several positions shares one payload, but not its ID.
+				let mut nf = nf.clone();
+				nf.uuid = summary.uuid;
+
+				Ok((summary.uuid, nf))
+			})
+			.collect::<Result<_, _>>()?;
+
+		Ok(Chain {
+			hook: self.hook,
+			links: self.links.clone(),
+			userland: self.userland.clone(),
+			nfs,
+			signature: self.signature.clone(),
+		})
+	}
+}
+
+/// Compresses an NF's payloads for transfer.
+pub fn compress_nf(nf: &Function) -> Vec<u8> {
+	let raw = postcard::to_stdvec(nf).expect("All local types should be postcard-friendly.");
+
+	zstd::encode_all(&raw[..], COMPRESSION_LEVEL).expect("In-memory compression cannot fail.")
+}
+
+/// Decompresses a fetched NF, checking that its payloads match `digest`.
+pub fn decompress_nf(digest: &Digest, data: &[u8]) -> Result<Function, TransferError> {
+	let raw = zstd::decode_all(data).map_err(TransferError::Decompress)?;
+	let nf: Function = postcard::from_bytes(&raw).map_err(TransferError::Deserialize)?;
+
+	if nf.digest != *digest || !nf.digest_matches() {
+		return Err(TransferError::DigestMismatch(nf.uuid));
+	}
+
+	Ok(nf)
+}
+
+/// Computes the digest identifying a kernel's BTF.
+pub fn btf_digest(btf: &[u8]) -> Digest {
+	let mut out = Digest::default();
+	out.copy_from_slice(digest(&SHA256, btf).as_ref());
+
+	out
+}
+
+/// Compresses a kernel's BTF for upload.
+pub fn compress_btf(btf: &[u8]) -> Vec<u8> {
+	zstd::encode_all(btf, COMPRESSION_LEVEL).expect("In-memory compression cannot fail.")
+}
+
+/// Decompresses an uploaded kernel BTF, checking that it matches `digest`.
+pub fn decompress_btf(digest: &Digest, data: &[u8]) -> Result<Vec<u8>, TransferError> {
+	let mut btf = vec![];
+	zstd::stream::Decoder::new(data)
+		.and_then(|decoder| decoder.take(MAX_BTF_SIZE + 1).read_to_end(&mut btf))
+		.map_err(TransferError::Decompress)?;
+
+	if btf.len() as u64 > MAX_BTF_SIZE {
+		return Err(TransferError::BtfTooLarge);
+	}
+
+	if btf_digest(&btf) != *digest {
+		return Err(TransferError::BtfMismatch);
+	}
+
+	Ok(btf)
+}
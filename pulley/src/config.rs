@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use clap::{Parser, ValueEnum};
 
 #[derive(Parser)]
@@ -7,6 +9,28 @@ pub struct Cli {
 	/// URL for a `chainsmith` compiler server, i.e. "wss://" or "ws://127.0.0.1:8080".
 	pub server_addr: String,
 
+	#[clap(value_parser, long)]
+	/// Install the chain stored in this offline bundle (built by `chainsmith build`),
+	/// rather than fetching it from a server.
+	///
+	/// No map updates or rebuilt chains are received while running from a bundle.
+	pub bundle: Option<PathBuf>,
+
+	#[clap(value_parser, long)]
+	/// Name of the chain to request from the server.
+	///
+	/// If unset, the server chooses based on this client's certificate.
+	pub chain: Option<String>,
+
+	#[clap(value_parser = parse_public_key, long)]
+	/// Hex-encoded Ed25519 public key which every chain must be signed by, as
+	/// printed by `chainsmith gen-key`.
+	///
+	/// Chains (including offline bundles) which are unsigned, signed by another
+	/// key, or whose NFs do not match their signed digests are refused before any
+	/// of their code is loaded. If unset, chain signatures are not checked.
+	pub trusted_key: Option<Vec<u8>>,
+
 	#[clap(value_parser, long, short = 'i', required = true, num_args=1..)]
 	/// Ethernet interface[s] to attach XDP programs to.
 	pub interface: Vec<String>,
@@ -40,14 +64,37 @@ pub struct Cli {
 	/// Defaults to 5ms.
 	pub upcall_poll_timeout: usize,
 
-	#[clap(value_parser, long, default_value_t = 0.5)]
-	/// Sets the likelihood to upcall, as used in the 'upcall_likelihood' map for the
-	/// 'load-balance' NF.
+	#[arg(value_enum, long, default_value_t = UpcallSteering::Flow)]
+	/// Configures how upcalled packets are spread across XDP processing threads.
 	///
-	/// Testing value for evaluation, not intended for production use.
+	/// `flow` hashes each packet's addresses, protocol, and ports, so that every
+	/// packet of a flow is handled in order by the same thread. `random` balances
+	/// load more evenly, but may reorder packets within a flow.
+	pub upcall_steering: UpcallSteering,
+
+	#[arg(long)]
+	/// Measures the cost of each NF, and reports it to the server.
 	///
-	/// Defaults to 0.5 (50%).
-	pub loadbalance_chance: f64,
+	/// Each eBPF program is test-run on a sample packet when installed, and its
+	/// verifier complexity recorded. Time spent in userland NFs and upcalls into
+	/// each are counted as packets arrive. Reports are printed instead when
+	/// running from a bundle.
+	pub profile: bool,
+
+	#[clap(value_parser, long, default_value_t = 10)]
+	/// Sets the interval (s) between profile reports, if `--profile` is set.
+	pub profile_interval: u64,
+}
+
+fn parse_public_key(key: &str) -> Result<Vec<u8>, String> {
+	if key.len() != 64 || !key.is_ascii() {
+		return Err("expected 64 hex digits".into());
+	}
+
+	(0..key.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(&key[i..i + 2], 16).map_err(|e| e.to_string()))
+		.collect()
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
@@ -56,3 +103,12 @@ pub enum UmemDisposalMode {
 	FirstThread,
 	ExtraThread,
 }
+
+/// Values are shared with chain programs via their `DataplaneState`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+#[clap(rename_all = "kebab_case")]
+#[repr(u32)]
+pub enum UpcallSteering {
+	Flow = 0,
+	Random = 1,
+}
@@ -1,8 +1,12 @@
+use std::io::Error as IoError;
+
 #[cfg(unix)]
 use libbpf_rs::Error as BpfError;
 #[cfg(unix)]
 use nix::errno::Errno;
-use protocol::DeserError;
+#[cfg(unix)]
+use protocol::Hook;
+use protocol::{DeserError, SignatureError, TransferError};
 use thiserror::Error;
 use tokio_tungstenite::tungstenite::Error as WsError;
 use uuid::Uuid;
@@ -21,6 +25,35 @@ pub enum ChainGetError {
 	ServerError(String),
 	#[error("server closed session prematurely")]
 	SessionClosed,
+	#[error("chain was rebuilt while its NFs were being fetched")]
+	Superseded,
+	#[error("server is incompatible with this client: {0}")]
+	Incompatible(String),
+	#[error("failed to fetch chain from server")]
+	Transfer(#[source] TransferError),
+	#[error("failed to read bundle {0}")]
+	ReadBundle(String, #[source] IoError),
+	#[error("bundle could not be deserialised")]
+	BundleDeserialize(#[source] DeserError),
+	#[error("bundle was built for {0}, but this client is {1}")]
+	BundleTarget(String, String),
+}
+
+#[derive(Debug, Error)]
+pub enum MapUpdateError {
+	#[error("NF {0} is not in the live chain")]
+	MissingNf(Uuid),
+	#[error("NF {0} runs only in userland, where it has no maps to update")]
+	UserlandOnly(Uuid),
+	#[error("NF {0} has no map {1}")]
+	MissingMap(Uuid, String),
+	#[error("entries for map {0} do not match its key/value sizes")]
+	Layout(String),
+	#[error("split weights of NF {0} must total at most {}", u32::MAX)]
+	WeightTotal(Uuid),
+	#[cfg(unix)]
+	#[error("failed to modify map {0}: {1}")]
+	Syscall(String, #[source] Errno),
 }
 
 #[derive(Debug, Error)]
@@ -29,8 +62,26 @@ pub enum ChainInstallError {
 	#[error("failed to get interface {0}")]
 	IfaceLookup(String, #[source] Errno),
 	#[cfg(unix)]
+	#[error("kernel rejected eBPF program of NF {0}")]
+	Load(Uuid, #[source] BpfError),
+	#[cfg(unix)]
 	#[error("failed to update map \"{1}\" for NF {0}")]
 	MapUpdateFail(Uuid, String, #[source] BpfError),
+	#[cfg(unix)]
+	#[error("failed to attach root NF to interface {0}")]
+	Attach(String, #[source] BpfError),
+	#[cfg(unix)]
+	#[error("failed to attach root NF to tc hook of interface {0}")]
+	TcAttach(String, #[source] Errno),
+	#[cfg(unix)]
+	#[error("failed to swap root NF of live chain")]
+	RootSwap(#[source] Errno),
+	#[cfg(unix)]
+	#[error("updated chain uses the {0:?} hook, but the live chain does not")]
+	HookChanged(Hook),
+
+	#[error("chain failed signature check")]
+	Signature(#[source] SignatureError),
 
 	// TODO: move to ahead-of-time verifier.
 	#[error("NF {0} is missing in received chain")]
@@ -43,6 +94,8 @@ pub enum ChainInstallError {
 	BadNfLink(Uuid, Uuid),
 	#[error("intermediate NF {0} is missing the required map {1}")]
 	MissingMap(Uuid, String),
+	#[error("initial entries for NF {0} map {1} do not match its key/value sizes")]
+	MapEntryLayout(Uuid, String),
 	#[error("chain has no root NF from `rx` -- cannot execute")]
 	MissingRootNf,
 }
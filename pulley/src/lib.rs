@@ -1,14 +1,24 @@
 pub mod config;
 pub mod error;
+pub mod probe;
 #[cfg(unix)]
-pub mod prog;
+pub mod profile;
 
 #[cfg(unix)]
 use std::{
-	collections::HashMap,
+	collections::HashSet,
+	ffi::c_void,
+	mem,
 	os::unix::io::{AsRawFd, RawFd},
+	sync::atomic::{AtomicU32, Ordering},
+};
+use std::{
+	collections::{HashMap, VecDeque},
+	io::Error as IoError,
+	path::{Path, PathBuf},
+	sync::Arc,
+	time::Duration,
 };
-use std::{io::Error as IoError, path::PathBuf, sync::Arc};
 
 use config::Cli;
 #[cfg(unix)]
@@ -16,15 +26,48 @@ use dlopen2::wrapper::{Container, WrapperApi};
 use error::*;
 use futures_util::{SinkExt, StreamExt};
 #[cfg(unix)]
-use libbpf_rs::{MapFlags, ObjectBuilder};
+use libbpf_rs::{libbpf_sys, Link, Map, MapFlags, Object, ObjectBuilder};
 #[cfg(unix)]
 use nf::{Map as NfMapTrait, RawMap};
 #[cfg(unix)]
-use prog::Prog;
-use protocol::{Chain, ClientToServer, CrpServerTlsVerifier, ServerToClient};
+use nix::errno::Errno;
+#[cfg(unix)]
+use profile::NfCounters;
+use protocol::{
+	check_version,
+	Bundle,
+	Chain,
+	ChainManifest,
+	ChainProfile,
+	ClientHello,
+	ClientToServer,
+	CrpServerTlsVerifier,
+	Digest,
+	Features,
+	Function,
+	KernelInfo,
+	ServerToClient,
+	MIN_PROTOCOL_VERSION,
+	PROTOCOL_VERSION,
+};
 #[cfg(unix)]
-use protocol::{LinkAction, XdpLinkState};
+use protocol::{
+	fused_map_prefix,
+	Hook,
+	LinkAction,
+	MapDatum,
+	MapEntry,
+	MapOp,
+	MapUpdate,
+	Mirror,
+	XdpLink,
+	XdpLinkState,
+	MIRROR_MAP,
+	SPLIT_WEIGHTS_MAP,
+};
+use tokio::net::TcpStream;
 use tokio_rustls::rustls::{client::WebPkiVerifier, Certificate, PrivateKey};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 #[cfg(unix)]
 use uuid::Uuid;
 #[cfg(unix)]
@@ -39,72 +82,429 @@ use xsk_rs::{
 	TxQueue,
 };
 
-pub async fn get_chain(server: &str) -> Result<Chain, ChainGetError> {
-	let mut trust = tokio_rustls::rustls::RootCertStore { roots: vec![] };
-	trust
-		.add_parsable_certificates(&[include_bytes!("../../certs/server/certs/cert.der").to_vec()]);
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Number of times to reconnect to the server while fetching an NF.
+const RECONNECT_ATTEMPTS: usize = 5;
+
+/// Time to wait before each reconnection attempt.
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// Long-lived connection to a chainsmith server.
+///
+/// After requesting a chain, the server will push a fresh copy whenever it
+/// rebuilds that chain. Chains are received as manifests, and only NFs whose
+/// payloads are not already held are downloaded.
+pub struct ChainSession {
+	server: String,
+	ws: WsStream,
+	/// Optional features supported by both this client and the server.
+	features: Features,
+	/// This client's kernel, probed once and re-sent in each hello.
+	kernel: KernelInfo,
+	/// Name given in the last chain request, re-sent after reconnecting.
+	requested: Option<Option<String>>,
+	/// Messages received while fetching NFs, to be handled afterwards.
+	pending: VecDeque<ServerToClient>,
+	/// Payloads of every NF in the last assembled chain, by digest.
+	known: HashMap<Digest, Function>,
+	/// Compressed payloads received so far for NFs still being fetched.
+	partial: HashMap<Digest, Vec<u8>>,
+	/// Serialised manifest of the last assembled chain.
+	last_manifest: Option<Vec<u8>>,
+}
 
-	let crps = if let Ok(data) = tokio::fs::read("testcrps.post").await {
-		postcard::from_bytes(&data).unwrap()
-	} else {
-		eprintln!("Warning! Using random CRP store, not pre-shared!");
+impl ChainSession {
+	pub async fn connect(server: &str) -> Result<Self, ChainGetError> {
+		let kernel = probe::kernel_info();
+		let (ws, features) = Self::open(server, &kernel).await?;
 
-		protocol::KeySource::new_random()
-	};
+		Ok(Self {
+			server: server.into(),
+			ws,
+			features,
+			kernel,
+			requested: None,
+			pending: VecDeque::new(),
+			known: HashMap::new(),
+			partial: HashMap::new(),
+			last_manifest: None,
+		})
+	}
 
-	let verifier = CrpServerTlsVerifier {
-		base: Arc::new(WebPkiVerifier::new(trust, None)),
-		crps,
-	};
+	/// Connects to `server` and exchanges hellos, describing this client as
+	/// running `kernel`, and returns the features the server supports.
+	async fn open(
+		server: &str,
+		kernel: &KernelInfo,
+	) -> Result<(WsStream, Features), ChainGetError> {
+		let mut trust = tokio_rustls::rustls::RootCertStore { roots: vec![] };
+		trust.add_parsable_certificates(&[
+			include_bytes!("../../certs/server/certs/cert.der").to_vec()
+		]);
+
+		let crps = if let Ok(data) = tokio::fs::read("testcrps.post").await {
+			postcard::from_bytes(&data).unwrap()
+		} else {
+			eprintln!("Warning! Using random CRP store, not pre-shared!");
 
-	let cfg = tokio_rustls::rustls::ClientConfig::builder()
-		.with_safe_defaults()
-		.with_custom_certificate_verifier(Arc::new(verifier))
-		// .with_client_cert_resolver(client_auth_cert_resolver)
-		.with_single_cert(
-			vec![Certificate(
-				include_bytes!("../../certs/client/certs/cert.der").to_vec(),
-			)],
-			PrivateKey(include_bytes!("../../certs/client/certs/key.der").to_vec()),
-		)
-		.expect("Failed to create own trust chain.");
+			protocol::KeySource::new_random()
+		};
 
-	let connector = tokio_tungstenite::Connector::Rustls(cfg.into());
+		let verifier = CrpServerTlsVerifier {
+			base: Arc::new(WebPkiVerifier::new(trust, None)),
+			crps,
+		};
 
-	println!("Connecting to: {server}");
-	let (mut ws_stream, _) =
-		tokio_tungstenite::connect_async_tls_with_config(server, None, Some(connector))
+		let cfg = tokio_rustls::rustls::ClientConfig::builder()
+			.with_safe_defaults()
+			.with_custom_certificate_verifier(Arc::new(verifier))
+			// .with_client_cert_resolver(client_auth_cert_resolver)
+			.with_single_cert(
+				vec![Certificate(
+					include_bytes!("../../certs/client/certs/cert.der").to_vec(),
+				)],
+				PrivateKey(include_bytes!("../../certs/client/certs/key.der").to_vec()),
+			)
+			.expect("Failed to create own trust chain.");
+
+		let connector = tokio_tungstenite::Connector::Rustls(cfg.into());
+
+		println!("Connecting to: {server}");
+		let (mut ws, _) =
+			tokio_tungstenite::connect_async_tls_with_config(server, None, Some(connector))
+				.await
+				.map_err(ChainGetError::Connect)?;
+
+		let hello = ClientToServer::Hello(ClientHello {
+			version: PROTOCOL_VERSION,
+			min_version: MIN_PROTOCOL_VERSION,
+			features: Features::ALL,
+			kernel: kernel.clone(),
+		});
+
+		ws.send(protocol::ser(&hello))
 			.await
-			.map_err(ChainGetError::Connect)?;
+			.map_err(ChainGetError::SendRequest)?;
+
+		let reply = loop {
+			match ws.next().await {
+				Some(Ok(msg)) => match protocol::deser::<ServerToClient>(&msg)
+					.map_err(ChainGetError::Deserialize)?
+				{
+					Some(ServerToClient::Hello(reply)) => break reply,
+					Some(ServerToClient::RequestChainError(err)) =>
+						return Err(ChainGetError::ServerError(err)),
+					Some(_) =>
+						return Err(ChainGetError::Incompatible(
+							"server did not reply to hello".into(),
+						)),
+					None => {},
+				},
+				Some(Err(e)) => return Err(ChainGetError::WsRecv(e)),
+				None =>
+					return Err(ChainGetError::Incompatible(
+						"server closed connection on hello, and may predate protocol versioning"
+							.into(),
+					)),
+			}
+		};
 
-	// println!("{:?}", ws_stream);
+		check_version(reply.version, reply.min_version).map_err(ChainGetError::Incompatible)?;
 
-	ws_stream
-		.send(protocol::ser(&ClientToServer::RequestChain(
-			env!("TARGET").into(),
-		)))
-		.await
-		.map_err(ChainGetError::SendRequest)?;
+		if reply.request_btf {
+			// An empty upload tells the server to fall back to its own BTF.
+			let btf = probe::kernel_btf()
+				.map(|btf| protocol::compress_btf(&btf))
+				.unwrap_or_default();
 
-	loop {
-		match ws_stream.next().await {
-			Some(Ok(msg)) =>
-				match protocol::deser::<ServerToClient>(&msg).map_err(ChainGetError::Deserialize)? {
-					Some(ServerToClient::Chain(v)) => return Ok(v),
+			ws.send(protocol::ser(&ClientToServer::KernelBtf(btf)))
+				.await
+				.map_err(ChainGetError::SendRequest)?;
+		}
+
+		Ok((ws, reply.features.intersection(Features::ALL)))
+	}
+
+	/// Ask the server for a chain built for this client's target.
+	///
+	/// If no `name` is given, the server picks which chain to send.
+	pub async fn request_chain(&mut self, name: Option<&str>) -> Result<(), ChainGetError> {
+		self.requested = Some(name.map(String::from));
+
+		let target = env!("TARGET").into();
+		let msg = match name {
+			_ if self.features.contains(Features::CHUNKED_TRANSFER) =>
+				ClientToServer::RequestManifest {
+					name: name.map(String::from),
+					target,
+				},
+			Some(name) => ClientToServer::RequestNamedChain {
+				name: name.into(),
+				target,
+			},
+			None => ClientToServer::RequestChain(target),
+		};
+
+		self.send(&msg).await
+	}
+
+	/// Report the outcome of the server's map update `id`.
+	pub async fn ack_map_update(
+		&mut self,
+		id: u64,
+		result: Result<(), String>,
+	) -> Result<(), ChainGetError> {
+		self.send(&ClientToServer::MapUpdateAck { id, result })
+			.await
+	}
+
+	/// Whether the server accepts reports from [`Self::report_profile`].
+	pub fn accepts_profiles(&self) -> bool {
+		self.features.contains(Features::PROFILES)
+	}
+
+	/// Report the costs measured for each NF in the live chain.
+	///
+	/// This should only be sent if [`Self::accepts_profiles`].
+	pub async fn report_profile(&mut self, profile: ChainProfile) -> Result<(), ChainGetError> {
+		self.send(&ClientToServer::Profile(profile)).await
+	}
+
+	async fn send(&mut self, msg: &ClientToServer) -> Result<(), ChainGetError> {
+		self.ws
+			.send(protocol::ser(msg))
+			.await
+			.map_err(ChainGetError::SendRequest)
+	}
+
+	/// Wait for the next chain sent by the server, ignoring any other messages.
+	pub async fn next_chain(&mut self) -> Result<Chain, ChainGetError> {
+		loop {
+			if let ServerToClient::Chain(v) = self.next_message().await? {
+				return Ok(v);
+			}
+		}
+	}
+
+	/// Wait for the next chain or map update sent by the server.
+	///
+	/// Chain manifests are returned as full chains once any missing NFs have been
+	/// fetched. Manifests identical to the last chain are skipped.
+	pub async fn next_message(&mut self) -> Result<ServerToClient, ChainGetError> {
+		loop {
+			let msg = match self.pending.pop_front() {
+				Some(msg) => msg,
+				None => self.recv().await?,
+			};
+
+			match msg {
+				ServerToClient::ChainManifest(manifest) => match self.fetch_chain(manifest).await {
+					Ok(Some(chain)) => return Ok(ServerToClient::Chain(chain)),
+					// The server has sent (or is about to send) a newer manifest.
+					Ok(None) | Err(ChainGetError::Superseded) => {},
+					Err(e) => return Err(e),
+				},
+				msg => return Ok(msg),
+			}
+		}
+	}
+
+	async fn recv(&mut self) -> Result<ServerToClient, ChainGetError> {
+		loop {
+			match self.ws.next().await {
+				Some(Ok(msg)) => match protocol::deser::<ServerToClient>(&msg)
+					.map_err(ChainGetError::Deserialize)?
+				{
 					Some(ServerToClient::RequestChainError(err)) =>
 						return Err(ChainGetError::ServerError(err)),
-					_ => {},
+					Some(msg) => return Ok(msg),
+					None => {},
 				},
-			Some(Err(msg)) => {
-				return Err(ChainGetError::WsRecv(msg));
-			},
-			None => {
-				return Err(ChainGetError::SessionClosed);
-			},
+				Some(Err(msg)) => {
+					return Err(ChainGetError::WsRecv(msg));
+				},
+				None => {
+					return Err(ChainGetError::SessionClosed);
+				},
+			}
+		}
+	}
+
+	/// Fetches every NF in `manifest` whose payload is not already held, then
+	/// assembles the full chain.
+	///
+	/// Returns `None` if `manifest` matches the last assembled chain.
+	async fn fetch_chain(
+		&mut self,
+		manifest: ChainManifest,
+	) -> Result<Option<Chain>, ChainGetError> {
+		let manifest_bytes =
+			postcard::to_allocvec(&manifest).expect("All local types should be postcard-friendly.");
+		if self.last_manifest.as_ref() == Some(&manifest_bytes) {
+			return Ok(None);
+		}
+
+		// Drop partial downloads of NFs from chains which have since been replaced.
+		self.partial
+			.retain(|digest, _| manifest.nfs.iter().any(|nf| nf.digest == *digest));
+
+		let mut payloads = HashMap::new();
+		for nf in &manifest.nfs {
+			if payloads.contains_key(&nf.digest) {
+				continue;
+			}
+
+			let payload = match self.known.get(&nf.digest).cloned() {
+				Some(payload) => payload,
+				None => {
+					let data = self.fetch_nf(&nf.digest, nf.size).await?;
+					protocol::decompress_nf(&nf.digest, &data).map_err(ChainGetError::Transfer)?
+				},
+			};
+
+			payloads.insert(nf.digest, payload);
+		}
+
+		let chain = manifest
+			.assemble(&payloads)
+			.map_err(ChainGetError::Transfer)?;
+
+		self.known = payloads;
+		self.last_manifest = Some(manifest_bytes);
+
+		Ok(Some(chain))
+	}
+
+	/// Downloads the `size`-byte compressed payload of an NF, reconnecting and
+	/// resuming from the last received byte if the connection drops.
+	async fn fetch_nf(&mut self, digest: &Digest, size: u64) -> Result<Vec<u8>, ChainGetError> {
+		let mut attempts = 0;
+
+		loop {
+			let err = match self.receive_nf(digest, size).await {
+				Ok(()) => return Ok(self.partial.remove(digest).unwrap_or_default()),
+				Err(
+					e @ (ChainGetError::WsRecv(_)
+					| ChainGetError::SendRequest(_)
+					| ChainGetError::SessionClosed),
+				) => e,
+				Err(e) => return Err(e),
+			};
+
+			loop {
+				if attempts == RECONNECT_ATTEMPTS {
+					return Err(err);
+				}
+				attempts += 1;
+
+				eprintln!("Lost connection while fetching NF ({err}), resuming...");
+				tokio::time::sleep(RECONNECT_DELAY).await;
+
+				match self.reconnect().await {
+					Ok(()) => break,
+					Err(e) => eprintln!("Failed to reconnect: {e}"),
+				}
+			}
+		}
+	}
+
+	/// Requests the NF with `digest` from the server, appending received chunks
+	/// to its partial download until `size` bytes are held.
+	///
+	/// Returns [`ChainGetError::Superseded`] if the chain is rebuilt mid-fetch,
+	/// leaving any newer manifest at the front of the pending queue.
+	async fn receive_nf(&mut self, digest: &Digest, size: u64) -> Result<(), ChainGetError> {
+		let mut requested = self.partial.entry(*digest).or_default().len() as u64;
+		if requested >= size {
+			return Ok(());
 		}
+
+		self.send(&ClientToServer::RequestNf {
+			digest: *digest,
+			offset: requested,
+		})
+		.await?;
+
+		loop {
+			let msg = match self.recv().await {
+				Ok(msg) => msg,
+				// The server only refuses NFs which are not part of the client's
+				// current chain, i.e., the chain was rebuilt since its manifest.
+				Err(ChainGetError::ServerError(_)) => return Err(ChainGetError::Superseded),
+				Err(e) => return Err(e),
+			};
+
+			match msg {
+				ServerToClient::NfChunk {
+					digest: chunk_digest,
+					offset,
+					data,
+					..
+				} if chunk_digest == *digest => {
+					let buf = self.partial.entry(*digest).or_default();
+					let held = buf.len() as u64;
+					let end = offset + data.len() as u64;
+
+					if offset <= held && end > held {
+						buf.extend_from_slice(&data[(held - offset) as usize..]);
+					} else if offset > held && requested != held {
+						// A chunk went missing: resume the stream from what we hold.
+						requested = held;
+						self.send(&ClientToServer::RequestNf {
+							digest: *digest,
+							offset: held,
+						})
+						.await?;
+					}
+
+					if self.partial[digest].len() as u64 >= size {
+						return Ok(());
+					}
+				},
+				msg @ ServerToClient::ChainManifest(_) => {
+					self.pending.push_front(msg);
+					return Err(ChainGetError::Superseded);
+				},
+				msg => self.pending.push_back(msg),
+			}
+		}
+	}
+
+	/// Opens a fresh connection, and re-requests the current chain so that the
+	/// server will keep serving its NFs and updates.
+	async fn reconnect(&mut self) -> Result<(), ChainGetError> {
+		(self.ws, self.features) = Self::open(&self.server, &self.kernel).await?;
+
+		if let Some(name) = self.requested.clone() {
+			self.request_chain(name.as_deref()).await?;
+		}
+
+		Ok(())
 	}
 }
 
+/// Read a chain from an offline bundle, checking it was built for this client's target.
+pub async fn load_bundle(path: &Path) -> Result<Chain, ChainGetError> {
+	let bytes = tokio::fs::read(path)
+		.await
+		.map_err(|e| ChainGetError::ReadBundle(path.display().to_string(), e))?;
+	let bundle = Bundle::from_bytes(&bytes).map_err(ChainGetError::BundleDeserialize)?;
+
+	let target = env!("TARGET");
+	if bundle.target != target {
+		return Err(ChainGetError::BundleTarget(bundle.target, target.into()));
+	}
+
+	println!(
+		"Loaded chain {} from bundle {}.",
+		bundle.name,
+		path.display()
+	);
+
+	Ok(bundle.chain)
+}
+
 #[cfg(not(unix))]
 pub type XskFds = ();
 #[cfg(unix)]
@@ -139,7 +539,7 @@ pub fn create_upcall_sockets(config: &Cli) -> XskFds {
 	// no pref over (zero)copy mode, or native/drv.
 	let skt_cfg = skt_cfg.build();
 	let mut umem_cfg = UmemConfig::builder();
-	umem_cfg.frame_headroom(8);
+	umem_cfg.frame_headroom(UPCALL_HEADROOM as u32);
 
 	let mut shared_umem = None;
 
@@ -193,31 +593,73 @@ pub fn create_upcall_sockets(config: &Cli) -> XskFds {
 pub fn install_chain(
 	_chain: &Chain,
 	_config: &Cli,
-	xsk_fd: &XskFds,
+	_xsk_fds: &[i32],
 ) -> Result<ChainState, ChainInstallError> {
 	eprintln!("Windows: no binaries loaded!");
 
 	Ok(ChainState {})
 }
 
+/// Checks `chain` against the key pinned with `--trusted-key`, if any.
+///
+/// This must be called before loading any NF in `chain`.
+pub fn verify_chain(chain: &Chain, config: &Cli) -> Result<(), ChainInstallError> {
+	match &config.trusted_key {
+		Some(key) => chain.verify(key).map_err(ChainInstallError::Signature),
+		None => {
+			eprintln!("Warning! No trusted key given, chain signature not checked!");
+			Ok(())
+		},
+	}
+}
+
+/// Loads all eBPF NFs in `chain` and wires up their tailcalls and upcall sockets.
+///
+/// The root NF is not attached: see [`attach_chain`] and [`swap_chain`].
 #[cfg(unix)]
 pub fn install_chain(
 	chain: &Chain,
 	config: &Cli,
-	xsks: &XskFds,
+	xsk_fds: &[RawFd],
 ) -> Result<ChainState, ChainInstallError> {
-	// TODO: allow multiple rx + tx.
-	let iface_name = config.interface[0].clone();
-
 	// todo: one uuid -> vec index?
-	let mut linked_ebpfs = HashMap::new();
+	let mut ebpfs = HashMap::new();
 	let mut prog_fds = HashMap::new();
 	let mut instance_ids = HashMap::new();
 	let mut link_states = HashMap::new();
 	let mut raw_maps = HashMap::new();
 
-	let iface = nix::net::if_::if_nametoindex(iface_name.as_str())
-		.map_err(|e| ChainInstallError::IfaceLookup(iface_name.clone(), e))?;
+	// NFs inlined into another's program, with the owning NF and their position.
+	let fused_into: HashMap<Uuid, (Uuid, usize)> = chain
+		.links
+		.iter()
+		.flat_map(|link| {
+			link.fused
+				.iter()
+				.enumerate()
+				.map(move |(i, member)| (*member, (link.uuid, i + 1)))
+		})
+		.collect();
+
+	// Branch weights of split links, shared with userland NFs.
+	let split_weights: HashMap<Uuid, Vec<AtomicU32>> = chain
+		.links
+		.iter()
+		.filter(|link| !link.weights.is_empty())
+		.map(|link| {
+			let weights = link.weights.iter().map(|w| AtomicU32::new(*w)).collect();
+			(link.uuid, weights)
+		})
+		.collect();
+
+	let mirrors: HashMap<Uuid, Mirror> = chain
+		.links
+		.iter()
+		.filter_map(|link| Some((link.uuid, link.mirror?)))
+		.collect();
+
+	// Test-run cost and verifier complexity of each eBPF program, if profiling.
+	let mut ebpf_costs = HashMap::new();
 
 	// AF_XDP handling
 	// Load prog code for all files in chain.
@@ -238,7 +680,15 @@ pub fn install_chain(
 
 		let ebpf_elfs = if let Some(a) = ebpf_elfs { a } else { continue };
 
-		let my_prog = match &chain_link.state {
+		// NFs moved to userland (e.g., due to missing kernel features) are
+		// reached via upcalls alone.
+		if chain_link.disable_xdp {
+			continue;
+		}
+
+		// A fused program acts on behalf of the last NF inlined into it.
+		let nf_links = fused_links(chain, chain_link)?;
+		let my_prog = match &nf_links.last().unwrap_or(&chain_link).state {
 			XdpLinkState::Tail => &ebpf_elfs.end,
 			XdpLinkState::Body(_) => &ebpf_elfs.link,
 		};
@@ -246,7 +696,9 @@ pub fn install_chain(
 			.open_memory("outer_xdp_sock_prog", my_prog)
 			.map_err(|_| ChainInstallError::MissingEbpfEntry(chain_link.uuid))?;
 
-		let load_obj = obj.load().unwrap();
+		let mut load_obj = obj
+			.load()
+			.map_err(|e| ChainInstallError::Load(chain_link.uuid, e))?;
 		let fd = load_obj
 			.prog("outer_xdp_sock_prog")
 			.expect("Exists due to above open_memory.")
@@ -254,29 +706,49 @@ pub fn install_chain(
 
 		prog_fds.insert(chain_link.uuid, fd);
 
-		let mut my_maps = vec![];
+		// The maps of each fused NF are defined in this program too.
+		let prog_links: Vec<_> = [chain_link].into_iter().chain(nf_links).collect();
+		for (position, nf_link) in prog_links.iter().enumerate() {
+			let prefix = fused_map_prefix(position);
+			let mut my_maps = vec![];
+
+			for name in nf_link.map_names.iter() {
+				let code_name = format!("{prefix}{}", name.to_ascii_uppercase());
+				my_maps.push(unsafe {
+					RawMap::new(
+						load_obj
+							.map(&code_name)
+							.ok_or(ChainInstallError::MissingMap(nf_link.uuid, code_name))?,
+					)
+				});
+			}
 
-		for name in chain_link.map_names.iter() {
-			let code_name = name.to_ascii_uppercase();
-			my_maps.push(unsafe {
-				RawMap::new(
-					load_obj
-						.map(&code_name)
-						.ok_or(ChainInstallError::MissingMap(chain_link.uuid, code_name))?,
-				)
-			});
+			raw_maps.insert(nf_link.uuid, my_maps);
 		}
 
-		raw_maps.insert(chain_link.uuid, my_maps);
+		apply_map_entries(&mut load_obj, &prog_links)?;
+
+		// Programs are measured on a copy, so that test packets leave no state in
+		// the live program's maps, and before their tail calls are wired up, so
+		// only their own cost is counted.
+		if config.profile {
+			let xdp_ns = ObjectBuilder::default()
+				.open_memory("outer_xdp_sock_prog", my_prog)
+				.and_then(|obj| obj.load())
+				.ok()
+				.and_then(|mut copy| {
+					apply_map_entries(&mut copy, &prog_links).ok()?;
+					profile::test_run_ns(copy.prog("outer_xdp_sock_prog")?.fd())
+				});
+
+			ebpf_costs.insert(chain_link.uuid, (xdp_ns, profile::verified_insns(fd)));
+		}
 
-		linked_ebpfs.insert(chain_link.uuid, Prog::Unlinked(load_obj));
+		ebpfs.insert(chain_link.uuid, load_obj);
 	}
 
 	// Build and patch prog maps to include the right jumps
-	//for (uuid, prog) in linked_ebpfs.iter() {
-	for (uuid, prog) in linked_ebpfs.iter_mut() {
-		// let object = prog.object();
-		let object = prog.object_mut();
+	for (uuid, object) in ebpfs.iter() {
 		for map in object.maps_iter() {
 			println!(
 				"{} -- {}, {}, {}B per entry",
@@ -286,75 +758,77 @@ pub fn install_chain(
 				map.value_size()
 			);
 		}
-
-		// FIXME: left in as test code.
-		if let Some(map) = object.map_mut("BLOCKED_IPS") {
-			map.update(&[192, 168, 0, 69], &[1], MapFlags::ANY).unwrap();
-		}
-
-		if let Some(map) = object.map_mut("UPCALL_LIKELIHOOD") {
-			let likelihood = ((config.loadbalance_chance * (u32::MAX as f64)) as u32).to_ne_bytes();
-			map.update(&[0, 0, 0, 0], &likelihood, MapFlags::ANY)
-				.unwrap();
-		}
 	}
 
 	for (id, chain_link) in chain.links.iter().enumerate() {
 		link_states.insert(chain_link.uuid, chain_link.state.clone());
 
+		// Upcalls from a fused program are made on behalf of its last NF.
+		let (id, prog_link) = match chain_link.fused.last() {
+			Some(last) => chain
+				.links
+				.iter()
+				.enumerate()
+				.find(|(_, link)| link.uuid == *last)
+				.ok_or(ChainInstallError::BadNfLink(chain_link.uuid, *last))?,
+			None => (id, chain_link),
+		};
+
 		// Insert ID state for each program into it's eBPF maps, as needed.
-		if let XdpLinkState::Body(els) = &chain_link.state {
-			let prog = linked_ebpfs.get_mut(&chain_link.uuid);
+		if let XdpLinkState::Body(els) = &prog_link.state {
+			let obj = ebpfs.get_mut(&chain_link.uuid);
 
-			let prog = if let Some(a) = prog { a } else { continue };
+			let obj = if let Some(a) = obj { a } else { continue };
+
+			// Only XDP programs can upcall, so tc chains have no state or socket maps.
+			if chain.hook == Hook::Xdp {
+				// TODO: better ID assignment when I add in support for duplicate NFs?
+				let mut id_map =
+					obj.map_mut("my_state_map")
+						.ok_or(ChainInstallError::MissingMap(
+							chain_link.uuid,
+							"my_state_map".into(),
+						))?;
+
+				let state = DataplaneState {
+					prog_id: id as u32,
+					num_cores: config.xdp_cores.unwrap(),
+					steering: config.upcall_steering as u32,
+				};
 
-			let obj = prog.object_mut();
+				(&mut id_map).put(&0, &state);
+				// .update(
+				// 	&0_u32.to_le_bytes(),
+				// 	&(id as u32).to_le_bytes(),
+				// 	MapFlags::ANY,
+				// )
+				// .map_err(|e| {
+				// 	ChainInstallError::MapUpdateFail(chain_link.uuid, "my_id_map".into(), e)
+				// })?;
 
-			// TODO: better ID assignment when I add in support for duplicate NFs?
-			let mut id_map = obj
-				.map_mut("my_state_map")
-				.ok_or(ChainInstallError::MissingMap(
+				instance_ids.insert(id as u32, prog_link.uuid);
+
+				// TODO: bind and give XSK to all.
+				let xsk_map = obj.map_mut("xsk_map").ok_or(ChainInstallError::MissingMap(
 					chain_link.uuid,
-					"my_state_map".into(),
+					"xsk_map".into(),
 				))?;
 
-			let state = DataplaneState {
-				prog_id: id as u32,
-				num_cores: config.xdp_cores.unwrap(),
-			};
-
-			(&mut id_map).put(&0, &state);
-			// .update(
-			// 	&0_u32.to_le_bytes(),
-			// 	&(id as u32).to_le_bytes(),
-			// 	MapFlags::ANY,
-			// )
-			// .map_err(|e| {
-			// 	ChainInstallError::MapUpdateFail(chain_link.uuid, "my_id_map".into(), e)
-			// })?;
-
-			instance_ids.insert(id as u32, chain_link.uuid);
-
-			// TODO: bind and give XSK to all.
-			let xsk_map = obj.map_mut("xsk_map").ok_or(ChainInstallError::MissingMap(
-				chain_link.uuid,
-				"xsk_map".into(),
-			))?;
-
-			for (xsk_i, xsk) in xsks.iter().enumerate() {
-				xsk_map
-					.update(
-						&(xsk_i as u32).to_le_bytes(),
-						&(xsk.fd).to_le_bytes(),
-						MapFlags::ANY,
-					)
-					.map_err(|e| {
-						ChainInstallError::MapUpdateFail(
-							chain_link.uuid,
-							format!("xsk_map[{xsk_i}]"),
-							e,
+				for (xsk_i, xsk_fd) in xsk_fds.iter().enumerate() {
+					xsk_map
+						.update(
+							&(xsk_i as u32).to_le_bytes(),
+							&xsk_fd.to_le_bytes(),
+							MapFlags::ANY,
 						)
-					})?;
+						.map_err(|e| {
+							ChainInstallError::MapUpdateFail(
+								chain_link.uuid,
+								format!("xsk_map[{xsk_i}]"),
+								e,
+							)
+						})?;
+				}
 			}
 
 			for (i, action) in els.iter().enumerate() {
@@ -402,51 +876,564 @@ pub fn install_chain(
 						})?;
 				}
 			}
+
+			for (i, weight) in prog_link.weights.iter().enumerate() {
+				let split = obj
+					.map_mut("split_map")
+					.ok_or(ChainInstallError::MissingMap(
+						chain_link.uuid,
+						"split_map".into(),
+					))?;
+
+				split
+					.update(
+						&(i as u32).to_le_bytes(),
+						&weight.to_le_bytes(),
+						MapFlags::ANY,
+					)
+					.map_err(|e| {
+						ChainInstallError::MapUpdateFail(chain_link.uuid, "split_map".into(), e)
+					})?;
+			}
 		}
 	}
 
-	// Last step: link the true prog to the iface?
-	let root_idx = root_idx.ok_or(ChainInstallError::MissingRootNf)?;
-	if let Some(Prog::Unlinked(mut obj)) = linked_ebpfs.remove(&root_idx) {
-		let link = obj
-			.prog_mut("outer_xdp_sock_prog")
-			.expect("Already verified presence of this program.")
-			.attach_xdp(iface as i32)
-			.unwrap();
+	let root = root_idx.ok_or(ChainInstallError::MissingRootNf)?;
 
-		linked_ebpfs.insert(root_idx, Prog::Linked(obj, link));
-	} else {
-		unreachable!()
+	if !prog_fds.contains_key(&root) {
+		return Err(ChainInstallError::MissingEbpfPayload(root));
 	}
 
-	eprintln!("Chain linked and loaded -- packet mods should occur!");
+	eprintln!("Chain loaded.");
+
+	// The costs of a fused program are reported against the NF which owns it
+	// alone, as those of the NFs inlined into it cannot be told apart.
+	let profile = config.profile.then(|| {
+		chain
+			.nfs
+			.keys()
+			.map(|uuid| {
+				let (xdp_ns, verified_insns) =
+					ebpf_costs.get(uuid).copied().unwrap_or((None, None));
+
+				(*uuid, NfCounters::new(xdp_ns, verified_insns))
+			})
+			.collect()
+	});
 
 	Ok(ChainState {
-		linked_ebpfs,
+		ebpfs,
+		fused_into,
+		hook: chain.hook,
+		root,
 		prog_fds,
 		instance_ids,
 		link_states,
 		raw_maps,
+		split_weights,
+		mirrors,
+		profile,
 	})
 }
 
+/// The root NF of a live chain, attached to an interface at the chain's [`Hook`].
+///
+/// The program is detached when this is dropped.
+#[cfg(unix)]
+pub enum RootLink {
+	Xdp(Link),
+	Tc(TcFilter),
+}
+
+/// A `cls_bpf` filter on one hook of an interface's `clsact` qdisc.
+///
+/// The qdisc is created if needed, but left in place on drop as other programs
+/// may rely on it.
+#[cfg(unix)]
+pub struct TcFilter {
+	hook: Hook,
+	tc_hook: libbpf_sys::bpf_tc_hook,
+}
+
+#[cfg(unix)]
+impl TcFilter {
+	/// Handle and priority of pulley's filter, so that it can be replaced in place.
+	const HANDLE: u32 = 1;
+	const PRIORITY: u32 = 1;
+
+	fn new(ifindex: u32, hook: Hook) -> Result<Self, Errno> {
+		// SAFETY: all-zero is a valid (empty) `bpf_tc_hook`.
+		let mut tc_hook: libbpf_sys::bpf_tc_hook = unsafe { mem::zeroed() };
+		tc_hook.sz = mem::size_of::<libbpf_sys::bpf_tc_hook>() as _;
+		tc_hook.ifindex = ifindex as i32;
+		tc_hook.attach_point = match hook {
+			Hook::TcIngress => libbpf_sys::BPF_TC_INGRESS,
+			_ => libbpf_sys::BPF_TC_EGRESS,
+		};
+
+		let err = unsafe { libbpf_sys::bpf_tc_hook_create(&mut tc_hook) };
+		if err < 0 && Errno::from_i32(-err) != Errno::EEXIST {
+			return Err(Errno::from_i32(-err));
+		}
+
+		Ok(Self { hook, tc_hook })
+	}
+
+	fn opts(prog_fd: i32, flags: u32) -> libbpf_sys::bpf_tc_opts {
+		// SAFETY: all-zero is a valid (empty) `bpf_tc_opts`.
+		let mut opts: libbpf_sys::bpf_tc_opts = unsafe { mem::zeroed() };
+		opts.sz = mem::size_of::<libbpf_sys::bpf_tc_opts>() as _;
+		opts.handle = Self::HANDLE;
+		opts.priority = Self::PRIORITY;
+		opts.prog_fd = prog_fd;
+		opts.flags = flags;
+
+		opts
+	}
+
+	/// Attaches `prog_fd` as this filter's program, replacing any existing one.
+	fn attach(&self, prog_fd: i32) -> Result<(), Errno> {
+		let mut opts = Self::opts(prog_fd, libbpf_sys::BPF_TC_F_REPLACE);
+
+		let err = unsafe { libbpf_sys::bpf_tc_attach(&self.tc_hook, &mut opts) };
+		if err < 0 {
+			return Err(Errno::from_i32(-err));
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(unix)]
+impl Drop for TcFilter {
+	fn drop(&mut self) {
+		let opts = Self::opts(0, 0);
+
+		let _ = unsafe { libbpf_sys::bpf_tc_detach(&self.tc_hook, &opts) };
+	}
+}
+
+/// Finds the links of the NFs fused into `link`'s program, in order.
+#[cfg(unix)]
+fn fused_links<'a>(
+	chain: &'a Chain,
+	link: &XdpLink,
+) -> Result<Vec<&'a XdpLink>, ChainInstallError> {
+	link.fused
+		.iter()
+		.map(|member| {
+			chain
+				.links
+				.iter()
+				.find(|other| other.uuid == *member)
+				.ok_or(ChainInstallError::BadNfLink(link.uuid, *member))
+		})
+		.collect()
+}
+
+/// Writes the initial entries of every NF's maps into `obj`, whose program
+/// runs the NFs of `prog_links` in order.
+#[cfg(unix)]
+fn apply_map_entries(obj: &mut Object, prog_links: &[&XdpLink]) -> Result<(), ChainInstallError> {
+	for (position, nf_link) in prog_links.iter().enumerate() {
+		let prefix = fused_map_prefix(position);
+
+		for (name, entries) in &nf_link.map_entries {
+			let code_name = format!("{prefix}{}", name.to_ascii_uppercase());
+			let map = obj
+				.map_mut(&code_name)
+				.ok_or_else(|| ChainInstallError::MissingMap(nf_link.uuid, code_name.clone()))?;
+
+			for entry in entries {
+				let key = entry.key.to_ne_bytes();
+				let value = entry.value.to_ne_bytes();
+
+				if key.len() != map.key_size() as usize || value.len() != map.value_size() as usize
+				{
+					return Err(ChainInstallError::MapEntryLayout(nf_link.uuid, code_name));
+				}
+
+				map.update(&key, &value, MapFlags::ANY).map_err(|e| {
+					ChainInstallError::MapUpdateFail(nf_link.uuid, code_name.clone(), e)
+				})?;
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// Attaches the root NF of a freshly installed chain to the configured interface,
+/// at the chain's hook.
+#[cfg(unix)]
+pub fn attach_chain(chain: &mut ChainState, config: &Cli) -> Result<RootLink, ChainInstallError> {
+	// TODO: allow multiple rx + tx.
+	let iface_name = config.interface[0].clone();
+
+	let iface = nix::net::if_::if_nametoindex(iface_name.as_str())
+		.map_err(|e| ChainInstallError::IfaceLookup(iface_name.clone(), e))?;
+
+	let link = if chain.hook.is_tc() {
+		let filter = TcFilter::new(iface, chain.hook)
+			.and_then(|filter| filter.attach(chain.prog_fds[&chain.root]).map(|_| filter))
+			.map_err(|e| ChainInstallError::TcAttach(iface_name, e))?;
+
+		RootLink::Tc(filter)
+	} else {
+		let link = chain
+			.ebpfs
+			.get_mut(&chain.root)
+			.expect("Root presence verified by install_chain.")
+			.prog_mut("outer_xdp_sock_prog")
+			.expect("Already verified presence of this program.")
+			.attach_xdp(iface as i32)
+			.map_err(|e| ChainInstallError::Attach(iface_name, e))?;
+
+		RootLink::Xdp(link)
+	};
+
+	eprintln!("Chain linked and loaded -- packet mods should occur!");
+
+	Ok(link)
+}
+
+/// Atomically replaces the program behind an attached chain's `link` with the
+/// root NF of `chain`.
+///
+/// Packets already mid-chain finish on the old programs. Chains cannot be moved
+/// to another hook this way.
+#[cfg(unix)]
+pub fn swap_chain(link: &RootLink, chain: &ChainState) -> Result<(), ChainInstallError> {
+	let root_fd = chain.prog_fds[&chain.root];
+
+	match link {
+		RootLink::Xdp(link) if chain.hook == Hook::Xdp => {
+			let err = unsafe { libbpf_sys::bpf_link_update(link.fd(), root_fd, std::ptr::null()) };
+
+			if err < 0 {
+				return Err(ChainInstallError::RootSwap(Errno::last()));
+			}
+		},
+		RootLink::Tc(filter) if filter.hook == chain.hook => {
+			filter
+				.attach(root_fd)
+				.map_err(ChainInstallError::RootSwap)?;
+		},
+		_ => return Err(ChainInstallError::HookChanged(chain.hook)),
+	}
+
+	eprintln!("Swapped in updated chain.");
+
+	Ok(())
+}
+
+/// Applies a map update from chainsmith to the maps of a live chain.
+///
+/// Entries are changed one at a time, so if an update fails then any earlier
+/// entries in it remain applied.
+#[cfg(unix)]
+pub fn apply_map_update(chain: &ChainState, update: &MapUpdate) -> Result<(), MapUpdateError> {
+	if update.map == SPLIT_WEIGHTS_MAP {
+		return apply_split_update(chain, update);
+	}
+
+	let (owner, position) = chain
+		.fused_into
+		.get(&update.nf)
+		.copied()
+		.unwrap_or((update.nf, 0));
+	let code_name = format!(
+		"{}{}",
+		fused_map_prefix(position),
+		update.map.to_ascii_uppercase()
+	);
+	// NF maps are defined in (and loaded with) eBPF programs, so NFs without one
+	// have no maps at all.
+	let map = chain
+		.ebpfs
+		.get(&owner)
+		.ok_or(if chain.link_states.contains_key(&update.nf) {
+			MapUpdateError::UserlandOnly(update.nf)
+		} else {
+			MapUpdateError::MissingNf(update.nf)
+		})?
+		.map(&code_name)
+		.ok_or_else(|| MapUpdateError::MissingMap(update.nf, code_name.clone()))?;
+
+	let fd = map.fd();
+	let key_size = map.key_size() as usize;
+	let value_size = map.value_size() as usize;
+
+	let to_bytes = |datum: &MapDatum, size: usize| {
+		let bytes = datum.to_ne_bytes();
+		if bytes.len() == size {
+			Ok(bytes)
+		} else {
+			Err(MapUpdateError::Layout(code_name.clone()))
+		}
+	};
+
+	let syscall_err = |e: Errno| MapUpdateError::Syscall(code_name.clone(), e);
+
+	let put = |entries: &[MapEntry], flags: MapFlags| -> Result<(), MapUpdateError> {
+		for entry in entries {
+			let key = to_bytes(&entry.key, key_size)?;
+			let value = to_bytes(&entry.value, value_size)?;
+			raw_map_update(fd, &key, &value, flags).map_err(syscall_err)?;
+		}
+
+		Ok(())
+	};
+
+	match &update.op {
+		MapOp::Insert(entries) => put(entries, MapFlags::NO_EXIST),
+		MapOp::Update(entries) => put(entries, MapFlags::EXIST),
+		MapOp::Delete(keys) => {
+			for key in keys {
+				raw_map_delete(fd, &to_bytes(key, key_size)?, value_size).map_err(syscall_err)?;
+			}
+
+			Ok(())
+		},
+		MapOp::Replace(entries) => {
+			// Write the new entries first, so that kept keys never go missing.
+			put(entries, MapFlags::ANY)?;
+
+			let mut keep = HashSet::new();
+			for entry in entries {
+				keep.insert(to_bytes(&entry.key, key_size)?);
+			}
+
+			for key in raw_map_keys(fd, key_size) {
+				if !keep.contains(&key) {
+					raw_map_delete(fd, &key, value_size).map_err(syscall_err)?;
+				}
+			}
+
+			Ok(())
+		},
+	}
+}
+
+/// Applies an update to the branch weights of a split link, both in userland and
+/// in the eBPF program (if any) which takes that link.
+///
+/// Every branch always has a weight, so inserts behave as updates, and deletes
+/// set a branch's weight to 0.
+#[cfg(unix)]
+fn apply_split_update(chain: &ChainState, update: &MapUpdate) -> Result<(), MapUpdateError> {
+	let weights = chain
+		.split_weights
+		.get(&update.nf)
+		.ok_or_else(|| MapUpdateError::MissingMap(update.nf, SPLIT_WEIGHTS_MAP.into()))?;
+
+	let layout_err = || MapUpdateError::Layout(SPLIT_WEIGHTS_MAP.into());
+	let index = |datum: &MapDatum| match datum {
+		MapDatum::U32(i) if (*i as usize) < weights.len() => Ok(*i as usize),
+		_ => Err(layout_err()),
+	};
+	let weight = |datum: &MapDatum| match datum {
+		MapDatum::U32(w) => Ok(*w),
+		_ => Err(layout_err()),
+	};
+
+	// Check the whole update before applying any of it.
+	let mut new: Vec<u32> = weights.iter().map(|w| w.load(Ordering::Relaxed)).collect();
+	match &update.op {
+		MapOp::Insert(entries) | MapOp::Update(entries) =>
+			for entry in entries {
+				new[index(&entry.key)?] = weight(&entry.value)?;
+			},
+		MapOp::Delete(keys) =>
+			for key in keys {
+				new[index(key)?] = 0;
+			},
+		MapOp::Replace(entries) => {
+			new.fill(0);
+			for entry in entries {
+				new[index(&entry.key)?] = weight(&entry.value)?;
+			}
+		},
+	}
+
+	// Branches are picked modulo the total weight, which must not wrap.
+	if new
+		.iter()
+		.try_fold(0u32, |acc, w| acc.checked_add(*w))
+		.is_none()
+	{
+		return Err(MapUpdateError::WeightTotal(update.nf));
+	}
+
+	for (atomic, w) in weights.iter().zip(&new) {
+		atomic.store(*w, Ordering::Relaxed);
+	}
+
+	let (owner, _) = chain
+		.fused_into
+		.get(&update.nf)
+		.copied()
+		.unwrap_or((update.nf, 0));
+
+	if let Some(map) = chain.ebpfs.get(&owner).and_then(|obj| obj.map("split_map")) {
+		for (i, w) in new.iter().enumerate() {
+			raw_map_update(
+				map.fd(),
+				&(i as u32).to_ne_bytes(),
+				&w.to_ne_bytes(),
+				MapFlags::ANY,
+			)
+			.map_err(|e| MapUpdateError::Syscall("split_map".into(), e))?;
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(unix)]
+fn raw_map_update(fd: RawFd, key: &[u8], value: &[u8], flags: MapFlags) -> Result<(), Errno> {
+	let err = unsafe {
+		libbpf_sys::bpf_map_update_elem(
+			fd,
+			key.as_ptr() as *const c_void,
+			value.as_ptr() as *const c_void,
+			flags.bits(),
+		)
+	};
+
+	if err < 0 {
+		Err(Errno::last())
+	} else {
+		Ok(())
+	}
+}
+
+/// Removes `key` from a map, or zeroes its value if the map (e.g., an array)
+/// does not support deletion.
+#[cfg(unix)]
+fn raw_map_delete(fd: RawFd, key: &[u8], value_size: usize) -> Result<(), Errno> {
+	let err = unsafe { libbpf_sys::bpf_map_delete_elem(fd, key.as_ptr() as *const c_void) };
+
+	if err >= 0 {
+		return Ok(());
+	}
+
+	match Errno::last() {
+		Errno::EINVAL => raw_map_update(fd, key, &vec![0; value_size], MapFlags::EXIST),
+		errno => Err(errno),
+	}
+}
+
+#[cfg(unix)]
+fn raw_map_keys(fd: RawFd, key_size: usize) -> Vec<Vec<u8>> {
+	let mut out: Vec<Vec<u8>> = vec![];
+
+	loop {
+		let prev = out
+			.last()
+			.map(|k| k.as_ptr() as *const c_void)
+			.unwrap_or(std::ptr::null());
+		let mut next = vec![0; key_size];
+
+		let err =
+			unsafe { libbpf_sys::bpf_map_get_next_key(fd, prev, next.as_mut_ptr() as *mut c_void) };
+
+		if err < 0 {
+			break;
+		}
+
+		out.push(next);
+	}
+
+	out
+}
+
 pub type ProgId = u32;
 
+/// Bytes preceding each upcalled packet: its source program ID and action,
+/// followed by its [`nf::meta`] area.
+pub const UPCALL_HEADROOM: usize =
+	core::mem::size_of::<ProgId>() + core::mem::size_of::<u32>() + nf::meta::META_LEN;
+
+/// Splits a record read from an NF's [`MIRROR_MAP`] into the mirrored packet
+/// and its [`nf::meta`] area.
+///
+/// Returns `None` if the record is too short to hold the packet it describes.
+#[cfg(unix)]
+pub fn mirror_sample(data: &[u8]) -> Option<(&[u8], &[u8])> {
+	const LEN_LEN: usize = mem::size_of::<u32>();
+	const BODY_START: usize = LEN_LEN + nf::meta::META_LEN;
+
+	let len = u32::from_ne_bytes(data.get(..LEN_LEN)?.try_into().ok()?) as usize;
+	let meta = data.get(LEN_LEN..BODY_START)?;
+
+	// Perf records are padded, so may run past the end of the packet.
+	let body = data.get(BODY_START..BODY_START.checked_add(len)?)?;
+
+	Some((body, meta))
+}
+
 #[repr(C)]
 struct DataplaneState {
 	prog_id: ProgId,
 	num_cores: u32,
+	steering: u32,
 }
 
 #[cfg(unix)]
 #[derive(WrapperApi)]
 pub struct NfUserApi {
-	user_nf_program: fn(pkt: &mut [u8], maps: &mut [RawMap]) -> usize,
+	user_nf_program: fn(pkt: &mut [u8], meta: &mut [u8], maps: &mut [RawMap]) -> usize,
+}
+
+#[cfg(unix)]
+#[derive(WrapperApi)]
+#[allow(clippy::type_complexity)]
+pub struct ChainUserApi {
+	user_chain_program:
+		fn(nf: usize, pkt: &mut [u8], meta: &mut [u8], maps: &mut [Vec<RawMap>]) -> bool,
+}
+
+/// A chain's userland NFs, linked into one library which dispatches
+/// between them itself.
+#[cfg(unix)]
+pub struct ChainProgram {
+	pub program: Uuid,
+	lib: Container<ChainUserApi>,
+	nfs: Vec<Uuid>,
+	indices: HashMap<Uuid, usize>,
+}
+
+#[cfg(unix)]
+impl ChainProgram {
+	/// Runs the chain from `nf` onward, returning whether `pkt` should be sent.
+	#[inline(always)]
+	pub fn run(&self, nf: Uuid, pkt: &mut [u8], meta: &mut [u8], maps: &mut [Vec<RawMap>]) -> bool {
+		match self.indices.get(&nf) {
+			Some(i) => self.lib.user_chain_program(*i, pkt, meta, maps),
+			None => false,
+		}
+	}
+
+	/// Orders each NF's maps by its index in the library.
+	pub fn maps(&self, map_hax: &MapHaxType) -> ChainMapsType {
+		self.nfs
+			.iter()
+			.map(|uuid| map_hax.get(uuid).cloned().unwrap_or_default())
+			.collect()
+	}
 }
 
 pub struct ChainState {
 	#[cfg(unix)]
-	pub linked_ebpfs: HashMap<Uuid, Prog>,
+	pub ebpfs: HashMap<Uuid, Object>,
+	/// The NF whose program each fused NF is inlined into, and its position there.
+	#[cfg(unix)]
+	pub fused_into: HashMap<Uuid, (Uuid, usize)>,
+	#[cfg(unix)]
+	pub hook: Hook,
+	#[cfg(unix)]
+	pub root: Uuid,
 	#[cfg(unix)]
 	pub prog_fds: HashMap<Uuid, i32>,
 	#[cfg(unix)]
@@ -455,17 +1442,110 @@ pub struct ChainState {
 	pub link_states: HashMap<Uuid, XdpLinkState>,
 	#[cfg(unix)]
 	pub raw_maps: HashMap<Uuid, Vec<RawMap>>,
+	/// Current branch weights of each NF whose link splits traffic.
+	#[cfg(unix)]
+	pub split_weights: HashMap<Uuid, Vec<AtomicU32>>,
+	/// Observer NFs sent copies of packets leaving each NF, if any.
+	#[cfg(unix)]
+	pub mirrors: HashMap<Uuid, Mirror>,
+	/// Costs measured for each NF, if run with `--profile`.
+	#[cfg(unix)]
+	pub profile: Option<HashMap<Uuid, NfCounters>>,
+}
+
+#[cfg(unix)]
+impl ChainState {
+	/// Picks a branch of `nf`'s split link at random, in proportion to each's weight.
+	///
+	/// Returns `None` if `nf`'s link does not split, or no branch has any weight.
+	pub fn split_branch(&self, nf: &Uuid) -> Option<u32> {
+		let weights = self.split_weights.get(nf)?;
+		// Weights are checked to total at most `u32::MAX` when set, but may be
+		// read mid-update.
+		let total = weights
+			.iter()
+			.fold(0u32, |acc, w| acc.wrapping_add(w.load(Ordering::Relaxed)));
+
+		if total == 0 {
+			return None;
+		}
+
+		let mut pick = nf::random::random_u32() % total;
+		for (i, weight) in weights.iter().enumerate() {
+			let weight = weight.load(Ordering::Relaxed);
+			if pick < weight {
+				return Some(i as u32);
+			}
+			pick -= weight;
+		}
+
+		None
+	}
+
+	/// Returns the mirror NF to which a packet leaving `nf` should be copied,
+	/// if it is sampled.
+	pub fn mirror_target(&self, nf: &Uuid) -> Option<Uuid> {
+		let mirror = self.mirrors.get(nf)?;
+
+		(mirror.sample == u32::MAX || nf::random::random_u32() < mirror.sample).then_some(mirror.nf)
+	}
+
+	/// Returns the perf event array through which each mirror NF is sent
+	/// copies of packets by the eBPF program of the NF it observes.
+	pub fn mirror_maps(&self) -> Vec<(Uuid, &Map)> {
+		self.mirrors
+			.iter()
+			.filter_map(|(nf, mirror)| {
+				// Fused NFs mirror through the program they are inlined into.
+				let owner = self.fused_into.get(nf).map_or(nf, |(owner, _)| owner);
+				let map = self.ebpfs.get(owner)?.map(MIRROR_MAP)?;
+
+				Some((mirror.nf, map))
+			})
+			.collect()
+	}
+
+	/// Returns the cost counters of `nf`, if profiling.
+	#[inline(always)]
+	pub fn counters(&self, nf: &Uuid) -> Option<&NfCounters> {
+		self.profile.as_ref()?.get(nf)
+	}
+
+	/// Reads the costs measured so far for every NF, if profiling.
+	pub fn profile(&self) -> Option<ChainProfile> {
+		let nfs = self
+			.profile
+			.as_ref()?
+			.iter()
+			.map(|(uuid, counters)| counters.snapshot(*uuid))
+			.collect();
+
+		Some(ChainProfile { nfs })
+	}
 }
 
 #[cfg(unix)]
 pub type MapHaxType = HashMap<Uuid, Vec<RawMap>>;
 
+#[cfg(unix)]
+pub type ChainMapsType = Vec<Vec<RawMap>>;
+
 unsafe impl Send for ChainState {}
 unsafe impl Sync for ChainState {}
 
+/// Control messages sent from the control plane to each dataplane thread.
+#[derive(Clone)]
+pub enum DataplaneCtl {
+	Stop,
+	/// Handle all further upcalls using a newly installed chain.
+	Swap(Arc<ChainState>, Arc<DylibStore>),
+}
+
 pub struct DylibStore {
 	#[cfg(unix)]
-	pub dylibs: HashMap<Uuid, Container<NfUserApi>>,
+	pub dylibs: HashMap<Uuid, Arc<Container<NfUserApi>>>,
+	#[cfg(unix)]
+	pub chain_program: Option<Arc<ChainProgram>>,
 	pub temp_path: PathBuf,
 }
 
@@ -477,6 +1557,8 @@ impl DylibStore {
 		Ok(Self {
 			#[cfg(unix)]
 			dylibs: HashMap::new(),
+			#[cfg(unix)]
+			chain_program: None,
 			temp_path,
 		})
 	}
@@ -492,21 +1574,145 @@ impl DylibStore {
 		Ok(())
 	}
 
+	/// Creates the store for a replacement `chain`, reusing any libraries
+	/// already loaded here and dropping those which are no longer needed.
+	pub async fn successor(&self, chain: &Chain) -> Result<Self, IoError> {
+		let mut out = Self {
+			#[cfg(unix)]
+			dylibs: self
+				.dylibs
+				.iter()
+				.filter(|(uuid, _)| chain.nfs.contains_key(uuid))
+				.map(|(uuid, dll)| (*uuid, dll.clone()))
+				.collect(),
+			#[cfg(unix)]
+			chain_program: self
+				.chain_program
+				.as_ref()
+				.filter(|prog| chain.userland.as_ref().map(|u| u.program) == Some(prog.program))
+				.cloned(),
+			temp_path: self.temp_path.clone(),
+		};
+
+		out.load_dylib_nfs(chain).await?;
+
+		Ok(out)
+	}
+
 	#[cfg(unix)]
 	pub async fn load_dylib_nfs(&mut self, chain: &Chain) -> Result<(), IoError> {
-		let elf_path = tempfile::tempdir()?.into_path();
+		let chain_program = chain.userland.as_ref().map(|u| u.program);
 
 		for (uuid, nf) in &chain.nfs {
+			// NF IDs are content-derived: a known ID means identical code is loaded.
+			if self.dylibs.contains_key(uuid) || Some(*uuid) == chain_program {
+				continue;
+			}
+
 			if let Some(elf) = &nf.elf {
-				let fs_path = elf_path.join(format!("{uuid}"));
+				let fs_path = self.temp_path.join(format!("{uuid}"));
 				tokio::fs::write(&fs_path, elf).await?;
 
 				let dll: Container<NfUserApi> = unsafe { Container::load(fs_path).unwrap() };
 
-				self.dylibs.insert(*uuid, dll);
+				self.dylibs.insert(*uuid, Arc::new(dll));
 			}
 		}
 
+		self.load_chain_program(chain).await
+	}
+
+	#[cfg(unix)]
+	async fn load_chain_program(&mut self, chain: &Chain) -> Result<(), IoError> {
+		let userland = match &chain.userland {
+			Some(userland) => userland,
+			None => {
+				self.chain_program = None;
+				return Ok(());
+			},
+		};
+
+		if self.chain_program.is_some() {
+			return Ok(());
+		}
+
+		if let Some(elf) = chain
+			.nfs
+			.get(&userland.program)
+			.and_then(|nf| nf.elf.as_ref())
+		{
+			let fs_path = self.temp_path.join(format!("{}", userland.program));
+			tokio::fs::write(&fs_path, elf).await?;
+
+			let lib: Container<ChainUserApi> = unsafe { Container::load(fs_path).unwrap() };
+
+			self.chain_program = Some(Arc::new(ChainProgram {
+				program: userland.program,
+				lib,
+				nfs: userland.nfs.clone(),
+				indices: userland
+					.nfs
+					.iter()
+					.enumerate()
+					.map(|(i, uuid)| (*uuid, i))
+					.collect(),
+			}));
+		}
+
 		Ok(())
 	}
+
+	/// Each NF's maps, ordered for this chain's fused userland program (if any).
+	#[cfg(unix)]
+	pub fn chain_maps(&self, map_hax: &MapHaxType) -> ChainMapsType {
+		self.chain_program
+			.as_ref()
+			.map(|prog| prog.maps(map_hax))
+			.unwrap_or_default()
+	}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use nf::meta::META_LEN;
+
+	use super::*;
+
+	/// A mirror record declaring a `len`-byte packet, holding `body` after its
+	/// metadata.
+	fn record(len: u32, meta: &[u8], body: &[u8]) -> Vec<u8> {
+		let mut out = len.to_ne_bytes().to_vec();
+		out.extend_from_slice(meta);
+		out.extend_from_slice(body);
+		out
+	}
+
+	#[test]
+	fn mirror_sample_splits_body_and_meta() {
+		let meta: Vec<u8> = (0..META_LEN as u8).collect();
+		// Perf records are padded past the end of the packet.
+		let data = record(3, &meta, &[7, 8, 9, 0, 0]);
+
+		let (body, sample_meta) = mirror_sample(&data).unwrap();
+		assert_eq!(body, [7, 8, 9]);
+		assert_eq!(sample_meta, meta);
+	}
+
+	#[test]
+	fn mirror_sample_rejects_truncated_records() {
+		let meta = [0u8; META_LEN];
+
+		assert!(mirror_sample(&[]).is_none());
+		assert!(mirror_sample(&1u32.to_ne_bytes()[..2]).is_none());
+		assert!(mirror_sample(&record(0, &meta[..META_LEN - 1], &[])).is_none());
+		assert!(mirror_sample(&record(0, &meta, &[])).is_some());
+	}
+
+	#[test]
+	fn mirror_sample_rejects_overlong_lengths() {
+		let meta = [0u8; META_LEN];
+
+		assert!(mirror_sample(&record(4, &meta, &[1, 2, 3])).is_none());
+		assert!(mirror_sample(&record(u32::MAX, &meta, &[1, 2, 3])).is_none());
+	}
 }
@@ -1,25 +1,40 @@
+#[cfg(unix)]
+use std::cell::RefCell;
 use std::{
 	num::NonZeroUsize,
 	sync::{
 		mpsc::{self, TryRecvError},
 		Arc,
 	},
-	time::Duration,
+	time::{Duration, Instant},
 };
 
 #[cfg(unix)]
 use bus::BusReader;
 use clap::Parser;
 use crossbeam_channel::RecvTimeoutError;
+#[cfg(unix)]
+use libbpf_rs::PerfBufferBuilder;
+use protocol::ChainProfile;
+#[cfg(unix)]
+use protocol::ServerToClient;
+#[cfg(unix)]
+use pulley::{apply_map_update, attach_chain, install_chain, swap_chain, RootLink};
 use pulley::{
 	config::{Cli, UmemDisposalMode},
+	error::ChainGetError,
+	ChainSession,
+	DataplaneCtl,
 	DylibStore,
 	ProgId,
+	UPCALL_HEADROOM,
 };
 #[cfg(unix)]
-use pulley::{ChainState, MapHaxType, UmemMediate, XskData};
+use pulley::{ChainMapsType, ChainState, MapHaxType, UmemMediate, XskData};
 use ringbuf::{HeapConsumer, HeapProducer, SharedRb};
 #[cfg(unix)]
+use uuid::Uuid;
+#[cfg(unix)]
 use xsk_rs::umem::frame::FrameDesc;
 
 #[tokio::main]
@@ -42,17 +57,32 @@ async fn main() -> anyhow::Result<()> {
 
 	let xdp_ct = *xdp_ct;
 
-	let chain = pulley::get_chain(config.server_addr.as_str()).await?;
+	let (chain, mut session) = match &config.bundle {
+		Some(path) => (pulley::load_bundle(path).await?, None),
+		None => {
+			let mut session = ChainSession::connect(config.server_addr.as_str()).await?;
+			session.request_chain(config.chain.as_deref()).await?;
+			let chain = session.next_chain().await?;
+
+			(chain, Some(session))
+		},
+	};
 
 	// TODO: move some expects from install_chain into a Chain::verify -> VerifyError?
 
+	pulley::verify_chain(&chain, &config)?;
+
 	let mut xsks = pulley::create_upcall_sockets(&config);
-	let g_live_fds = Arc::new(pulley::install_chain(&chain, &config, &xsks)?);
+	let xsk_fds: Vec<_> = xsks.iter().map(|xsk| xsk.fd).collect();
+
+	let mut live_chain = install_chain(&chain, &config, &xsk_fds)?;
+	let root_link = attach_chain(&mut live_chain, &config)?;
+	let mut g_live_fds = Arc::new(live_chain);
 
 	let mut dylibs = DylibStore::new().await?;
 	dylibs.load_dylib_nfs(&chain).await?;
 
-	let g_dylibs = Arc::new(dylibs);
+	let mut g_dylibs = Arc::new(dylibs);
 
 	let mut bus = bus::Bus::new(xsks.len());
 
@@ -109,8 +139,9 @@ async fn main() -> anyhow::Result<()> {
 					handled += additional_pkts;
 				}
 
-				if rx.try_recv().is_ok() {
-					break;
+				match rx.try_recv() {
+					Ok(DataplaneCtl::Stop) | Err(TryRecvError::Disconnected) => break,
+					_ => {},
 				}
 
 				if handled == 0 {
@@ -132,10 +163,9 @@ async fn main() -> anyhow::Result<()> {
 	#[cfg(unix)]
 	for (t_id, mut xsk) in xsks.drain(..).enumerate() {
 		let dylibs = g_dylibs.clone();
-		let mut kill_rx = bus.add_rx();
+		let mut ctl_rx = bus.add_rx();
 
 		let live_fds = g_live_fds.clone();
-		let mut map_hax = live_fds.raw_maps.clone();
 
 		let maybe_rx_set = ring_receivers.take();
 		let maybe_sender = if config.umem_mode == UmemDisposalMode::ExtraThread || t_id != 0 {
@@ -153,7 +183,7 @@ async fn main() -> anyhow::Result<()> {
 					xsk,
 					live_fds,
 					dylibs,
-					kill_rx,
+					ctl_rx,
 					config.upcall_poll_timeout,
 					sender,
 				);
@@ -166,7 +196,7 @@ async fn main() -> anyhow::Result<()> {
 					xsk,
 					live_fds,
 					dylibs,
-					kill_rx,
+					ctl_rx,
 					config.upcall_poll_timeout,
 					mediate,
 				);
@@ -181,7 +211,7 @@ async fn main() -> anyhow::Result<()> {
 					xsk,
 					live_fds,
 					dylibs,
-					kill_rx,
+					ctl_rx,
 					config.upcall_poll_timeout,
 					mediate,
 					receivers,
@@ -190,36 +220,172 @@ async fn main() -> anyhow::Result<()> {
 		});
 	}
 
+	// Mirror NFs are run on their own thread, on copies sent by eBPF.
+	#[cfg(unix)]
+	{
+		let mut chain = g_live_fds.clone();
+		let mut dylibs = g_dylibs.clone();
+		let mut ctl_rx = bus.add_rx();
+		let timeout = config.upcall_poll_timeout;
+
+		let _hangup = std::thread::spawn(move || {
+			while let Some((new_chain, new_dylibs)) =
+				mirror_observer(&chain, &dylibs, &mut ctl_rx, timeout)
+			{
+				chain = new_chain;
+				dylibs = new_dylibs;
+			}
+		});
+	}
+
+	let mut profile_timer =
+		tokio::time::interval(Duration::from_secs(config.profile_interval.max(1)));
+
 	println!("Press ctrl+c to exit.");
-	tokio::signal::ctrl_c().await?;
+	loop {
+		tokio::select! {
+			res = tokio::signal::ctrl_c() => {
+				res?;
+				break;
+			},
+			_ = profile_timer.tick(), if config.profile => {
+				if let Some(profile) = g_live_fds.profile() {
+					match session.as_mut().filter(|session| session.accepts_profiles()) {
+						Some(session) =>
+							if let Err(e) = session.report_profile(profile).await {
+								eprintln!("Failed to report profile: {e}");
+							},
+						None => print_profile(&profile),
+					}
+				}
+			},
+			msg = next_message(&mut session), if session.is_some() => match msg {
+				Ok(ServerToClient::Chain(chain)) => {
+					let reloaded =
+						reload_chain(&chain, &config, &xsk_fds, &root_link, &g_dylibs).await;
+
+					match reloaded {
+						Ok((live_fds, dylibs)) => {
+							g_live_fds = live_fds.clone();
+							g_dylibs = dylibs.clone();
+							bus.broadcast(DataplaneCtl::Swap(live_fds, dylibs));
+						},
+						Err(e) => eprintln!("Failed to install updated chain, keeping old: {e:?}"),
+					}
+				},
+				Ok(ServerToClient::MapUpdate { id, update }) => {
+					let result = apply_map_update(&g_live_fds, &update)
+						.map_err(|e| e.to_string());
+
+					if let Err(e) = &result {
+						eprintln!("Failed to apply map update to {}: {e}", update.map);
+					}
+
+					if let Some(session) = session.as_mut() {
+						if let Err(e) = session.ack_map_update(id, result).await {
+							eprintln!("Failed to acknowledge map update: {e}");
+						}
+					}
+				},
+				Ok(_) => {},
+				Err(e) => {
+					eprintln!("Lost connection to chain server, no further updates: {e}");
+					session = None;
+				},
+			},
+		}
+	}
 
-	let _ = bus.broadcast(());
+	bus.broadcast(DataplaneCtl::Stop);
 
 	g_dylibs.cleanup().await?;
 
 	Ok(())
 }
 
+/// Prints the costs measured for each NF, for clients without a server to
+/// report them to.
+fn print_profile(profile: &ChainProfile) {
+	println!("NF profile:");
+	for nf in &profile.nfs {
+		let xdp_ns = nf.xdp_ns.map_or("-".into(), |ns| ns.to_string());
+		let insns = nf.verified_insns.map_or("-".into(), |n| n.to_string());
+		let user_ns = nf
+			.user_ns_per_packet()
+			.map_or("-".into(), |ns| ns.to_string());
+
+		println!(
+			"\t{}: eBPF {xdp_ns}ns ({insns} insns verified), userland {user_ns}ns/pkt over {} pkts, {} upcalls",
+			nf.nf, nf.user_packets, nf.upcalls,
+		);
+	}
+}
+
+/// Waits for the next message from the chain server, if connected to one.
+async fn next_message(session: &mut Option<ChainSession>) -> Result<ServerToClient, ChainGetError> {
+	match session {
+		Some(session) => session.next_message().await,
+		None => std::future::pending().await,
+	}
+}
+
+#[cfg(unix)]
+/// Installs an updated chain alongside the live one, then atomically swaps it onto
+/// the interface.
+///
+/// Dataplane threads must be told to use the returned state afterwards.
+async fn reload_chain(
+	chain: &protocol::Chain,
+	config: &Cli,
+	xsk_fds: &[i32],
+	root_link: &RootLink,
+	dylibs: &DylibStore,
+) -> anyhow::Result<(Arc<ChainState>, Arc<DylibStore>)> {
+	pulley::verify_chain(chain, config)?;
+
+	// Userland NFs must be ready before new upcalls can reach them.
+	let dylibs = dylibs.successor(chain).await?;
+	let live_fds = install_chain(chain, config, xsk_fds)?;
+
+	swap_chain(root_link, &live_fds)?;
+
+	Ok((Arc::new(live_fds), Arc::new(dylibs)))
+}
+
 // Dataplane impls below are done to remove conditionals from main loop.
 
 #[cfg(unix)]
 /// Use if xdp cores == 1 and self.
 fn dataplane_self_mediate_solo(
 	mut xsk: XskData,
-	chain: Arc<ChainState>,
-	dylibs: Arc<DylibStore>,
-	mut kill_rx: BusReader<()>,
+	mut chain: Arc<ChainState>,
+	mut dylibs: Arc<DylibStore>,
+	mut ctl_rx: BusReader<DataplaneCtl>,
 	timeout: usize,
 	mut mediate: UmemMediate,
 ) {
 	let mut map_hax = chain.raw_maps.clone();
+	let mut chain_maps = dylibs.chain_maps(&map_hax);
 	loop {
-		match kill_rx.try_recv() {
-			Ok(()) | Err(TryRecvError::Disconnected) => break,
+		match ctl_rx.try_recv() {
+			Ok(DataplaneCtl::Stop) | Err(TryRecvError::Disconnected) => break,
+			Ok(DataplaneCtl::Swap(new_chain, new_dylibs)) => {
+				chain = new_chain;
+				dylibs = new_dylibs;
+				map_hax = chain.raw_maps.clone();
+				chain_maps = dylibs.chain_maps(&map_hax);
+			},
 			_ => {},
 		}
 
-		let (num_tx, pkts_recvd) = dataplane_core(&mut xsk, &chain, &dylibs, timeout, &mut map_hax);
+		let (num_tx, pkts_recvd) = dataplane_core(
+			&mut xsk,
+			&chain,
+			&dylibs,
+			timeout,
+			&mut map_hax,
+			&mut chain_maps,
+		);
 
 		// How to handle decisions?
 		// tx all descs in `descs[..num_tx]`
@@ -244,21 +410,35 @@ fn dataplane_self_mediate_solo(
 #[cfg(unix)]
 fn dataplane_self_mediate(
 	mut xsk: XskData,
-	chain: Arc<ChainState>,
-	dylibs: Arc<DylibStore>,
-	mut kill_rx: BusReader<()>,
+	mut chain: Arc<ChainState>,
+	mut dylibs: Arc<DylibStore>,
+	mut ctl_rx: BusReader<DataplaneCtl>,
 	timeout: usize,
 	mut mediate: UmemMediate,
 	mut remote_descs: Vec<HeapConsumer<FrameDesc>>,
 ) {
 	let mut map_hax = chain.raw_maps.clone();
+	let mut chain_maps = dylibs.chain_maps(&map_hax);
 	loop {
-		match kill_rx.try_recv() {
-			Ok(()) | Err(TryRecvError::Disconnected) => break,
+		match ctl_rx.try_recv() {
+			Ok(DataplaneCtl::Stop) | Err(TryRecvError::Disconnected) => break,
+			Ok(DataplaneCtl::Swap(new_chain, new_dylibs)) => {
+				chain = new_chain;
+				dylibs = new_dylibs;
+				map_hax = chain.raw_maps.clone();
+				chain_maps = dylibs.chain_maps(&map_hax);
+			},
 			_ => {},
 		}
 
-		let (num_tx, pkts_recvd) = dataplane_core(&mut xsk, &chain, &dylibs, timeout, &mut map_hax);
+		let (num_tx, pkts_recvd) = dataplane_core(
+			&mut xsk,
+			&chain,
+			&dylibs,
+			timeout,
+			&mut map_hax,
+			&mut chain_maps,
+		);
 
 		// How to handle decisions?
 		// tx all descs in `descs[..num_tx]`
@@ -299,20 +479,34 @@ fn dataplane_self_mediate(
 #[cfg(unix)]
 fn dataplane_other_mediate(
 	mut xsk: XskData,
-	chain: Arc<ChainState>,
-	dylibs: Arc<DylibStore>,
-	mut kill_rx: BusReader<()>,
+	mut chain: Arc<ChainState>,
+	mut dylibs: Arc<DylibStore>,
+	mut ctl_rx: BusReader<DataplaneCtl>,
 	timeout: usize,
 	mut fd_sender: HeapProducer<FrameDesc>,
 ) {
 	let mut map_hax = chain.raw_maps.clone();
+	let mut chain_maps = dylibs.chain_maps(&map_hax);
 	loop {
-		match kill_rx.try_recv() {
-			Ok(()) | Err(TryRecvError::Disconnected) => break,
+		match ctl_rx.try_recv() {
+			Ok(DataplaneCtl::Stop) | Err(TryRecvError::Disconnected) => break,
+			Ok(DataplaneCtl::Swap(new_chain, new_dylibs)) => {
+				chain = new_chain;
+				dylibs = new_dylibs;
+				map_hax = chain.raw_maps.clone();
+				chain_maps = dylibs.chain_maps(&map_hax);
+			},
 			_ => {},
 		}
 
-		let (num_tx, pkts_recvd) = dataplane_core(&mut xsk, &chain, &dylibs, timeout, &mut map_hax);
+		let (num_tx, pkts_recvd) = dataplane_core(
+			&mut xsk,
+			&chain,
+			&dylibs,
+			timeout,
+			&mut map_hax,
+			&mut chain_maps,
+		);
 
 		// How to handle decisions?
 		// tx all descs in `descs[..num_tx]`
@@ -336,6 +530,7 @@ fn dataplane_core(
 	dylibs: &Arc<DylibStore>,
 	timeout: usize,
 	map_hax: &mut MapHaxType,
+	chain_maps: &mut ChainMapsType,
 ) -> (usize, usize) {
 	// run-to-completion for each packet where possible.
 	// check for ctl plane signalling every... 5ms?
@@ -357,7 +552,7 @@ fn dataplane_core(
 
 		let pid_len = core::mem::size_of::<ProgId>();
 		let act_len = core::mem::size_of::<u32>();
-		let needed_len = pid_len + act_len;
+		let needed_len = UPCALL_HEADROOM;
 
 		let headroom = unsafe { xsk.umem.headroom(recv_desc) };
 		let contents = headroom.contents();
@@ -367,14 +562,15 @@ fn dataplane_core(
 		let avail_len = (dat_ptr as usize).checked_sub(hr_ptr as usize);
 
 		// Truest headroom: XSK-rs assumes the space is not written to.
-		let (src_nf, act) = if avail_len == Some(needed_len) {
+		let (src_nf, act, meta) = if avail_len == Some(needed_len) {
 			let headroom_slice =
-				unsafe { core::slice::from_raw_parts(contents.as_ptr(), needed_len) };
+				unsafe { core::slice::from_raw_parts_mut(hr_ptr as *mut u8, needed_len) };
+			let (ids, meta) = headroom_slice.split_at_mut(pid_len + act_len);
 
-			let src_nf = ProgId::from_ne_bytes(headroom_slice[0..pid_len].try_into().unwrap());
-			let act = u32::from_ne_bytes(headroom_slice[pid_len..].try_into().unwrap());
+			let src_nf = ProgId::from_ne_bytes(ids[0..pid_len].try_into().unwrap());
+			let act = u32::from_ne_bytes(ids[pid_len..].try_into().unwrap());
 
-			(src_nf, act)
+			(src_nf, act, meta)
 		} else {
 			// EMERGENCY: print first... 20 bytes?
 			let dat = unsafe { xsk.umem.data(recv_desc) };
@@ -400,38 +596,82 @@ fn dataplane_core(
 		//   if not fn? send or not, then break
 		//  return umem credit to cq/fq?
 
-		let src_uuid = chain.instance_ids.get(&src_nf).unwrap();
-		// eprintln!("called by {src_uuid}");
+		// Packets upcalled by a chain we have just swapped away from (or to)
+		// may not match our view of the chain: drop them.
+		let next_nf = chain
+			.instance_ids
+			.get(&src_nf)
+			.and_then(|src_uuid| chain.link_states.get(src_uuid))
+			.and_then(|state| state.act(act).next_nf());
 		// eprintln!("{:#?}", chain.link_states);
-		let mut curr_uuid = chain
-			.link_states
-			.get(src_uuid)
-			.unwrap()
-			.act(act)
-			.next_nf()
-			.unwrap();
-
-		let do_tx = loop {
-			// TODO: select maps, put them in a slice somehow?
-			//    should these be prebuilt?
-			//    can we clone map fds freely?
-			let mut maps = map_hax.get_mut(&curr_uuid);
-			let lib = dylibs.dylibs.get(&curr_uuid).unwrap();
-			let act = lib.user_nf_program(
-				body,
-				&mut maps.as_mut().map(|v| &mut v[..]).unwrap_or(&mut []),
-			);
-
-			// eprintln!("Got {act}, NF has choices {0:?}.", live_fds.link_states);
-
-			match chain.link_states.get(&curr_uuid).unwrap().act(act as u32) {
-				protocol::LinkAction::Tailcall(id) | protocol::LinkAction::Upcall(id) => {
-					curr_uuid = id;
-				},
-				protocol::LinkAction::Tx => break true,
-				// feed drops/aborts AND cq'd packets back into fq (inc. userland counters?)
-				// TODO: increment atomic ctrs?
-				_ => break false,
+
+		let mut curr_uuid = if let Some(uuid) = next_nf {
+			uuid
+		} else {
+			num_tx -= 1;
+			xsk.frames[..].swap(i, num_tx);
+			continue;
+		};
+
+		let counters = chain.counters(&curr_uuid);
+		if let Some(counters) = counters {
+			counters.upcall();
+		}
+
+		// A fused userland build dispatches between NFs itself, so its time is
+		// counted against the NF each packet enters it at.
+		let do_tx = if let Some(program) = &dylibs.chain_program {
+			let start = counters.map(|_| Instant::now());
+			let do_tx = program.run(curr_uuid, body, meta, chain_maps);
+
+			if let (Some(counters), Some(start)) = (counters, start) {
+				counters.record(start.elapsed());
+			}
+
+			do_tx
+		} else {
+			loop {
+				// TODO: select maps, put them in a slice somehow?
+				//    should these be prebuilt?
+				//    can we clone map fds freely?
+				let mut maps = map_hax.get_mut(&curr_uuid);
+				let (lib, state) = match (
+					dylibs.dylibs.get(&curr_uuid),
+					chain.link_states.get(&curr_uuid),
+				) {
+					(Some(lib), Some(state)) => (lib, state),
+					_ => break false,
+				};
+				let counters = chain.counters(&curr_uuid);
+				let start = counters.map(|_| Instant::now());
+				let act = lib.user_nf_program(
+					body,
+					meta,
+					&mut maps.as_mut().map(|v| &mut v[..]).unwrap_or(&mut []),
+				);
+
+				if let (Some(counters), Some(start)) = (counters, start) {
+					counters.record(start.elapsed());
+				}
+
+				// eprintln!("Got {act}, NF has choices {0:?}.", live_fds.link_states);
+
+				// Split links pick their branch here, as in eBPF.
+				let act = chain.split_branch(&curr_uuid).unwrap_or(act as u32);
+
+				if let Some(mirror) = chain.mirror_target(&curr_uuid) {
+					run_mirror(&mirror, body, meta, chain, dylibs, map_hax);
+				}
+
+				match state.act(act) {
+					protocol::LinkAction::Tailcall(id) | protocol::LinkAction::Upcall(id) => {
+						curr_uuid = id;
+					},
+					protocol::LinkAction::Tx => break true,
+					// feed drops/aborts AND cq'd packets back into fq (inc. userland counters?)
+					// TODO: increment atomic ctrs?
+					_ => break false,
+				}
 			}
 		};
 
@@ -447,3 +687,98 @@ fn dataplane_core(
 
 	(num_tx, pkts_recvd)
 }
+
+/// Runs each mirror NF of `chain` on the packet copies sent to it by eBPF,
+/// until the chain is swapped out or the dataplane stops.
+///
+/// Returns the chain to observe next, if any.
+#[cfg(unix)]
+fn mirror_observer(
+	chain: &ChainState,
+	dylibs: &DylibStore,
+	ctl_rx: &mut BusReader<DataplaneCtl>,
+	timeout: usize,
+) -> Option<(Arc<ChainState>, Arc<DylibStore>)> {
+	let map_hax = RefCell::new(chain.raw_maps.clone());
+
+	let buffers: Vec<_> = chain
+		.mirror_maps()
+		.into_iter()
+		.filter_map(|(nf, map)| {
+			let map_hax = &map_hax;
+			PerfBufferBuilder::new(map)
+				.sample_cb(move |_cpu, data: &[u8]| {
+					let (body, meta) = if let Some(sample) = pulley::mirror_sample(data) {
+						sample
+					} else {
+						return;
+					};
+
+					if let Some(counters) = chain.counters(&nf) {
+						counters.upcall();
+					}
+
+					run_mirror(&nf, body, meta, chain, dylibs, &mut map_hax.borrow_mut());
+				})
+				.build()
+				.map_err(|e| eprintln!("Failed to open mirror buffer for {nf}: {e}"))
+				.ok()
+		})
+		.collect();
+
+	loop {
+		let msg = if buffers.is_empty() {
+			ctl_rx.recv().map_err(|_| TryRecvError::Disconnected)
+		} else {
+			// Spread the timeout over every buffer, so that control messages
+			// are still checked for as often as by the dataplane.
+			let per_buffer = Duration::from_millis((timeout / buffers.len()).max(1) as u64);
+			for buffer in &buffers {
+				if let Err(e) = buffer.poll(per_buffer) {
+					eprintln!("Failed to poll mirror buffer: {e}");
+				}
+			}
+
+			ctl_rx.try_recv()
+		};
+
+		match msg {
+			Ok(DataplaneCtl::Stop) | Err(TryRecvError::Disconnected) => return None,
+			Ok(DataplaneCtl::Swap(new_chain, new_dylibs)) => return Some((new_chain, new_dylibs)),
+			_ => {},
+		}
+	}
+}
+
+/// Runs mirror NF `nf` on a copy of a packet and its metadata, ignoring its action.
+#[cfg(unix)]
+fn run_mirror(
+	nf: &Uuid,
+	body: &[u8],
+	meta: &[u8],
+	chain: &ChainState,
+	dylibs: &DylibStore,
+	map_hax: &mut MapHaxType,
+) {
+	let lib = if let Some(lib) = dylibs.dylibs.get(nf) {
+		lib
+	} else {
+		return;
+	};
+
+	let mut body = body.to_vec();
+	let mut meta = meta.to_vec();
+	let mut maps = map_hax.get_mut(nf);
+
+	let counters = chain.counters(nf);
+	let start = counters.map(|_| Instant::now());
+	let _ = lib.user_nf_program(
+		&mut body,
+		&mut meta,
+		&mut maps.as_mut().map(|v| &mut v[..]).unwrap_or(&mut []),
+	);
+
+	if let (Some(counters), Some(start)) = (counters, start) {
+		counters.record(start.elapsed());
+	}
+}
@@ -0,0 +1,68 @@
+#[cfg(unix)]
+use std::{fs, path::Path, ptr};
+
+#[cfg(unix)]
+use libbpf_rs::libbpf_sys;
+use protocol::KernelInfo;
+
+/// Location of the kernel's own BTF, if it was built with `CONFIG_DEBUG_INFO_BTF`.
+#[cfg(unix)]
+const VMLINUX_BTF: &str = "/sys/kernel/btf/vmlinux";
+
+/// Describes the running kernel and probes which eBPF features it supports,
+/// so that chainsmith can move any NFs it cannot run into userland.
+#[cfg(unix)]
+pub fn kernel_info() -> KernelInfo {
+	let release = nix::sys::utsname::uname().release().to_string();
+	let btf = Path::new(VMLINUX_BTF).exists();
+	let btf_digest = kernel_btf().map(|btf| protocol::btf_digest(&btf));
+
+	// SAFETY: each probe loads and immediately unloads a minimal program or map;
+	// null options are permitted.
+	let xdp = unsafe {
+		libbpf_sys::libbpf_probe_bpf_prog_type(libbpf_sys::BPF_PROG_TYPE_XDP, ptr::null()) == 1
+	};
+
+	let helpers = if xdp {
+		(1..libbpf_sys::__BPF_FUNC_MAX_ID)
+			.filter(|&id| unsafe {
+				libbpf_sys::libbpf_probe_bpf_helper(libbpf_sys::BPF_PROG_TYPE_XDP, id, ptr::null())
+					== 1
+			})
+			.collect()
+	} else {
+		Default::default()
+	};
+
+	let map_types = (1..libbpf_sys::__MAX_BPF_MAP_TYPE)
+		.filter(|&map_type| unsafe {
+			libbpf_sys::libbpf_probe_bpf_map_type(map_type, ptr::null()) == 1
+		})
+		.collect();
+
+	KernelInfo {
+		release,
+		probed: true,
+		xdp,
+		btf,
+		btf_digest,
+		helpers,
+		map_types,
+	}
+}
+
+/// Reads the kernel's own BTF, if exposed.
+#[cfg(unix)]
+pub fn kernel_btf() -> Option<Vec<u8>> {
+	fs::read(VMLINUX_BTF).ok()
+}
+
+#[cfg(not(unix))]
+pub fn kernel_info() -> KernelInfo {
+	KernelInfo::default()
+}
+
+#[cfg(not(unix))]
+pub fn kernel_btf() -> Option<Vec<u8>> {
+	None
+}
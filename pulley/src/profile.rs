@@ -0,0 +1,121 @@
+use std::{
+	mem,
+	sync::atomic::{AtomicU64, Ordering},
+	time::Duration,
+};
+
+use libbpf_rs::libbpf_sys;
+use protocol::NfProfile;
+use uuid::Uuid;
+
+/// Number of times each eBPF program is run when measuring its cost.
+const TEST_RUN_REPEAT: i32 = 10_000;
+
+/// Headers of a minimal Ethernet/IPv4/UDP frame (10.0.0.1:1234 -> 10.0.0.2:5678),
+/// used to measure the cost of each eBPF program.
+const TEST_HEADERS: [&[u8]; 3] = [
+	&[0x02, 0, 0, 0, 0, 0x02, 0x02, 0, 0, 0, 0, 0x01, 0x08, 0x00],
+	&[
+		0x45, 0, 0x00, 0x32, 0, 0, 0x40, 0, 0x40, 0x11, 0x26, 0xb9, 10, 0, 0, 1, 10, 0, 0, 2,
+	],
+	&[0x04, 0xd2, 0x16, 0x2e, 0x00, 0x1e, 0, 0],
+];
+
+/// Length of the test frame, including its zeroed payload.
+const TEST_PACKET_LEN: usize = 64;
+
+/// Costs measured for one NF while its chain is live.
+pub struct NfCounters {
+	/// Mean time (in ns) the NF's eBPF program took to process a test packet.
+	pub xdp_ns: Option<u64>,
+	/// Instructions processed by the verifier when loading the NF's eBPF program.
+	pub verified_insns: Option<u32>,
+	user_packets: AtomicU64,
+	user_ns: AtomicU64,
+	upcalls: AtomicU64,
+}
+
+impl NfCounters {
+	pub fn new(xdp_ns: Option<u64>, verified_insns: Option<u32>) -> Self {
+		Self {
+			xdp_ns,
+			verified_insns,
+			user_packets: AtomicU64::new(0),
+			user_ns: AtomicU64::new(0),
+			upcalls: AtomicU64::new(0),
+		}
+	}
+
+	/// Records one packet processed by the NF in userland, taking `elapsed`.
+	#[inline(always)]
+	pub fn record(&self, elapsed: Duration) {
+		self.user_packets.fetch_add(1, Ordering::Relaxed);
+		self.user_ns
+			.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+	}
+
+	/// Records one packet upcalled to the NF.
+	#[inline(always)]
+	pub fn upcall(&self) {
+		self.upcalls.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Reads every cost measured so far, on behalf of `nf`.
+	pub fn snapshot(&self, nf: Uuid) -> NfProfile {
+		NfProfile {
+			nf,
+			xdp_ns: self.xdp_ns,
+			verified_insns: self.verified_insns,
+			user_packets: self.user_packets.load(Ordering::Relaxed),
+			user_ns: self.user_ns.load(Ordering::Relaxed),
+			upcalls: self.upcalls.load(Ordering::Relaxed),
+		}
+	}
+}
+
+/// Reads the number of instructions the verifier processed when loading the
+/// program `prog_fd`.
+///
+/// Returns `None` on kernels which do not report this (i.e., before 5.16).
+pub fn verified_insns(prog_fd: i32) -> Option<u32> {
+	// SAFETY: all-zero is a valid (empty) `bpf_prog_info`.
+	let mut info: libbpf_sys::bpf_prog_info = unsafe { mem::zeroed() };
+	let mut info_len = mem::size_of::<libbpf_sys::bpf_prog_info>() as u32;
+
+	let err = unsafe {
+		libbpf_sys::bpf_obj_get_info_by_fd(prog_fd, &mut info as *mut _ as *mut _, &mut info_len)
+	};
+
+	(err == 0 && info.verified_insns != 0).then_some(info.verified_insns)
+}
+
+/// Measures the mean time (in ns) taken by the program `prog_fd` to process a
+/// test packet, via `BPF_PROG_TEST_RUN`.
+///
+/// This should be called on a copy of the program, as test packets may update
+/// its maps, and before its tail calls are wired up, so that only its own cost
+/// is measured.
+pub fn test_run_ns(prog_fd: i32) -> Option<u64> {
+	let mut data_in = [0u8; TEST_PACKET_LEN];
+	let mut data_out = [0u8; TEST_PACKET_LEN];
+
+	let mut offset = 0;
+	for header in TEST_HEADERS {
+		data_in[offset..offset + header.len()].copy_from_slice(header);
+		offset += header.len();
+	}
+
+	let mut opts = libbpf_sys::bpf_test_run_opts {
+		sz: mem::size_of::<libbpf_sys::bpf_test_run_opts>() as _,
+		data_in: data_in.as_ptr() as *const _,
+		data_out: data_out.as_mut_ptr() as *mut _,
+		data_size_in: data_in.len() as u32,
+		data_size_out: data_out.len() as u32,
+		repeat: TEST_RUN_REPEAT,
+		..Default::default()
+	};
+
+	let err = unsafe { libbpf_sys::bpf_prog_test_run_opts(prog_fd, &mut opts) };
+
+	(err == 0).then_some(u64::from(opts.duration))
+}